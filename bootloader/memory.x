@@ -0,0 +1,24 @@
+/* Flash layout for A/B firmware updates, shared with the application's
+   memory-dfu.x. The two must agree, or the bootloader and the firmware
+   will disagree about where the slots are. */
+
+MEMORY
+{
+  /* NOTE 1 K = 1 KiBi = 1024 bytes */
+  FLASH            : ORIGIN = 0x08000000, LENGTH = 8K
+  BOOTLOADER_STATE : ORIGIN = 0x08002000, LENGTH = 2K
+  ACTIVE           : ORIGIN = 0x08002800, LENGTH = 54K
+  DFU              : ORIGIN = 0x08010000, LENGTH = 56K
+  /* The last 8K hold the application's config store */
+  RAM              : ORIGIN = 0x20000000, LENGTH = 32K
+}
+
+/* Offsets from the start of flash */
+__bootloader_state_start = ORIGIN(BOOTLOADER_STATE) - ORIGIN(FLASH);
+__bootloader_state_end = ORIGIN(BOOTLOADER_STATE) + LENGTH(BOOTLOADER_STATE) - ORIGIN(FLASH);
+
+__bootloader_active_start = ORIGIN(ACTIVE) - ORIGIN(FLASH);
+__bootloader_active_end = ORIGIN(ACTIVE) + LENGTH(ACTIVE) - ORIGIN(FLASH);
+
+__bootloader_dfu_start = ORIGIN(DFU) - ORIGIN(FLASH);
+__bootloader_dfu_end = ORIGIN(DFU) + LENGTH(DFU) - ORIGIN(FLASH);
@@ -0,0 +1,192 @@
+//! Client side of the chunked bulk transfers, for subcommands moving more
+//! than fits in one frame
+
+use std::time::Duration;
+
+use postcard_rpc::{
+    header::VarSeq,
+    host_client::{HostClient, HostErr, MultiSubscription},
+    standard_icd::WireError,
+};
+use template_icd::{
+    BulkAck, BulkChunk, BulkData, BulkDownloadAckTopic, BulkDownloadTopic, BulkError, BulkFinish,
+    BulkFinishEndpoint, BulkId, BulkInfo, BulkOpenEndpoint, BulkStatusEndpoint, BulkTarget, BulkUploadAckTopic,
+    BulkUploadTopic, BULK_MAX_CHUNK, BULK_WINDOW,
+};
+use tokio::time::timeout;
+
+/// CRC-32 as zlib computes it, zip files use it too
+pub const CRC: crc::Crc<u32> = crc::Crc::<u32>::new(&crc::CRC_32_ISO_HDLC);
+
+/// Bytes sent before waiting on an ack
+const WINDOW_BYTES: u32 = BULK_WINDOW * BULK_MAX_CHUNK as u32;
+/// Covers a window of chunks, and the flash work behind them
+const ACK_TIMEOUT: Duration = Duration::from_secs(2);
+/// Timeouts in a row before giving up
+const MAX_RETRIES: u32 = 5;
+
+#[derive(Debug)]
+pub enum BulkErr {
+    Device(BulkError),
+    Request(HostErr<WireError>),
+    /// The connection to the device closed
+    Closed,
+    /// No progress after several resends
+    Stalled,
+    /// The device expects a different amount of data
+    SizeMismatch { expected: u32 },
+}
+
+impl From<BulkError> for BulkErr {
+    fn from(e: BulkError) -> Self {
+        BulkErr::Device(e)
+    }
+}
+
+impl From<HostErr<WireError>> for BulkErr {
+    fn from(e: HostErr<WireError>) -> Self {
+        BulkErr::Request(e)
+    }
+}
+
+/// Send `data` to the target, `progress` is called with the bytes acked
+/// so far and the total
+pub async fn upload(
+    client: &HostClient<WireError>,
+    target: BulkTarget,
+    data: &[u8],
+    mut progress: impl FnMut(u32, u32),
+) -> Result<(), BulkErr> {
+    let mut acks = client.subscribe_multi::<BulkUploadAckTopic>(16).await.map_err(|_| BulkErr::Closed)?;
+    let BulkInfo { id, size } = client.send_resp::<BulkOpenEndpoint>(&target).await??;
+    if size as usize != data.len() {
+        return Err(BulkErr::SizeMismatch { expected: size });
+    }
+
+    let mut next = 0u32;
+    let mut seq = 0u16;
+    let mut retries = 0;
+    while next < size {
+        let end = size.min(next + WINDOW_BYTES);
+        for offset in (next..end).step_by(BULK_MAX_CHUNK) {
+            let len = (end - offset).min(BULK_MAX_CHUNK as u32);
+            let chunk = BulkChunk {
+                id,
+                offset,
+                data: BulkData::from_slice(&data[offset as usize..][..len as usize]).unwrap(),
+            };
+            client
+                .publish::<BulkUploadTopic>(VarSeq::Seq2(seq), &chunk)
+                .await
+                .map_err(|_| BulkErr::Closed)?;
+            seq = seq.wrapping_add(1);
+        }
+        // A gap ack is never behind the last one, anything that is is stale
+        match timeout(ACK_TIMEOUT, next_ack(&mut acks, id, next)).await {
+            Ok(ack) => {
+                next = ack?.next;
+                retries = 0;
+            }
+            Err(_) => {
+                retries += 1;
+                if retries > MAX_RETRIES {
+                    return Err(BulkErr::Stalled);
+                }
+                next = client.send_resp::<BulkStatusEndpoint>(&id).await??.next;
+            }
+        }
+        progress(next, size);
+    }
+
+    let finish = BulkFinish {
+        id,
+        crc: CRC.checksum(data),
+    };
+    Ok(client.send_resp::<BulkFinishEndpoint>(&finish).await??)
+}
+
+async fn next_ack(acks: &mut MultiSubscription<BulkAck>, id: BulkId, next: u32) -> Result<BulkAck, BulkErr> {
+    loop {
+        let ack = acks.recv().await.map_err(|_| BulkErr::Closed)?;
+        if ack.id == id && ack.next >= next {
+            return Ok(ack);
+        }
+    }
+}
+
+/// Read the whole target, `progress` is called with the bytes received so
+/// far and the total
+pub async fn download(
+    client: &HostClient<WireError>,
+    target: BulkTarget,
+    mut progress: impl FnMut(u32, u32),
+) -> Result<Vec<u8>, BulkErr> {
+    let mut chunks = client
+        .subscribe_multi::<BulkDownloadTopic>(2 * BULK_WINDOW as usize)
+        .await
+        .map_err(|_| BulkErr::Closed)?;
+    let BulkInfo { id, size } = client.send_resp::<BulkOpenEndpoint>(&target).await??;
+
+    let mut data = Vec::with_capacity(size as usize);
+    let mut seq = 0u16;
+
+    // Each ack asks for the window after it, the one for the whole size
+    // completes the transfer
+    let mut window_end = size.min(WINDOW_BYTES);
+    ack(client, id, 0, &mut seq).await?;
+    let mut gap = false;
+    let mut retries = 0;
+    while (data.len() as u32) < size {
+        let received = data.len() as u32;
+        let chunk = match timeout(ACK_TIMEOUT, chunks.recv()).await {
+            Ok(chunk) => chunk.map_err(|_| BulkErr::Closed)?,
+            Err(_) => {
+                retries += 1;
+                if retries > MAX_RETRIES {
+                    return Err(BulkErr::Stalled);
+                }
+                if let Err(e) = client.send_resp::<BulkStatusEndpoint>(&id).await? {
+                    return Err(e.into());
+                }
+                gap = false;
+                window_end = size.min(received + WINDOW_BYTES);
+                ack(client, id, received, &mut seq).await?;
+                continue;
+            }
+        };
+        if chunk.id != id || chunk.offset < received {
+            continue;
+        }
+        if chunk.offset > received {
+            // Ask for a resend once, the rest of the window is dropped
+            if !gap {
+                gap = true;
+                window_end = size.min(received + WINDOW_BYTES);
+                ack(client, id, received, &mut seq).await?;
+            }
+            continue;
+        }
+        data.extend_from_slice(&chunk.data);
+        gap = false;
+        retries = 0;
+        let received = data.len() as u32;
+        progress(received, size);
+        if received >= window_end {
+            window_end = size.min(received + WINDOW_BYTES);
+            ack(client, id, received, &mut seq).await?;
+        }
+    }
+
+    let finish = BulkFinish {
+        id,
+        crc: CRC.checksum(&data),
+    };
+    client.send_resp::<BulkFinishEndpoint>(&finish).await??;
+    Ok(data)
+}
+
+async fn ack(client: &HostClient<WireError>, id: BulkId, next: u32, seq: &mut u16) -> Result<(), BulkErr> {
+    let res = client.publish::<BulkDownloadAckTopic>(VarSeq::Seq2(*seq), &BulkAck { id, next }).await;
+    *seq = seq.wrapping_add(1);
+    res.map_err(|_| BulkErr::Closed)
+}
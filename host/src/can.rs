@@ -0,0 +1,369 @@
+//! The `can` subcommand: bus setup, a candump-style viewer, and a bridge
+//! to a SocketCAN interface such as `vcan0`
+//!
+//! Frames and filters use the can-utils text syntax, with IDs in hex
+
+use std::sync::Arc;
+
+use clap::{Subcommand, ValueEnum};
+use postcard_rpc::{
+    header::VarSeq,
+    host_client::{HostClient, HostErr, MultiSubscription},
+    standard_icd::WireError,
+};
+use socketcan::{id::FdFlags, CanAnyFrame, CanFdSocket, EmbeddedFrame, Socket};
+use template_icd::{
+    CanConfig, CanData, CanError, CanFilter, CanFilterAction, CanFilterMatch, CanFrame, CanId, CanMode,
+    CanRxFrame, CanRxTopic, CanTxTopic, ConfigureCanEndpoint, GetCanErrorsEndpoint, SetCanFilterEndpoint, Timestamp,
+};
+use tokio::sync::mpsc;
+
+#[derive(Subcommand)]
+pub enum CanCommand {
+    /// Set the bitrates and mode, and start the bus
+    Config {
+        #[arg(long, default_value_t = 500_000)]
+        bitrate: u32,
+        /// Enable CAN FD, switching to this rate for the data phase
+        #[arg(long)]
+        data_bitrate: Option<u32>,
+        #[arg(long, value_enum, default_value_t = Mode::Normal)]
+        mode: Mode,
+        /// Drop frames that match no filter
+        #[arg(long)]
+        reject_unmatched: bool,
+    },
+    /// Set one acceptance filter: `off`, `<id>`, `<id>,<id>`, `<from>-<to>` or `<id>:<mask>`
+    Filter {
+        slot: u8,
+        #[arg(value_parser = parse_match)]
+        matching: CanFilterMatch,
+        /// Match 29-bit IDs, there are separate slots for those
+        #[arg(long)]
+        extended: bool,
+        /// Drop matching frames instead of keeping them
+        #[arg(long)]
+        reject: bool,
+    },
+    /// Send a frame, e.g. `123#DEADBEEF`, `12345678#R4` or `123##1AABBCC`
+    Send {
+        #[arg(value_parser = parse_frame)]
+        frame: CanFrame,
+    },
+    /// Print received frames until interrupted
+    Dump {
+        /// Interface name to print, for tools that expect one
+        #[arg(long, default_value = "can0")]
+        iface: String,
+        /// Print in the candump log format, which canplayer can replay
+        #[arg(long)]
+        log: bool,
+    },
+    /// Show the error counters and bus state
+    Errors,
+    /// Forward frames both ways between the device and a SocketCAN
+    /// interface, until interrupted
+    Bridge { iface: String },
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+pub enum Mode {
+    Normal,
+    Loopback,
+    ExternalLoopback,
+    Silent,
+}
+
+pub async fn run(client: &HostClient<WireError>, command: CanCommand) {
+    match command {
+        CanCommand::Config {
+            bitrate,
+            data_bitrate,
+            mode,
+            reject_unmatched,
+        } => {
+            let config = CanConfig {
+                nominal_bitrate: bitrate,
+                data_bitrate,
+                mode: match mode {
+                    Mode::Normal => CanMode::Normal,
+                    Mode::Loopback => CanMode::InternalLoopback,
+                    Mode::ExternalLoopback => CanMode::ExternalLoopback,
+                    Mode::Silent => CanMode::Silent,
+                },
+                accept_unmatched: !reject_unmatched,
+            };
+            let res = client.send_resp::<ConfigureCanEndpoint>(&config).await;
+            report(res, |()| println!("ok"));
+        }
+        CanCommand::Filter {
+            slot,
+            matching,
+            extended,
+            reject,
+        } => {
+            let filter = CanFilter {
+                extended,
+                slot,
+                matching,
+                action: match reject {
+                    true => CanFilterAction::Reject,
+                    false => CanFilterAction::Accept,
+                },
+            };
+            let res = client.send_resp::<SetCanFilterEndpoint>(&filter).await;
+            report(res, |()| println!("ok"));
+        }
+        CanCommand::Send { frame } => {
+            // Topics have no reply, but the device handles messages in order,
+            // so the drop counter read afterwards tells whether it was sent
+            let dropped = || async {
+                match client.send_resp::<GetCanErrorsEndpoint>(&()).await {
+                    Ok(Ok(e)) => Ok(e.tx_dropped),
+                    Ok(Err(e)) => Err(format!("CAN error: {e:?}")),
+                    Err(e) => Err(format!("Request failed: {e:?}")),
+                }
+            };
+            let before = match dropped().await {
+                Ok(n) => n,
+                Err(e) => return eprintln!("{e}"),
+            };
+            if let Err(e) = client.publish::<CanTxTopic>(VarSeq::Seq2(0), &frame).await {
+                return eprintln!("Request failed: {e:?}");
+            }
+            match dropped().await {
+                Ok(n) if n == before => println!("ok"),
+                Ok(_) => eprintln!("The device dropped the frame, see its log for why"),
+                Err(e) => eprintln!("{e}"),
+            }
+        }
+        CanCommand::Dump { iface, log } => {
+            let Some(mut sub) = subscribe(client).await else {
+                return;
+            };
+            while let Ok(CanRxFrame { timestamp, frame }) = sub.recv().await {
+                // Since boot until the time is set with the `time` subcommand
+                let (Timestamp::Uptime(timestamp_us) | Timestamp::Unix(timestamp_us)) = timestamp;
+                let secs = timestamp_us / 1_000_000;
+                let micros = timestamp_us % 1_000_000;
+                if log {
+                    println!("({secs}.{micros:06}) {iface} {}", to_cansend(&frame));
+                } else {
+                    println!("({secs:>5}.{micros:06})  {iface}  {}", to_candump(&frame));
+                }
+            }
+        }
+        CanCommand::Errors => {
+            let res = client.send_resp::<GetCanErrorsEndpoint>(&()).await;
+            report(res, |e| {
+                println!("State:      {:?}", e.state);
+                println!("TX errors:  {}", e.tx_errors);
+                println!("RX errors:  {}", e.rx_errors);
+                println!("Bus errors: {}", e.bus_errors);
+                println!("TX dropped: {}", e.tx_dropped);
+            });
+        }
+        CanCommand::Bridge { iface } => bridge(client, &iface).await,
+    }
+}
+
+async fn bridge(client: &HostClient<WireError>, iface: &str) {
+    let socket = match CanFdSocket::open(iface) {
+        Ok(socket) => Arc::new(socket),
+        Err(e) => {
+            eprintln!("Could not open {iface}: {e}");
+            return;
+        }
+    };
+    let Some(mut sub) = subscribe(client).await else {
+        return;
+    };
+    println!("Bridging the device to {iface}");
+
+    // SocketCAN reads block, so they get their own thread
+    let (to_device, mut from_socket) = mpsc::channel::<CanFrame>(64);
+    let reader = socket.clone();
+    std::thread::spawn(move || {
+        while let Ok(frame) = reader.read_frame() {
+            // Error frames are local to the interface, there is nothing to forward
+            let Some(frame) = from_socketcan(frame) else {
+                continue;
+            };
+            if to_device.blocking_send(frame).is_err() {
+                break;
+            }
+        }
+    });
+    tokio::task::spawn(async move {
+        while let Ok(CanRxFrame { frame, .. }) = sub.recv().await {
+            let Some(frame) = to_socketcan(&frame) else {
+                continue;
+            };
+            if let Err(e) = socket.write_frame(&frame) {
+                eprintln!("Write to SocketCAN failed: {e}");
+            }
+        }
+    });
+
+    let mut seq = 0u16;
+    while let Some(frame) = from_socket.recv().await {
+        if client.publish::<CanTxTopic>(VarSeq::Seq2(seq), &frame).await.is_err() {
+            eprintln!("Device connection closed");
+            break;
+        }
+        seq = seq.wrapping_add(1);
+    }
+}
+
+async fn subscribe(client: &HostClient<WireError>) -> Option<MultiSubscription<CanRxFrame>> {
+    match client.subscribe_multi::<CanRxTopic>(256).await {
+        Ok(sub) => Some(sub),
+        Err(e) => {
+            eprintln!("Could not subscribe: {e:?}");
+            None
+        }
+    }
+}
+
+/// Parse the `cansend` frame syntax
+pub fn parse_frame(s: &str) -> Result<CanFrame, String> {
+    let (id, rest) = s.split_once('#').ok_or("expected <id>#<data>")?;
+    let id = match id.len() {
+        3 => CanId::Standard(u16::from_str_radix(id, 16).map_err(|e| e.to_string())?),
+        8 => CanId::Extended(u32::from_str_radix(id, 16).map_err(|e| e.to_string())?),
+        _ => return Err("the ID takes 3 hex digits, or 8 for an extended ID".into()),
+    };
+    let mut frame = CanFrame {
+        id,
+        remote: false,
+        fd: false,
+        brs: false,
+        data: CanData::new(),
+    };
+    let data = if let Some(rest) = rest.strip_prefix('#') {
+        // FD frame, a hex digit of flags comes first
+        let mut chars = rest.chars();
+        let flags = chars.next().and_then(|c| c.to_digit(16)).ok_or("expected FD flags after ##")?;
+        frame.fd = true;
+        frame.brs = flags & 1 != 0;
+        chars.as_str()
+    } else if let Some(len) = rest.strip_prefix('R') {
+        frame.remote = true;
+        let len = match len {
+            "" => 0,
+            len => len.parse::<usize>().map_err(|e| e.to_string())?,
+        };
+        let _ = frame.data.resize(len.min(8), 0);
+        return Ok(frame);
+    } else {
+        rest
+    };
+    let digits: String = data.chars().filter(|&c| c != '.').collect();
+    if !digits.len().is_multiple_of(2) {
+        return Err("data must be whole bytes".into());
+    }
+    for i in (0..digits.len()).step_by(2) {
+        let byte = u8::from_str_radix(&digits[i..i + 2], 16).map_err(|e| e.to_string())?;
+        frame.data.push(byte).map_err(|_| "at most 64 bytes of data")?;
+    }
+    Ok(frame)
+}
+
+/// Format a frame in the `cansend` syntax, the inverse of [`parse_frame`]
+pub fn to_cansend(frame: &CanFrame) -> String {
+    let data: String = frame.data.iter().map(|b| format!("{b:02X}")).collect();
+    match (frame.remote, frame.fd) {
+        (true, _) => format!("{}#R{}", id_str(frame.id), frame.data.len()),
+        (false, true) => format!("{}##{}{data}", id_str(frame.id), frame.brs as u8),
+        (false, false) => format!("{}#{data}", id_str(frame.id)),
+    }
+}
+
+/// Format a frame the way `candump` prints it
+pub fn to_candump(frame: &CanFrame) -> String {
+    let len = frame.data.len();
+    let data = match frame.remote {
+        true => "remote request".to_string(),
+        false => frame.data.iter().map(|b| format!("{b:02X}")).collect::<Vec<_>>().join(" "),
+    };
+    match frame.fd {
+        true => format!("{}  [{len:02}]  {data}", id_str(frame.id)),
+        false => format!("{}   [{len}]  {data}", id_str(frame.id)),
+    }
+}
+
+fn id_str(id: CanId) -> String {
+    match id {
+        CanId::Standard(id) => format!("{id:03X}"),
+        CanId::Extended(id) => format!("{id:08X}"),
+    }
+}
+
+/// Parse a filter in the syntax of [`CanCommand::Filter`]
+pub fn parse_match(s: &str) -> Result<CanFilterMatch, String> {
+    let hex = |s: &str| u32::from_str_radix(s, 16).map_err(|e| e.to_string());
+    if s == "off" {
+        Ok(CanFilterMatch::Disabled)
+    } else if let Some((id, mask)) = s.split_once(':') {
+        Ok(CanFilterMatch::Mask {
+            id: hex(id)?,
+            mask: hex(mask)?,
+        })
+    } else if let Some((from, to)) = s.split_once('-') {
+        Ok(CanFilterMatch::Range {
+            from: hex(from)?,
+            to: hex(to)?,
+        })
+    } else if let Some((a, b)) = s.split_once(',') {
+        Ok(CanFilterMatch::Either(hex(a)?, hex(b)?))
+    } else {
+        Ok(CanFilterMatch::Exact(hex(s)?))
+    }
+}
+
+fn to_socketcan(frame: &CanFrame) -> Option<CanAnyFrame> {
+    let id: socketcan::Id = match frame.id {
+        CanId::Standard(id) => socketcan::StandardId::new(id)?.into(),
+        CanId::Extended(id) => socketcan::ExtendedId::new(id)?.into(),
+    };
+    Some(match (frame.remote, frame.fd) {
+        (true, _) => socketcan::CanRemoteFrame::new_remote(id, frame.data.len())?.into(),
+        (false, true) => {
+            let flags = match frame.brs {
+                true => FdFlags::BRS,
+                false => FdFlags::empty(),
+            };
+            socketcan::CanFdFrame::with_flags(id, &frame.data, flags)?.into()
+        }
+        (false, false) => socketcan::CanDataFrame::new(id, &frame.data)?.into(),
+    })
+}
+
+fn from_socketcan(frame: CanAnyFrame) -> Option<CanFrame> {
+    let (id, remote, fd, brs, data) = match &frame {
+        CanAnyFrame::Normal(f) => (f.id(), false, false, false, f.data()),
+        // Only the length of a remote frame matters
+        CanAnyFrame::Remote(f) => (f.id(), true, false, false, &[0u8; 8][..f.dlc()]),
+        CanAnyFrame::Fd(f) => (f.id(), false, true, f.is_brs(), f.data()),
+        CanAnyFrame::Error(_) => return None,
+    };
+    let id = match id {
+        socketcan::Id::Standard(id) => CanId::Standard(id.as_raw()),
+        socketcan::Id::Extended(id) => CanId::Extended(id.as_raw()),
+    };
+    Some(CanFrame {
+        id,
+        remote,
+        fd,
+        brs,
+        data: CanData::from_slice(data).ok()?,
+    })
+}
+
+fn report<T>(res: Result<Result<T, CanError>, HostErr<WireError>>, ok: impl FnOnce(T)) {
+    match res {
+        Ok(Ok(t)) => ok(t),
+        Ok(Err(e)) => eprintln!("CAN error: {e:?}"),
+        Err(e) => eprintln!("Request failed: {e:?}"),
+    }
+}
@@ -0,0 +1,88 @@
+//! Requests to spawned handlers that the device drops again when the host
+//! stops waiting, by publishing the request's sequence number to
+//! [`CancelTopic`]
+
+use std::{
+    sync::atomic::{AtomicU32, Ordering},
+    time::Duration,
+};
+
+use postcard_rpc::{
+    header::{VarHeader, VarKey, VarSeq},
+    host_client::{HostClient, HostErr, RpcFrame},
+    standard_icd::WireError,
+    Endpoint,
+};
+use serde::{de::DeserializeOwned, Serialize};
+use template_icd::CancelTopic;
+use tokio::{runtime::Handle, time::timeout};
+
+/// The client numbers its own requests up from zero, these stay clear of them
+static NEXT_SEQ: AtomicU32 = AtomicU32::new(0x8000_0000);
+
+/// Cancels the request on the device unless disarmed first
+struct CancelOnDrop<'a> {
+    client: &'a HostClient<WireError>,
+    seq: u32,
+    armed: bool,
+}
+
+impl Drop for CancelOnDrop<'_> {
+    fn drop(&mut self) {
+        let Ok(runtime) = Handle::try_current() else {
+            return;
+        };
+        if self.armed {
+            let client = self.client.clone();
+            let seq = self.seq;
+            runtime.spawn(async move {
+                let _ = client.publish::<CancelTopic>(VarSeq::Seq4(seq), &seq).await;
+            });
+        }
+    }
+}
+
+/// As [`HostClient::send_resp`], but the device is told to cancel the
+/// request if this future is dropped before the answer comes
+pub async fn send_resp<E: Endpoint>(
+    client: &HostClient<WireError>,
+    req: &E::Request,
+) -> Result<E::Response, HostErr<WireError>>
+where
+    E::Request: Serialize,
+    E::Response: DeserializeOwned,
+{
+    let seq = NEXT_SEQ.fetch_add(1, Ordering::Relaxed);
+    let mut guard = CancelOnDrop {
+        client,
+        seq,
+        armed: true,
+    };
+    let frame = RpcFrame {
+        header: VarHeader {
+            key: VarKey::Key8(E::REQ_KEY),
+            seq_no: VarSeq::Seq4(seq),
+        },
+        body: postcard::to_stdvec(req).expect("Allocations should not ever fail"),
+    };
+    let res = client.send_resp_raw(frame, E::RESP_KEY).await;
+    guard.armed = false;
+    Ok(postcard::from_bytes(&res?.body)?)
+}
+
+/// [`send_resp`] giving up after `limit`, which cancels the request.
+/// `None` on a timeout.
+pub async fn send_resp_timeout<E: Endpoint>(
+    client: &HostClient<WireError>,
+    req: &E::Request,
+    limit: Duration,
+) -> Result<Option<E::Response>, HostErr<WireError>>
+where
+    E::Request: Serialize,
+    E::Response: DeserializeOwned,
+{
+    match timeout(limit, send_resp::<E>(client, req)).await {
+        Ok(res) => res.map(Some),
+        Err(_) => Ok(None),
+    }
+}
@@ -0,0 +1,143 @@
+//! The `capture` subcommand, measures frequency, period and pulse widths
+//! of a signal on the device's capture inputs
+
+use clap::{Subcommand, ValueEnum};
+use postcard_rpc::{
+    host_client::{HostClient, HostErr},
+    standard_icd::WireError,
+};
+use template_icd::{
+    CaptureError, CaptureInput, CaptureMeasureEndpoint, CaptureMeasurement, CaptureMode, CaptureRequest,
+    CaptureStreamEndpoint, CaptureTopic,
+};
+
+#[derive(Subcommand)]
+pub enum CaptureCommand {
+    /// Measure once, averaged over the window
+    Measure {
+        #[command(flatten)]
+        request: Request,
+    },
+    /// Have the device measure every window, and print each until
+    /// interrupted. The device keeps on until `capture stop`.
+    Watch {
+        #[command(flatten)]
+        request: Request,
+    },
+    /// Stop the measurements `capture watch` started
+    Stop,
+}
+
+#[derive(clap::Args)]
+pub struct Request {
+    #[arg(value_enum)]
+    input: Input,
+    /// `pwm` adds pulse widths and duty cycle
+    #[arg(long, value_enum, default_value_t = Mode::Period)]
+    mode: Mode,
+    #[arg(long, default_value_t = 1000)]
+    window_ms: u32,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+pub enum Input {
+    /// PB6
+    Ch1,
+    /// PB7
+    Ch2,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+pub enum Mode {
+    Period,
+    Pwm,
+}
+
+impl Request {
+    fn to_icd(&self) -> CaptureRequest {
+        CaptureRequest {
+            input: match self.input {
+                Input::Ch1 => CaptureInput::Ch1,
+                Input::Ch2 => CaptureInput::Ch2,
+            },
+            mode: match self.mode {
+                Mode::Period => CaptureMode::InputCapture,
+                Mode::Pwm => CaptureMode::PwmInput,
+            },
+            window_ms: self.window_ms,
+        }
+    }
+}
+
+pub async fn run(client: &HostClient<WireError>, command: CaptureCommand) {
+    match command {
+        CaptureCommand::Measure { request } => {
+            let res = client.send_resp::<CaptureMeasureEndpoint>(&request.to_icd()).await;
+            report(res, |m| print_measurement(&m));
+        }
+        CaptureCommand::Watch { request } => {
+            let mut sub = match client.subscribe_multi::<CaptureTopic>(16).await {
+                Ok(sub) => sub,
+                Err(e) => {
+                    eprintln!("Could not subscribe: {e:?}");
+                    return;
+                }
+            };
+            match client.send_resp::<CaptureStreamEndpoint>(&Some(request.to_icd())).await {
+                Ok(Ok(())) => {}
+                res => {
+                    report(res, |()| {});
+                    return;
+                }
+            }
+            while let Ok(m) = sub.recv().await {
+                print_measurement(&m);
+            }
+        }
+        CaptureCommand::Stop => {
+            let res = client.send_resp::<CaptureStreamEndpoint>(&None).await;
+            report(res, |()| println!("ok"));
+        }
+    }
+}
+
+fn print_measurement(m: &CaptureMeasurement) {
+    if m.periods == 0 {
+        println!("No signal in {} ms ({} edges missed)", m.window_ms, m.missed);
+        return;
+    }
+    let hz = m.frequency_millihz / 1000;
+    let millihz = m.frequency_millihz % 1000;
+    print!("{hz}.{millihz:03} Hz, period {}", format_ns(m.period_ns));
+    if let Some(pulse) = m.pulse {
+        let duty = pulse.duty_ppm / 10_000;
+        let frac = pulse.duty_ppm % 10_000 / 100;
+        print!(
+            ", high {}, low {}, duty {duty}.{frac:02}%",
+            format_ns(pulse.high_ns),
+            format_ns(pulse.low_ns)
+        );
+    }
+    print!(" over {} periods", m.periods);
+    if m.missed > 0 {
+        print!(", {} edges missed", m.missed);
+    }
+    println!(" ({:.1} ns resolution)", 1e9 / f64::from(m.timer_hz));
+}
+
+fn format_ns(ns: u64) -> String {
+    match ns {
+        0..1_000 => format!("{ns} ns"),
+        1_000..1_000_000 => format!("{:.3} us", ns as f64 / 1e3),
+        1_000_000..1_000_000_000 => format!("{:.3} ms", ns as f64 / 1e6),
+        _ => format!("{:.3} s", ns as f64 / 1e9),
+    }
+}
+
+fn report<T>(res: Result<Result<T, CaptureError>, HostErr<WireError>>, ok: impl FnOnce(T)) {
+    match res {
+        Ok(Ok(t)) => ok(t),
+        Ok(Err(e)) => eprintln!("Capture error: {e:?}"),
+        Err(e) => eprintln!("Request failed: {e:?}"),
+    }
+}
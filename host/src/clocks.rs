@@ -0,0 +1,30 @@
+//! The `clocks` subcommand, shows the device's clock tree
+
+use postcard_rpc::{host_client::HostClient, standard_icd::WireError};
+use template_icd::{ClockSource, ClockTree, ClockTreeEndpoint};
+
+pub async fn run(client: &HostClient<WireError>) {
+    match client.send_resp::<ClockTreeEndpoint>(&()).await {
+        Ok(tree) => print_tree(&tree),
+        Err(e) => eprintln!("Request failed: {e:?}"),
+    }
+}
+
+fn print_tree(tree: &ClockTree) {
+    match tree.source {
+        ClockSource::Hsi16 => println!("Source:  HSI16"),
+        ClockSource::Hse { hz } => println!("Source:  HSE, {}", mhz(hz)),
+    }
+    println!("SYSCLK:  {}", mhz(tree.sysclk_hz));
+    println!("HCLK:    {}", mhz(tree.hclk_hz));
+    println!("PCLK1:   {}", mhz(tree.pclk1_hz));
+    println!("PCLK2:   {}", mhz(tree.pclk2_hz));
+    println!("Kernel clocks:");
+    for k in &tree.kernels {
+        println!("  {:<8} {}", k.name, mhz(k.hz));
+    }
+}
+
+fn mhz(hz: u32) -> String {
+    format!("{:>7.3} MHz", hz as f64 / 1e6)
+}
@@ -0,0 +1,139 @@
+//! The `config` subcommand, manages the settings stored in device flash
+
+use clap::Subcommand;
+use postcard_dyn::Value;
+use postcard_rpc::{
+    host_client::{HostClient, HostErr},
+    standard_icd::WireError,
+};
+use postcard_schema::{schema::owned::OwnedNamedType, Schema};
+use template_icd::{
+    config_keys, CanConfig, ConfigEntry, ConfigError, ConfigKey, ConfigValue, DeleteConfigEndpoint,
+    FactoryResetEndpoint, GetConfigEndpoint, I2cConfig, ListConfigEndpoint, SetConfigEndpoint, SpiConfig,
+    UartConfig, CONFIG_MAX_KEY, CONFIG_MAX_VALUE,
+};
+
+use crate::hex;
+
+#[derive(Subcommand)]
+pub enum ConfigCommand {
+    /// List the stored keys
+    List,
+    /// Print a value, decoded if the firmware knows the key
+    Get { key: String },
+    /// Store a value, as JSON if the firmware knows the key and as hex bytes
+    /// otherwise, e.g. `config set heartbeat_ms 1000`. Settings read at boot
+    /// take effect on the next reset.
+    Set { key: String, value: String },
+    Delete { key: String },
+    /// Erase every stored setting
+    FactoryReset,
+}
+
+pub async fn run(client: &HostClient<WireError>, command: ConfigCommand) {
+    match command {
+        ConfigCommand::List => {
+            let res = client.send_resp::<ListConfigEndpoint>(&()).await;
+            report(res, |keys| {
+                for key in keys {
+                    println!("{key}");
+                }
+            });
+        }
+        ConfigCommand::Get { key } => {
+            let Some(key) = key_arg(&key) else {
+                return;
+            };
+            let res = client.send_resp::<GetConfigEndpoint>(&key).await;
+            report(res, |value| match known_schema(&key) {
+                Some(schema) => match postcard_dyn::from_slice_dyn(&schema, &value) {
+                    Ok(decoded) => println!("{decoded}"),
+                    Err(e) => eprintln!("Stored value does not decode ({e:?}): {}", hex(&value)),
+                },
+                None => println!("{}", hex(&value)),
+            });
+        }
+        ConfigCommand::Set { key, value } => {
+            let Some(key) = key_arg(&key) else {
+                return;
+            };
+            let encoded = match known_schema(&key) {
+                Some(schema) => value
+                    .parse::<Value>()
+                    .map_err(|e| e.to_string())
+                    .and_then(|json| postcard_dyn::to_stdvec_dyn(&schema, &json).map_err(|e| format!("{e:?}"))),
+                None => parse_hex(&value),
+            };
+            let value = match encoded {
+                Ok(bytes) => match ConfigValue::from_slice(&bytes) {
+                    Ok(value) => value,
+                    Err(()) => {
+                        eprintln!("Values are at most {CONFIG_MAX_VALUE} bytes");
+                        return;
+                    }
+                },
+                Err(e) => {
+                    eprintln!("Invalid value for {key}: {e}");
+                    return;
+                }
+            };
+            let res = client.send_resp::<SetConfigEndpoint>(&ConfigEntry { key, value }).await;
+            report(res, |()| {});
+        }
+        ConfigCommand::Delete { key } => {
+            let Some(key) = key_arg(&key) else {
+                return;
+            };
+            let res = client.send_resp::<DeleteConfigEndpoint>(&key).await;
+            report(res, |()| {});
+        }
+        ConfigCommand::FactoryReset => {
+            let res = client.send_resp::<FactoryResetEndpoint>(&()).await;
+            report(res, |()| println!("Settings erased, defaults apply from the next reset"));
+        }
+    }
+}
+
+/// The type the firmware decodes a key as, see [`config_keys`]
+fn known_schema(key: &str) -> Option<OwnedNamedType> {
+    let schema = match key {
+        config_keys::UNIQUE_ID => u64::SCHEMA,
+        config_keys::HEARTBEAT_MS => u32::SCHEMA,
+        config_keys::UART => UartConfig::SCHEMA,
+        config_keys::I2C => I2cConfig::SCHEMA,
+        config_keys::SPI => SpiConfig::SCHEMA,
+        config_keys::CAN => CanConfig::SCHEMA,
+        config_keys::SERVER_PRIORITY => u8::SCHEMA,
+        config_keys::HOST_TIMEOUT_MS => u32::SCHEMA,
+        _ => return None,
+    };
+    Some(schema.into())
+}
+
+fn key_arg(key: &str) -> Option<ConfigKey> {
+    let key = ConfigKey::try_from(key).ok();
+    if key.is_none() {
+        eprintln!("Keys are at most {CONFIG_MAX_KEY} bytes");
+    }
+    key
+}
+
+/// Parse bytes written as hex, optionally separated by spaces
+fn parse_hex(s: &str) -> Result<Vec<u8>, String> {
+    let digits: String = s.split_whitespace().collect();
+    if !digits.is_ascii() || !digits.len().is_multiple_of(2) {
+        return Err("expected pairs of hex digits".into());
+    }
+    (0..digits.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&digits[i..i + 2], 16).map_err(|e| e.to_string()))
+        .collect()
+}
+
+fn report<T>(res: Result<Result<T, ConfigError>, HostErr<WireError>>, ok: impl FnOnce(T)) {
+    match res {
+        Ok(Ok(t)) => ok(t),
+        Ok(Err(e)) => eprintln!("Config error: {e:?}"),
+        Err(e) => eprintln!("Request failed: {e:?}"),
+    }
+}
@@ -0,0 +1,167 @@
+//! The `dac` subcommand, levels and waveforms on the device's DAC outputs
+//!
+//! Waveforms are given by frequency, the sample rate follows from that and
+//! the samples per period. Tables are text files with one value per line
+//! from -1 to 1, uploaded to the device before they play.
+
+use std::path::{Path, PathBuf};
+
+use clap::{Subcommand, ValueEnum};
+use postcard_rpc::{
+    host_client::{HostClient, HostErr},
+    standard_icd::WireError,
+};
+use template_icd::{
+    BulkTarget, DacError, DacLevel, DacOutput, DacPlayEndpoint, DacSetEndpoint, DacShape, DacStopEndpoint, DacWave,
+    DAC_MAX_RATE_HZ, DAC_MAX_SAMPLES, DAC_VREF_MV,
+};
+
+use crate::bulk;
+
+#[derive(Subcommand)]
+pub enum DacCommand {
+    /// Hold a level, stopping any waveform
+    Level {
+        #[arg(value_enum)]
+        output: Output,
+        millivolts: u16,
+    },
+    /// Play a waveform, or change the one playing without a glitch
+    Play {
+        #[arg(value_enum)]
+        output: Output,
+        #[arg(value_enum)]
+        shape: Shape,
+        /// In Hz
+        #[arg(long)]
+        freq: f64,
+        /// Per period, fewer if the sample rate would get too high. Tables
+        /// play as many as they have.
+        #[arg(long, default_value_t = 128)]
+        samples: u16,
+        #[arg(long, default_value_t = DAC_VREF_MV / 2)]
+        amplitude_mv: u16,
+        #[arg(long, default_value_t = DAC_VREF_MV / 2)]
+        offset_mv: u16,
+        /// The table to upload, for the `table` shape
+        #[arg(long, required_if_eq("shape", "table"))]
+        table: Option<PathBuf>,
+    },
+    /// Stop at the end of the period, resting on the first sample
+    Stop {
+        #[arg(value_enum)]
+        output: Output,
+    },
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+pub enum Output {
+    /// PA4
+    Out1,
+    /// PA5
+    Out2,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+pub enum Shape {
+    Sine,
+    Triangle,
+    Square,
+    Table,
+}
+
+pub async fn run(client: &HostClient<WireError>, command: DacCommand) {
+    match command {
+        DacCommand::Level { output, millivolts } => {
+            let level = DacLevel {
+                output: output.into(),
+                millivolts,
+            };
+            let res = client.send_resp::<DacSetEndpoint>(&level).await;
+            report(res, |()| println!("ok"));
+        }
+        DacCommand::Play {
+            output,
+            shape,
+            freq,
+            samples,
+            amplitude_mv,
+            offset_mv,
+            table,
+        } => {
+            let output = output.into();
+            let samples = match table {
+                Some(path) => match upload_table(client, output, &path).await {
+                    Ok(samples) => samples,
+                    Err(e) => {
+                        eprintln!("{e}");
+                        return;
+                    }
+                },
+                None => samples.min((DAC_MAX_RATE_HZ as f64 / freq) as u16).clamp(2, DAC_MAX_SAMPLES),
+            };
+            let wave = DacWave {
+                output,
+                shape: match shape {
+                    Shape::Sine => DacShape::Sine,
+                    Shape::Triangle => DacShape::Triangle,
+                    Shape::Square => DacShape::Square,
+                    Shape::Table => DacShape::Table,
+                },
+                samples,
+                sample_rate_hz: (freq * f64::from(samples)).round() as u32,
+                amplitude_mv,
+                offset_mv,
+            };
+            let res = client.send_resp::<DacPlayEndpoint>(&wave).await;
+            report(res, |()| {
+                let freq = f64::from(wave.sample_rate_hz) / f64::from(samples);
+                println!("Playing {freq:.3} Hz, {samples} samples at {} Hz", wave.sample_rate_hz);
+            });
+        }
+        DacCommand::Stop { output } => match client.send_resp::<DacStopEndpoint>(&output.into()).await {
+            Ok(()) => println!("ok"),
+            Err(e) => eprintln!("Request failed: {e:?}"),
+        },
+    }
+}
+
+/// Uploads the table in `path`, returning how many samples it has
+async fn upload_table(client: &HostClient<WireError>, output: DacOutput, path: &Path) -> Result<u16, String> {
+    let text = std::fs::read_to_string(path).map_err(|e| format!("Could not read {}: {e}", path.display()))?;
+    let mut data = Vec::new();
+    for (n, line) in text.lines().map(str::trim).enumerate().filter(|(_, l)| !l.is_empty()) {
+        let value = match line.parse::<f64>() {
+            Ok(value) if (-1.0..=1.0).contains(&value) => value,
+            _ => return Err(format!("Line {}: expected a value from -1 to 1", n + 1)),
+        };
+        let sample = (value * f64::from(i16::MAX)).round() as i16;
+        data.extend_from_slice(&sample.to_le_bytes());
+    }
+    let samples = (data.len() / 2) as u16;
+    if !(2..=usize::from(DAC_MAX_SAMPLES)).contains(&(data.len() / 2)) {
+        return Err(format!("Tables have 2 to {DAC_MAX_SAMPLES} samples"));
+    }
+    let target = BulkTarget::DacTable { output, samples };
+    bulk::upload(client, target, &data, |_, _| {})
+        .await
+        .map_err(|e| format!("Upload failed: {e:?}"))?;
+    Ok(samples)
+}
+
+impl From<Output> for DacOutput {
+    fn from(output: Output) -> Self {
+        match output {
+            Output::Out1 => DacOutput::Out1,
+            Output::Out2 => DacOutput::Out2,
+        }
+    }
+}
+
+fn report<T>(res: Result<Result<T, DacError>, HostErr<WireError>>, ok: impl FnOnce(T)) {
+    match res {
+        Ok(Ok(t)) => ok(t),
+        Ok(Err(e)) => eprintln!("DAC error: {e:?}"),
+        Err(e) => eprintln!("Request failed: {e:?}"),
+    }
+}
@@ -0,0 +1,108 @@
+//! The `encoder` subcommand, reads and follows the quadrature encoder on
+//! the device
+
+use clap::Subcommand;
+use postcard_rpc::{
+    host_client::{HostClient, HostErr},
+    standard_icd::WireError,
+};
+use template_icd::{
+    EncoderConfig, EncoderConfigureEndpoint, EncoderError, EncoderPublishEndpoint, EncoderReadEndpoint,
+    EncoderReading, EncoderTopic, EncoderZeroEndpoint, Timestamp,
+};
+
+#[derive(Subcommand)]
+pub enum EncoderCommand {
+    /// Set the counting direction and input filter
+    Config {
+        #[arg(long)]
+        reversed: bool,
+        /// 0 for none, up to 15
+        #[arg(long, default_value_t = 0)]
+        filter: u8,
+    },
+    /// Print the position and velocity
+    Read {
+        /// Counts per revolution, to print revolutions and RPM too
+        #[arg(long)]
+        cpr: Option<u32>,
+    },
+    /// Make the current position zero
+    Zero,
+    /// Have the device publish readings, and print each until
+    /// interrupted. The device keeps on until `encoder stop`.
+    Watch {
+        #[arg(long, default_value_t = 100)]
+        every_ms: u32,
+        #[arg(long)]
+        cpr: Option<u32>,
+    },
+    /// Stop the readings `encoder watch` started
+    Stop,
+}
+
+pub async fn run(client: &HostClient<WireError>, command: EncoderCommand) {
+    match command {
+        EncoderCommand::Config { reversed, filter } => {
+            let config = EncoderConfig { reversed, filter };
+            let res = client.send_resp::<EncoderConfigureEndpoint>(&config).await;
+            report(res, |()| println!("ok"));
+        }
+        EncoderCommand::Read { cpr } => match client.send_resp::<EncoderReadEndpoint>(&()).await {
+            Ok(reading) => print_reading(&reading, cpr),
+            Err(e) => eprintln!("Request failed: {e:?}"),
+        },
+        EncoderCommand::Zero => match client.send_resp::<EncoderZeroEndpoint>(&()).await {
+            Ok(()) => println!("ok"),
+            Err(e) => eprintln!("Request failed: {e:?}"),
+        },
+        EncoderCommand::Watch { every_ms, cpr } => {
+            let mut sub = match client.subscribe_multi::<EncoderTopic>(64).await {
+                Ok(sub) => sub,
+                Err(e) => {
+                    eprintln!("Could not subscribe: {e:?}");
+                    return;
+                }
+            };
+            match client.send_resp::<EncoderPublishEndpoint>(&every_ms).await {
+                Ok(Ok(())) => {}
+                res => {
+                    report(res, |()| {});
+                    return;
+                }
+            }
+            while let Ok(reading) = sub.recv().await {
+                print_reading(&reading, cpr);
+            }
+        }
+        EncoderCommand::Stop => {
+            let res = client.send_resp::<EncoderPublishEndpoint>(&0).await;
+            report(res, |()| println!("ok"));
+        }
+    }
+}
+
+fn print_reading(reading: &EncoderReading, cpr: Option<u32>) {
+    let (Timestamp::Uptime(us) | Timestamp::Unix(us)) = reading.timestamp;
+    print!(
+        "({:>5}.{:06})  {:>12} counts  {:>10} counts/s",
+        us / 1_000_000,
+        us % 1_000_000,
+        reading.position,
+        reading.velocity_cps
+    );
+    if let Some(cpr) = cpr.filter(|&cpr| cpr > 0) {
+        let revs = reading.position as f64 / f64::from(cpr);
+        let rpm = f64::from(reading.velocity_cps) * 60.0 / f64::from(cpr);
+        print!("  {revs:>10.3} rev  {rpm:>9.1} rpm");
+    }
+    println!();
+}
+
+fn report<T>(res: Result<Result<T, EncoderError>, HostErr<WireError>>, ok: impl FnOnce(T)) {
+    match res {
+        Ok(Ok(t)) => ok(t),
+        Ok(Err(e)) => eprintln!("Encoder error: {e:?}"),
+        Err(e) => eprintln!("Request failed: {e:?}"),
+    }
+}
@@ -0,0 +1,183 @@
+//! The `i2c` subcommand, a thin wrapper around the I2C bridge endpoints
+
+use clap::Subcommand;
+use postcard_rpc::{
+    host_client::{HostClient, HostErr},
+    standard_icd::WireError,
+};
+use template_icd::{
+    ConfigureI2cEndpoint, I2cConfig, I2cData, I2cError, I2cRead, I2cReadEndpoint, I2cReadRegisterEndpoint,
+    I2cRegister, I2cRegisterRead, I2cRegisterWrite, I2cScanEndpoint, I2cWrite, I2cWriteEndpoint,
+    I2cWriteRead, I2cWriteReadEndpoint, I2cWriteRegisterEndpoint,
+};
+
+use crate::{hex, parse_int};
+
+#[derive(Subcommand)]
+pub enum I2cCommand {
+    /// List the addresses that acknowledge a read
+    Scan,
+    /// Read from a device register, e.g. `i2c read 0x48 0x00 2`
+    Read {
+        #[arg(value_parser = parse_int::<u8>)]
+        address: u8,
+        #[arg(value_parser = parse_int::<u16>)]
+        register: u16,
+        #[arg(value_parser = parse_int::<u16>)]
+        len: u16,
+        /// Send the register address as two bytes, big-endian
+        #[arg(long)]
+        word: bool,
+    },
+    /// Write to a device register
+    Write {
+        #[arg(value_parser = parse_int::<u8>)]
+        address: u8,
+        #[arg(value_parser = parse_int::<u16>)]
+        register: u16,
+        #[arg(value_parser = parse_int::<u8>)]
+        data: Vec<u8>,
+        /// Send the register address as two bytes, big-endian
+        #[arg(long)]
+        word: bool,
+    },
+    /// Read without sending a register address first
+    RawRead {
+        #[arg(value_parser = parse_int::<u8>)]
+        address: u8,
+        #[arg(value_parser = parse_int::<u16>)]
+        len: u16,
+    },
+    /// Write bytes as-is
+    RawWrite {
+        #[arg(value_parser = parse_int::<u8>)]
+        address: u8,
+        #[arg(value_parser = parse_int::<u8>, required = true)]
+        data: Vec<u8>,
+    },
+    /// Write bytes, then read back after a repeated start
+    WriteRead {
+        #[arg(value_parser = parse_int::<u8>)]
+        address: u8,
+        #[arg(value_parser = parse_int::<u16>)]
+        len: u16,
+        #[arg(value_parser = parse_int::<u8>, required = true)]
+        data: Vec<u8>,
+    },
+    /// Set the bus speed and the timeout of each operation
+    Config {
+        #[arg(long, default_value_t = 100_000)]
+        frequency: u32,
+        #[arg(long, default_value_t = 100)]
+        timeout_ms: u32,
+    },
+}
+
+pub async fn run(client: &HostClient<WireError>, command: I2cCommand) {
+    match command {
+        I2cCommand::Scan => {
+            let res = client.send_resp::<I2cScanEndpoint>(&()).await;
+            report(res, |found| {
+                println!("Found {} device(s)", found.len());
+                for address in found {
+                    println!("  {address:#04x}");
+                }
+            });
+        }
+        I2cCommand::Read {
+            address,
+            register,
+            len,
+            word,
+        } => {
+            let Some(register) = register_arg(register, word) else {
+                return;
+            };
+            let req = I2cRegisterRead {
+                address,
+                register,
+                len,
+            };
+            let res = client.send_resp::<I2cReadRegisterEndpoint>(&req).await;
+            report(res, |data| println!("{}", hex(&data)));
+        }
+        I2cCommand::Write {
+            address,
+            register,
+            data,
+            word,
+        } => {
+            let (Some(register), Some(data)) = (register_arg(register, word), data_arg(&data)) else {
+                return;
+            };
+            let req = I2cRegisterWrite {
+                address,
+                register,
+                data,
+            };
+            let res = client.send_resp::<I2cWriteRegisterEndpoint>(&req).await;
+            report(res, |()| println!("ok"));
+        }
+        I2cCommand::RawRead { address, len } => {
+            let res = client.send_resp::<I2cReadEndpoint>(&I2cRead { address, len }).await;
+            report(res, |data| println!("{}", hex(&data)));
+        }
+        I2cCommand::RawWrite { address, data } => {
+            let Some(data) = data_arg(&data) else {
+                return;
+            };
+            let res = client.send_resp::<I2cWriteEndpoint>(&I2cWrite { address, data }).await;
+            report(res, |()| println!("ok"));
+        }
+        I2cCommand::WriteRead { address, len, data } => {
+            let Some(data) = data_arg(&data) else {
+                return;
+            };
+            let req = I2cWriteRead {
+                address,
+                data,
+                read_len: len,
+            };
+            let res = client.send_resp::<I2cWriteReadEndpoint>(&req).await;
+            report(res, |data| println!("{}", hex(&data)));
+        }
+        I2cCommand::Config {
+            frequency,
+            timeout_ms,
+        } => {
+            let req = I2cConfig {
+                frequency_hz: frequency,
+                timeout_ms,
+            };
+            let res = client.send_resp::<ConfigureI2cEndpoint>(&req).await;
+            report(res, |()| println!("ok"));
+        }
+    }
+}
+
+fn register_arg(register: u16, word: bool) -> Option<I2cRegister> {
+    match (word, u8::try_from(register)) {
+        (true, _) => Some(I2cRegister::Word(register)),
+        (false, Ok(r)) => Some(I2cRegister::Byte(r)),
+        (false, Err(_)) => {
+            eprintln!("Register {register:#x} needs --word");
+            None
+        }
+    }
+}
+
+fn data_arg(data: &[u8]) -> Option<I2cData> {
+    let data = I2cData::from_slice(data).ok();
+    if data.is_none() {
+        eprintln!("At most {} bytes fit in one transfer", template_icd::I2C_MAX_PAYLOAD);
+    }
+    data
+}
+
+fn report<T>(res: Result<Result<T, I2cError>, HostErr<WireError>>, ok: impl FnOnce(T)) {
+    match res {
+        Ok(Ok(t)) => ok(t),
+        Ok(Err(e)) => eprintln!("I2C error: {e:?}"),
+        Err(e) => eprintln!("Request failed: {e:?}"),
+    }
+}
@@ -0,0 +1,140 @@
+//! The `job` subcommand, starts long-running operations on the device and
+//! follows them with a progress bar
+
+use std::{
+    io::{stdout, Write},
+    time::Duration,
+};
+
+use clap::Subcommand;
+use postcard_rpc::{
+    host_client::{HostClient, HostErr},
+    standard_icd::WireError,
+};
+use template_icd::{
+    JobCancelEndpoint, JobError, JobId, JobOutput, JobRequest, JobStartEndpoint, JobStatus, JobStatusEndpoint, JobTopic,
+    JobUpdate,
+};
+use tokio::time::timeout;
+
+use crate::parse_int;
+
+/// Without an update for this long, the status is asked for instead
+const UPDATE_TIMEOUT: Duration = Duration::from_secs(2);
+const BAR_WIDTH: usize = 40;
+
+#[derive(Subcommand)]
+pub enum JobCommand {
+    /// Sleep on the device, to try out jobs
+    Sleep { millis: u32 },
+    /// CRC-32 over a range of internal flash
+    FlashCrc {
+        /// From the start of flash
+        #[arg(value_parser = parse_int::<u32>)]
+        offset: u32,
+        #[arg(value_parser = parse_int::<u32>)]
+        size: u32,
+    },
+    /// Keep the device busy, to see how requests fare meanwhile
+    Load { millis: u32 },
+    /// Show the status of a job
+    Status { id: JobId },
+    /// Cancel a running job
+    Cancel { id: JobId },
+}
+
+pub async fn run(client: &HostClient<WireError>, command: JobCommand) {
+    match command {
+        JobCommand::Sleep { millis } => follow(client, JobRequest::Sleep { millis }).await,
+        JobCommand::FlashCrc { offset, size } => follow(client, JobRequest::FlashCrc { offset, size }).await,
+        JobCommand::Load { millis } => follow(client, JobRequest::Load { millis }).await,
+        JobCommand::Status { id } => {
+            let res = client.send_resp::<JobStatusEndpoint>(&id).await;
+            report(res, |status| println!("Job {id}: {status:?}"));
+        }
+        JobCommand::Cancel { id } => {
+            let res = client.send_resp::<JobCancelEndpoint>(&id).await;
+            report(res, |()| println!("Job {id} cancelled"));
+        }
+    }
+}
+
+/// Start a job and draw its progress until it ends
+async fn follow(client: &HostClient<WireError>, request: JobRequest) {
+    let mut sub = match client.subscribe_multi::<JobTopic>(64).await {
+        Ok(sub) => sub,
+        Err(e) => {
+            eprintln!("Could not subscribe: {e:?}");
+            return;
+        }
+    };
+    let id = match client.send_resp::<JobStartEndpoint>(&request).await {
+        Ok(id) => id,
+        Err(e) => {
+            eprintln!("Request failed: {e:?}");
+            return;
+        }
+    };
+    println!("Job {id} started");
+
+    loop {
+        let status = match timeout(UPDATE_TIMEOUT, sub.recv()).await {
+            Ok(Ok(JobUpdate { id: update_id, status })) if update_id == id => status,
+            Ok(Ok(_)) => continue,
+            Ok(Err(_)) => {
+                eprintln!("\nConnection closed");
+                return;
+            }
+            // The final update may have been dropped
+            Err(_) => match client.send_resp::<JobStatusEndpoint>(&id).await {
+                Ok(Ok(status)) => status,
+                res => {
+                    println!();
+                    report(res, |_| {});
+                    return;
+                }
+            },
+        };
+        match status {
+            JobStatus::Running { done, total } => draw_bar(done, total),
+            JobStatus::Finished(output) => {
+                match output {
+                    JobOutput::Slept { millis } => println!("\nSlept for {millis} ms"),
+                    JobOutput::FlashCrc(crc) => println!("\nCRC-32 {crc:#010x}"),
+                    JobOutput::Busy { millis } => println!("\nBusy for {millis} ms"),
+                }
+                return;
+            }
+            JobStatus::Failed(e) => {
+                eprintln!("\nJob error: {e:?}");
+                return;
+            }
+            JobStatus::Cancelled => {
+                println!("\nCancelled");
+                return;
+            }
+        }
+    }
+}
+
+fn draw_bar(done: u32, total: u32) {
+    let filled = match total {
+        0 => 0,
+        _ => (done as u64 * BAR_WIDTH as u64 / total as u64) as usize,
+    };
+    let percent = 100 * done as u64 / total.max(1) as u64;
+    print!(
+        "\r[{}{}] {percent:>3}% {done} / {total}",
+        "#".repeat(filled),
+        " ".repeat(BAR_WIDTH - filled.min(BAR_WIDTH))
+    );
+    let _ = stdout().flush();
+}
+
+fn report<T>(res: Result<Result<T, JobError>, HostErr<WireError>>, ok: impl FnOnce(T)) {
+    match res {
+        Ok(Ok(t)) => ok(t),
+        Ok(Err(e)) => eprintln!("Job error: {e:?}"),
+        Err(e) => eprintln!("Request failed: {e:?}"),
+    }
+}
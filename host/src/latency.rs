@@ -0,0 +1,55 @@
+//! The `latency` subcommand, times round trips to the device, optionally
+//! while a load job keeps its thread mode executor busy
+
+use std::time::{Duration, Instant};
+
+use postcard_rpc::{host_client::HostClient, standard_icd::WireError};
+use template_icd::{GetUniqueIdEndpoint, JobCancelEndpoint, JobRequest, JobStartEndpoint};
+
+pub async fn run(client: &HostClient<WireError>, count: usize, load_ms: Option<u32>) {
+    let job = match load_ms {
+        Some(millis) => match client.send_resp::<JobStartEndpoint>(&JobRequest::Load { millis }).await {
+            Ok(id) => {
+                println!("Load job {id} running for {millis} ms");
+                Some(id)
+            }
+            Err(e) => {
+                eprintln!("Request failed: {e:?}");
+                return;
+            }
+        },
+        None => None,
+    };
+
+    let mut times = Vec::with_capacity(count);
+    for _ in 0..count {
+        let start = Instant::now();
+        if let Err(e) = client.send_resp::<GetUniqueIdEndpoint>(&()).await {
+            eprintln!("Request failed: {e:?}");
+            return;
+        }
+        times.push(start.elapsed());
+    }
+
+    // A load job that outlasts the measurement is stopped, one that is
+    // already over just answers that it isn't running
+    if let Some(id) = job {
+        let _ = client.send_resp::<JobCancelEndpoint>(&id).await;
+    }
+
+    times.sort();
+    let Some((min, max)) = times.first().zip(times.last()) else {
+        return;
+    };
+    let median = times[times.len() / 2];
+    println!(
+        "{count} requests: min {}, median {}, max {}",
+        millis(*min),
+        millis(median),
+        millis(*max)
+    );
+}
+
+fn millis(d: Duration) -> String {
+    format!("{:.2} ms", d.as_secs_f64() * 1000.0)
+}
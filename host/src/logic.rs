@@ -0,0 +1,290 @@
+//! The `logic` subcommand, captures with the device's logic analyzer and
+//! saves them for PulseView or any other waveform viewer
+//!
+//! Captures are saved as VCD, or as a sigrok session when the file ends in
+//! `.sr`. Sessions are zip files, written here uncompressed.
+
+use std::{fmt::Write as _, path::PathBuf};
+
+use clap::{Subcommand, ValueEnum};
+use postcard_rpc::{host_client::HostClient, standard_icd::WireError};
+use template_icd::{
+    BulkTarget, LogicCapture, LogicCaptureEndpoint, LogicEdge, LogicPins, LogicPort, LogicRequest, LogicTrigger,
+    LOGIC_MAX_SAMPLES,
+};
+
+use crate::{bulk, parse_int};
+
+#[derive(Subcommand)]
+pub enum LogicCommand {
+    /// Capture, then save the samples to `output`
+    Capture {
+        #[arg(value_enum)]
+        port: Port,
+        /// Pins 8 to 15 rather than 0 to 7
+        #[arg(long)]
+        high_byte: bool,
+        /// The channels to capture, 0 to 7
+        #[arg(long, value_delimiter = ',', default_values_t = [0, 1, 2, 3, 4, 5, 6, 7])]
+        channels: Vec<u8>,
+        /// In Hz
+        #[arg(long, default_value_t = 1_000_000)]
+        rate: u32,
+        #[arg(long, default_value_t = LOGIC_MAX_SAMPLES)]
+        samples: u32,
+        /// Of the samples, those from before the trigger
+        #[arg(long, default_value_t = 0)]
+        pretrigger: u32,
+        /// `rise:N`, `fall:N` or `edge:N` on channel N, or `MASK=VALUE`
+        /// for a pattern. Without one the capture starts right away.
+        #[arg(long, value_parser = parse_trigger)]
+        trigger: Option<LogicTrigger>,
+        /// How long to wait for the trigger
+        #[arg(long, default_value_t = 10_000)]
+        timeout_ms: u32,
+        /// A `.vcd` file, or `.sr` for a sigrok session
+        output: PathBuf,
+    },
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+pub enum Port {
+    A,
+    B,
+    C,
+    D,
+    E,
+    F,
+    G,
+}
+
+#[derive(Clone, Copy)]
+enum Format {
+    Vcd,
+    Sigrok,
+}
+
+pub async fn run(client: &HostClient<WireError>, command: LogicCommand) {
+    let LogicCommand::Capture {
+        port,
+        high_byte,
+        channels,
+        rate,
+        samples,
+        pretrigger,
+        trigger,
+        timeout_ms,
+        output,
+    } = command;
+    let format = match output.extension().and_then(|e| e.to_str()) {
+        Some("vcd") => Format::Vcd,
+        Some("sr") => Format::Sigrok,
+        _ => {
+            eprintln!("The output has to end in .vcd or .sr");
+            return;
+        }
+    };
+    let Some(channels) = channels.iter().try_fold(0u8, |mask, &c| (c < 8).then(|| mask | 1 << c)) else {
+        eprintln!("Channels go from 0 to 7");
+        return;
+    };
+    let request = LogicRequest {
+        pins: LogicPins {
+            port: match port {
+                Port::A => LogicPort::A,
+                Port::B => LogicPort::B,
+                Port::C => LogicPort::C,
+                Port::D => LogicPort::D,
+                Port::E => LogicPort::E,
+                Port::F => LogicPort::F,
+                Port::G => LogicPort::G,
+            },
+            high_byte,
+            channels,
+        },
+        rate_hz: rate,
+        samples,
+        pretrigger,
+        trigger: trigger.unwrap_or(LogicTrigger::Immediate),
+        timeout_ms,
+    };
+
+    let capture = match client.send_resp::<LogicCaptureEndpoint>(&request).await {
+        Ok(Ok(capture)) => capture,
+        Ok(Err(e)) => {
+            eprintln!("Logic error: {e:?}");
+            return;
+        }
+        Err(e) => {
+            eprintln!("Request failed: {e:?}");
+            return;
+        }
+    };
+    let data = match bulk::download(client, BulkTarget::Logic, |_, _| {}).await {
+        Ok(data) => data,
+        Err(e) => {
+            eprintln!("Download failed: {e:?}");
+            return;
+        }
+    };
+    print!("Captured {} samples at {} Hz", data.len(), capture.rate_hz);
+    if let Some(at) = capture.trigger {
+        let us = u64::from(at) * 1_000_000 / u64::from(capture.rate_hz);
+        print!(", triggered at sample {at} ({us} us in)");
+    }
+    println!();
+
+    let res = match format {
+        Format::Vcd => std::fs::write(&output, vcd(&capture, &data)),
+        Format::Sigrok => std::fs::write(&output, sigrok(&capture, &data)),
+    };
+    if let Err(e) = res {
+        eprintln!("Cannot write {}: {e}", output.display());
+    }
+}
+
+/// See [`LogicCommand::Capture`] for the forms
+fn parse_trigger(s: &str) -> Result<LogicTrigger, String> {
+    if let Some((mask, value)) = s.split_once('=') {
+        return Ok(LogicTrigger::Pattern {
+            mask: parse_int(mask)?,
+            value: parse_int(value)?,
+        });
+    }
+    let (edge, channel) = s.split_once(':').ok_or("expected rise:N, fall:N, edge:N or MASK=VALUE")?;
+    let edge = match edge {
+        "rise" => LogicEdge::Rising,
+        "fall" => LogicEdge::Falling,
+        "edge" => LogicEdge::Either,
+        _ => return Err(format!("unknown edge {edge}")),
+    };
+    Ok(LogicTrigger::Edge {
+        channel: parse_int(channel)?,
+        edge,
+    })
+}
+
+/// Channel numbers of the capture, with the pins they are
+fn probes(pins: &LogicPins) -> Vec<(usize, String)> {
+    let port = match pins.port {
+        LogicPort::A => 'A',
+        LogicPort::B => 'B',
+        LogicPort::C => 'C',
+        LogicPort::D => 'D',
+        LogicPort::E => 'E',
+        LogicPort::F => 'F',
+        LogicPort::G => 'G',
+    };
+    let first = if pins.high_byte { 8 } else { 0 };
+    (0..8)
+        .filter(|c| pins.channels & (1 << c) != 0)
+        .map(|c| (c, format!("P{port}{}", first + c)))
+        .collect()
+}
+
+/// A value change dump, in nanoseconds
+fn vcd(capture: &LogicCapture, data: &[u8]) -> String {
+    let probes = probes(&capture.pins);
+    let id = |n: usize| char::from(b'!' + n as u8);
+    let time = |i: usize| i as u64 * 1_000_000_000 / u64::from(capture.rate_hz);
+
+    let mut out = String::new();
+    let _ = writeln!(out, "$version template host $end");
+    if let Some(at) = capture.trigger {
+        let _ = writeln!(out, "$comment triggered at {} ns $end", time(at as usize));
+    }
+    let _ = writeln!(out, "$timescale 1 ns $end");
+    let _ = writeln!(out, "$scope module logic $end");
+    for (n, (_, name)) in probes.iter().enumerate() {
+        let _ = writeln!(out, "$var wire 1 {} {name} $end", id(n));
+    }
+    let _ = writeln!(out, "$upscope $end");
+    let _ = writeln!(out, "$enddefinitions $end");
+
+    let mut last = None;
+    for (i, &sample) in data.iter().enumerate() {
+        let changed = last.map_or(0xff, |last| last ^ sample);
+        if changed == 0 {
+            continue;
+        }
+        let _ = writeln!(out, "#{}", time(i));
+        for (n, &(channel, _)) in probes.iter().enumerate() {
+            if changed & (1 << channel) != 0 {
+                let _ = writeln!(out, "{}{}", (sample >> channel) & 1, id(n));
+            }
+        }
+        last = Some(sample);
+    }
+    let _ = writeln!(out, "#{}", time(data.len()));
+    out
+}
+
+/// A sigrok session, the samples as they came with a byte each
+fn sigrok(capture: &LogicCapture, data: &[u8]) -> Vec<u8> {
+    let mut metadata = String::new();
+    let _ = writeln!(metadata, "[global]\nsigrok version=0.5.2\n");
+    let _ = writeln!(metadata, "[device 1]\ncapturefile=logic-1\ntotal probes=8");
+    let _ = writeln!(metadata, "samplerate={}\ntotal analog=0", capture.rate_hz);
+    for (channel, name) in probes(&capture.pins) {
+        let _ = writeln!(metadata, "probe{}={name}", channel + 1);
+    }
+    let _ = writeln!(metadata, "unitsize=1");
+    zip(&[
+        ("version", b"2"),
+        ("metadata", metadata.as_bytes()),
+        ("logic-1-1", data),
+    ])
+}
+
+/// A zip archive of `files`, stored without compression
+fn zip(files: &[(&str, &[u8])]) -> Vec<u8> {
+    // 1980-01-01, the earliest a zip can say
+    const DOS_DATE: u16 = (1 << 5) | 1;
+
+    let mut out = Vec::new();
+    let mut directory = Vec::new();
+    for &(name, data) in files {
+        let offset = out.len() as u32;
+        let crc = bulk::CRC.checksum(data);
+        // Version 2.0, no flags, stored, time and date
+        let common = |buf: &mut Vec<u8>| {
+            for half in [20, 0, 0, 0, DOS_DATE] {
+                buf.extend_from_slice(&u16::to_le_bytes(half));
+            }
+            for word in [crc, data.len() as u32, data.len() as u32] {
+                buf.extend_from_slice(&word.to_le_bytes());
+            }
+            buf.extend_from_slice(&(name.len() as u16).to_le_bytes());
+            // No extra field
+            buf.extend_from_slice(&[0, 0]);
+        };
+
+        out.extend_from_slice(b"PK\x03\x04");
+        common(&mut out);
+        out.extend_from_slice(name.as_bytes());
+        out.extend_from_slice(data);
+
+        directory.extend_from_slice(b"PK\x01\x02");
+        // Made by version 2.0
+        directory.extend_from_slice(&[20, 0]);
+        common(&mut directory);
+        // No comment, disk 0, no attributes
+        directory.extend_from_slice(&[0; 10]);
+        directory.extend_from_slice(&offset.to_le_bytes());
+        directory.extend_from_slice(name.as_bytes());
+    }
+
+    let directory_offset = out.len() as u32;
+    out.extend_from_slice(&directory);
+    out.extend_from_slice(b"PK\x05\x06");
+    // This disk and the one the directory starts on
+    out.extend_from_slice(&[0; 4]);
+    let count = (files.len() as u16).to_le_bytes();
+    out.extend_from_slice(&count);
+    out.extend_from_slice(&count);
+    out.extend_from_slice(&(directory.len() as u32).to_le_bytes());
+    out.extend_from_slice(&directory_offset.to_le_bytes());
+    // No comment
+    out.extend_from_slice(&[0, 0]);
+    out
+}
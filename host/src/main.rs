@@ -1,8 +1,11 @@
 use std::{
     io::{stdout, Write},
-    time::Duration,
+    path::{Path, PathBuf},
+    thread::JoinHandle,
+    time::{Duration, SystemTime, UNIX_EPOCH},
 };
 
+use clap::{Parser, Subcommand};
 use cobs::{decode_vec, encode_vec};
 use impls::{ProbeRttRx, ProbeRttTx, TokSpawn};
 use postcard_rpc::{header::VarSeqKind, header::VarHeader, host_client::{HostClient, RawMultiSubscription, RawSubscription, TopicReport}, standard_icd::{PingEndpoint, WireError}};
@@ -12,14 +15,150 @@ use probe_rs::{
     rtt::{Rtt, RttChannel, ScanRegion},
     Core, Permissions, Session,
 };
-use template_icd::HelloTopic;
+use template_icd::{BulkTarget, Cancelled, HelloTopic, HostHello, HostHelloEndpoint, CancellableSleepEndpoint, SleepMillis};
 use tokio::{sync::mpsc, time::{sleep, timeout}};
 use postcard_dyn;
 
+pub mod bulk;
+pub mod cancel;
+pub mod can;
+pub mod capture;
+pub mod clocks;
+pub mod config;
+pub mod dac;
+pub mod encoder;
+pub mod i2c;
 pub mod impls;
+pub mod jobs;
+pub mod latency;
+pub mod logic;
+pub mod pattern;
+pub mod memory;
+pub mod power;
+pub mod ram;
+pub mod tasks;
+pub mod time;
+pub mod uart;
+pub mod update;
+
+/// Well within the device's default host timeout
+const KEEPALIVE_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Parser)]
+struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Print the device schema and watch its topics for a while (default)
+    Schema,
+    /// Talk to devices on the I2C bus
+    I2c {
+        #[command(subcommand)]
+        command: i2c::I2cCommand,
+    },
+    /// Talk to the CAN bus
+    Can {
+        #[command(subcommand)]
+        command: can::CanCommand,
+    },
+    /// Measure frequency and pulse widths on the capture inputs
+    Capture {
+        #[command(subcommand)]
+        command: capture::CaptureCommand,
+    },
+    /// Manage the settings stored on the device
+    Config {
+        #[command(subcommand)]
+        command: config::ConfigCommand,
+    },
+    /// Tunnel the device UART to a local pseudo-terminal
+    Uart {
+        #[command(subcommand)]
+        command: uart::UartCommand,
+    },
+    /// Peek and poke raw memory and peripheral registers
+    Memory {
+        #[command(subcommand)]
+        command: memory::MemoryCommand,
+    },
+    /// Have the device sleep, as an example of a spawned request
+    Sleep {
+        millis: u16,
+        /// Cancel the sleep if it runs longer than this
+        #[arg(long)]
+        timeout_ms: Option<u64>,
+    },
+    /// Run long operations on the device, with progress
+    Job {
+        #[command(subcommand)]
+        command: jobs::JobCommand,
+    },
+    /// Time requests to the device
+    Latency {
+        /// Requests to time
+        #[arg(long, default_value_t = 100)]
+        count: usize,
+        /// Keep the device busy in thread mode meanwhile, for this long
+        #[arg(long)]
+        load_ms: Option<u32>,
+    },
+    /// Read and follow the quadrature encoder
+    Encoder {
+        #[command(subcommand)]
+        command: encoder::EncoderCommand,
+    },
+    /// Show RAM usage and the stack high-water mark on the device
+    Ram,
+    /// Show the clock tree of the device
+    Clocks,
+    /// Levels and waveforms on the DAC outputs
+    Dac {
+        #[command(subcommand)]
+        command: dac::DacCommand,
+    },
+    /// Capture pins with the logic analyzer, for PulseView
+    Logic {
+        #[command(subcommand)]
+        command: logic::LogicCommand,
+    },
+    /// Play words on pins with the pattern generator
+    Pattern {
+        #[command(subcommand)]
+        command: pattern::PatternCommand,
+    },
+    /// Set the device's clock, for absolute timestamps on its topics
+    Time {
+        #[command(subcommand)]
+        command: time::TimeCommand,
+    },
+    /// Show time spent running, sleeping and in STOP on the device
+    Power,
+    /// Show task arena and pool usage on the device
+    Tasks {
+        /// Keep running, printing every spawn the device rejects
+        #[arg(long)]
+        watch: bool,
+    },
+    /// Read a range of internal flash into a file
+    Dump {
+        /// From the start of flash
+        #[arg(value_parser = parse_int::<u32>)]
+        offset: u32,
+        #[arg(value_parser = parse_int::<u32>)]
+        size: u32,
+        output: PathBuf,
+    },
+    /// Flash new firmware over the RPC link. The device needs the bootloader
+    /// and firmware built with the `dfu` feature.
+    Update { elf: PathBuf },
+}
 
 #[tokio::main]
 async fn main() {
+    let cli = Cli::parse();
     let lister = Lister::new();
     let probes = lister.list_all();
     for p in probes.iter() {
@@ -29,45 +168,89 @@ async fn main() {
     let mut session = probe
         .attach(TargetSelector::from("STM32G431VBTx"), Permissions::default())
         .unwrap();
-    let rtt = {
-        let mut core = session.core(0).unwrap();
+    session.core(0).unwrap().reset().unwrap();
+    sleep(Duration::from_millis(1500)).await;
+    let rtt = attach_rtt(&mut session).await.unwrap();
+    let (client, worker) = start_client(session, rtt);
+    hello(&client).await;
+    time::attach(&client).await;
 
-    core.reset().unwrap();
-        sleep(Duration::from_millis(1500)).await;
-
-        eprintln!("Attaching to RTT...");
+    match cli.command.unwrap_or(Command::Schema) {
+        Command::Schema => schema(&client).await,
+        Command::I2c { command } => i2c::run(&client, command).await,
+        Command::Can { command } => can::run(&client, command).await,
+        Command::Capture { command } => capture::run(&client, command).await,
+        Command::Config { command } => config::run(&client, command).await,
+        Command::Uart { command } => uart::run(&client, command).await,
+        Command::Memory { command } => memory::run(&client, command).await,
+        Command::Sleep { millis, timeout_ms } => sleep_device(&client, millis, timeout_ms).await,
+        Command::Job { command } => jobs::run(&client, command).await,
+        Command::Latency { count, load_ms } => latency::run(&client, count, load_ms).await,
+        Command::Encoder { command } => encoder::run(&client, command).await,
+        Command::Ram => ram::run(&client).await,
+        Command::Clocks => clocks::run(&client).await,
+        Command::Dac { command } => dac::run(&client, command).await,
+        Command::Logic { command } => logic::run(&client, command).await,
+        Command::Pattern { command } => pattern::run(&client, command).await,
+        Command::Time { command } => time::run(&client, command).await,
+        Command::Power => power::run(&client).await,
+        Command::Tasks { watch } => tasks::run(&client, watch).await,
+        Command::Dump { offset, size, output } => dump(&client, offset, size, &output).await,
+        Command::Update { elf } => update::run(client, worker, &elf).await,
+    }
+}
 
-        // let mut rtt = Rtt::attach_region(&mut core, &ScanRegion::Ranges(vec![Range{start: 0x20000000, end: 0x20008000}])).unwrap();
-        let mut rtt = Rtt::attach_region(&mut core, &ScanRegion::Ram).unwrap();
-        eprintln!("Found control block at {:#010x}", rtt.ptr());
+/// Parse a number given in decimal, or in hex with a `0x` prefix
+pub fn parse_int<T: TryFrom<u64>>(s: &str) -> Result<T, String> {
+    let val = match s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+        Some(hex) => u64::from_str_radix(hex, 16),
+        None => s.parse::<u64>(),
+    }
+    .map_err(|e| e.to_string())?;
+    T::try_from(val).map_err(|_| format!("{s} is out of range"))
+}
 
-        println!("Up channels:");
-        list_channels(rtt.up_channels());
+/// Format bytes as space separated hex
+pub fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect::<Vec<_>>().join(" ")
+}
 
-        println!("Down channels:");
-        list_channels(rtt.down_channels());
+async fn dump(client: &HostClient<WireError>, offset: u32, size: u32, output: &Path) {
+    let res = bulk::download(client, BulkTarget::Flash { offset, size }, |done, size| {
+        print!("\rRead {done} / {size} bytes");
+        let _ = stdout().flush();
+    })
+    .await;
+    println!();
+    match res {
+        Ok(data) => {
+            if let Err(e) = std::fs::write(output, data) {
+                eprintln!("Cannot write {}: {e}", output.display());
+            }
+        }
+        Err(e) => eprintln!("Dump failed: {e:?}"),
+    }
+}
 
-        sleep(Duration::from_millis(50)).await;
-        rtt
+async fn sleep_device(client: &HostClient<WireError>, millis: u16, timeout_ms: Option<u64>) {
+    let req = SleepMillis { millis };
+    let res = match timeout_ms {
+        Some(ms) => cancel::send_resp_timeout::<CancellableSleepEndpoint>(client, &req, Duration::from_millis(ms)).await,
+        None => cancel::send_resp::<CancellableSleepEndpoint>(client, &req).await.map(Some),
     };
+    match res {
+        Ok(Some(Ok(slept))) => println!("Slept for {} ms", slept.millis),
+        Ok(Some(Err(Cancelled))) => println!("Cancelled"),
+        Ok(None) => {
+            println!("Timed out, cancelling");
+            // Let the cancel go out before the client shuts down
+            sleep(Duration::from_millis(100)).await;
+        }
+        Err(e) => eprintln!("Request failed: {e:?}"),
+    }
+}
 
-    let (out_tx, out_rx) = mpsc::channel(64);
-    let (inc_tx, inc_rx) = mpsc::channel(64);
-
-    let app_rx = ProbeRttRx { inc: inc_rx };
-    let app_tx = ProbeRttTx { out: out_tx };
-
-    std::thread::spawn(move || worker(session, rtt, inc_tx, out_rx));
-
-    let client = HostClient::<WireError>::new_with_wire(
-        app_tx,
-        app_rx,
-        TokSpawn,
-        VarSeqKind::Seq2,
-        "error",
-        64,
-    );
-
+async fn schema(client: &HostClient<WireError>) {
     let mut sub = client.subscribe_multi::<HelloTopic>(64).await.unwrap();
     tokio::task::spawn(async move {
         while let Ok(x) = sub.recv().await {
@@ -146,18 +329,84 @@ async fn process_subscription(mut subscription: RawSubscription, topic_report: &
     }
 }
 
+/// Find the RTT control block of the running firmware
+pub async fn attach_rtt(session: &mut Session) -> Result<Rtt, probe_rs::rtt::Error> {
+    let mut core = session.core(0).unwrap();
+
+    eprintln!("Attaching to RTT...");
+
+    // let mut rtt = Rtt::attach_region(&mut core, &ScanRegion::Ranges(vec![Range{start: 0x20000000, end: 0x20008000}])).unwrap();
+    let mut rtt = Rtt::attach_region(&mut core, &ScanRegion::Ram)?;
+    eprintln!("Found control block at {:#010x}", rtt.ptr());
+
+    println!("Up channels:");
+    list_channels(rtt.up_channels());
+
+    println!("Down channels:");
+    list_channels(rtt.down_channels());
+
+    sleep(Duration::from_millis(50)).await;
+    Ok(rtt)
+}
+
+/// Run a client over RTT, the worker hands the session back once the
+/// client is closed
+pub fn start_client(session: Session, rtt: Rtt) -> (HostClient<WireError>, JoinHandle<Session>) {
+    let (out_tx, out_rx) = mpsc::channel(64);
+    let (inc_tx, inc_rx) = mpsc::channel(64);
+
+    let app_rx = ProbeRttRx { inc: inc_rx };
+    let app_tx = ProbeRttTx { out: out_tx };
+
+    let worker = std::thread::spawn(move || worker(session, rtt, inc_tx, out_rx));
+
+    let client = HostClient::<WireError>::new_with_wire(
+        app_tx,
+        app_rx,
+        TokSpawn,
+        VarSeqKind::Seq2,
+        "error",
+        64,
+    );
+    tokio::spawn(keepalive(client.clone()));
+    (client, worker)
+}
+
+/// Starts a session, which the device announces on `ConnectTopic`
+pub async fn hello(client: &HostClient<WireError>) {
+    // Only has to differ from the last host's
+    let nanos = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().subsec_nanos();
+    let session = nanos ^ std::process::id();
+    if let Err(e) = client.send_resp::<HostHelloEndpoint>(&HostHello { session }).await {
+        eprintln!("Hello failed: {e:?}");
+    }
+}
+
+/// Keeps the device from taking the host for lost while the client is
+/// open, see `config_keys::HOST_TIMEOUT_MS`
+async fn keepalive(client: HostClient<WireError>) {
+    let mut ctr = 0u32;
+    loop {
+        sleep(KEEPALIVE_INTERVAL).await;
+        if client.send_resp::<PingEndpoint>(&ctr).await.is_err() {
+            return;
+        }
+        ctr = ctr.wrapping_add(1);
+    }
+}
+
 fn worker(
     mut session: Session,
     mut rtt: Rtt,
     inc_tx: mpsc::Sender<Vec<u8>>,
     mut out_rx: mpsc::Receiver<Vec<u8>>,
-) {
+) -> Session {
     let mut core = session.core(0).unwrap();
     let mut buf = [0u8; 1024];
     let mut inc_staging = vec![];
     let mut pending_out = None;
 
-    loop {
+    'run: loop {
         let mut progress = false;
         let up = rtt.up_channel(0).unwrap();
         let got = up.read(&mut core, &mut buf).unwrap();
@@ -171,7 +420,9 @@ fn worker(
                     inc_staging.extend_from_slice(now);
                     if let Ok(frame) = decode_vec(&inc_staging) {
                         // println!("RX: Got Frame {}", frame.len());
-                        inc_tx.blocking_send(frame).unwrap();
+                        if inc_tx.blocking_send(frame).is_err() {
+                            break 'run;
+                        }
                     } else {
                         // println!("RX: DECODE FAIL");
                     }
@@ -193,7 +444,7 @@ fn worker(
                     pending_out = Some(out);
                 }
                 Err(mpsc::error::TryRecvError::Empty) => {}
-                Err(mpsc::error::TryRecvError::Disconnected) => panic!(),
+                Err(mpsc::error::TryRecvError::Disconnected) => break 'run,
             }
         }
 
@@ -219,6 +470,8 @@ fn worker(
             std::thread::sleep(Duration::from_millis(5));
         }
     }
+    drop(core);
+    session
 }
 
 fn list_channels(channels: &[impl RttChannel]) {
@@ -0,0 +1,204 @@
+//! The `memory` subcommand, reads and writes raw memory on the device.
+//! With an SVD file registers can be given by name, and are printed field
+//! by field.
+
+use std::path::{Path, PathBuf};
+
+use clap::Subcommand;
+use postcard_rpc::{
+    host_client::{HostClient, HostErr},
+    standard_icd::WireError,
+};
+use svd_parser::svd::{Device, Register};
+use template_icd::{
+    BulkTarget, MemoryData, MemoryError, MemoryRead, MemoryReadEndpoint, MemoryRegionsEndpoint, MemoryUnlockEndpoint,
+    MemoryWrite, MemoryWriteEndpoint, MEMORY_MAX_DATA, MEMORY_UNLOCK_KEY,
+};
+
+use crate::{bulk, hex, parse_int};
+
+#[derive(Subcommand)]
+pub enum MemoryCommand {
+    /// List the regions the firmware allows access to
+    Regions,
+    /// Read memory, or a register
+    Peek {
+        /// An address, or `PERIPHERAL.REGISTER` with an SVD file
+        target: String,
+        /// Bytes to read, by default a register or a word
+        #[arg(value_parser = parse_int::<u32>)]
+        len: Option<u32>,
+        #[arg(long, env = "TEMPLATE_SVD")]
+        svd: Option<PathBuf>,
+    },
+    /// Write a 32-bit word, needs firmware built with the `memory-write` feature
+    Poke {
+        /// An address, or `PERIPHERAL.REGISTER` with an SVD file
+        target: String,
+        #[arg(value_parser = parse_int::<u32>)]
+        value: u32,
+        #[arg(long, env = "TEMPLATE_SVD")]
+        svd: Option<PathBuf>,
+        /// Unlock writes for this one, and lock them again after. Without
+        /// it the write only goes through if writes are already unlocked.
+        #[arg(long)]
+        unlock: bool,
+    },
+}
+
+pub async fn run(client: &HostClient<WireError>, command: MemoryCommand) {
+    match command {
+        MemoryCommand::Regions => match client.send_resp::<MemoryRegionsEndpoint>(&()).await {
+            Ok(regions) => {
+                for r in regions {
+                    let end = r.start as u64 + r.size as u64;
+                    let rw = if r.writable { "rw" } else { "r-" };
+                    println!("{:#010x}..{end:#010x} {rw} {:?} {}", r.start, r.access, r.name);
+                }
+            }
+            Err(e) => eprintln!("Request failed: {e:?}"),
+        },
+        MemoryCommand::Peek { target, len, svd } => {
+            let Some(svd) = load_svd(svd.as_deref()) else {
+                return;
+            };
+            let Some(address) = resolve(svd.as_ref(), &target) else {
+                return;
+            };
+            let register = svd.as_ref().and_then(|svd| find_register(svd, address));
+            let len = len.or(register.as_ref().map(|(_, r)| register_bytes(r))).unwrap_or(4);
+            if len as usize <= MEMORY_MAX_DATA {
+                let res = client.send_resp::<MemoryReadEndpoint>(&MemoryRead { address, len: len as u16 }).await;
+                report(res, |data| print_memory(address, &data, register));
+            } else {
+                match bulk::download(client, BulkTarget::Memory { address, size: len }, |_, _| {}).await {
+                    Ok(data) => print_memory(address, &data, None),
+                    Err(e) => eprintln!("Read failed: {e:?}"),
+                }
+            }
+        }
+        MemoryCommand::Poke {
+            target,
+            value,
+            svd,
+            unlock,
+        } => {
+            let Some(svd) = load_svd(svd.as_deref()) else {
+                return;
+            };
+            let Some(address) = resolve(svd.as_ref(), &target) else {
+                return;
+            };
+            let write = MemoryWrite {
+                address,
+                data: MemoryData::from_slice(&value.to_le_bytes()).unwrap(),
+            };
+            if unlock {
+                let res = client.send_resp::<MemoryUnlockEndpoint>(&MEMORY_UNLOCK_KEY).await;
+                if !matches!(res, Ok(Ok(()))) {
+                    report(res, |()| {});
+                    return;
+                }
+            }
+            let res = client.send_resp::<MemoryWriteEndpoint>(&write).await;
+            if unlock {
+                let _ = client.send_resp::<MemoryUnlockEndpoint>(&0).await;
+            }
+            match res {
+                Ok(Ok(())) => {}
+                Ok(Err(MemoryError::Locked)) => {
+                    eprintln!("Memory error: Locked, pass --unlock to unlock writes for this one");
+                    return;
+                }
+                res => {
+                    report(res, |()| {});
+                    return;
+                }
+            }
+
+            // Registers don't always read back what was written
+            let register = svd.as_ref().and_then(|svd| find_register(svd, address));
+            let res = client.send_resp::<MemoryReadEndpoint>(&MemoryRead { address, len: 4 }).await;
+            report(res, |data| print_memory(address, &data, register));
+        }
+    }
+}
+
+fn load_svd(path: Option<&Path>) -> Option<Option<Device>> {
+    let Some(path) = path else {
+        return Some(None);
+    };
+    let config = svd_parser::Config::default().expand(true);
+    let device = std::fs::read_to_string(path)
+        .map_err(|e| e.to_string())
+        .and_then(|xml| svd_parser::parse_with_config(&xml, &config).map_err(|e| e.to_string()));
+    match device {
+        Ok(device) => Some(Some(device)),
+        Err(e) => {
+            eprintln!("Cannot load {}: {e}", path.display());
+            None
+        }
+    }
+}
+
+/// An address, or a register named in the SVD
+fn resolve(svd: Option<&Device>, target: &str) -> Option<u32> {
+    if let Ok(address) = parse_int::<u32>(target) {
+        return Some(address);
+    }
+    let Some(svd) = svd else {
+        eprintln!("{target} is not an address, registers can only be named with an SVD file");
+        return None;
+    };
+    let address = target.split_once('.').and_then(|(periph, reg)| {
+        let periph = svd.peripherals.iter().find(|p| p.name.eq_ignore_ascii_case(periph))?;
+        let register = periph.registers().find(|r| r.name.eq_ignore_ascii_case(reg))?;
+        Some(periph.base_address as u32 + register.address_offset)
+    });
+    if address.is_none() {
+        eprintln!("No register {target} in the SVD file");
+    }
+    address
+}
+
+/// The register at an address, with its full name
+fn find_register(svd: &Device, address: u32) -> Option<(String, &Register)> {
+    svd.peripherals.iter().find_map(|p| {
+        let register = p
+            .registers()
+            .find(|r| p.base_address as u32 + r.address_offset == address)?;
+        Some((format!("{}.{}", p.name, register.name), register))
+    })
+}
+
+fn register_bytes(register: &Register) -> u32 {
+    register.properties.size.unwrap_or(32) / 8
+}
+
+fn print_memory(address: u32, data: &[u8], register: Option<(String, &Register)>) {
+    let Some((name, register)) = register.filter(|_| data.len() == 4) else {
+        for (i, line) in data.chunks(16).enumerate() {
+            println!("{:#010x}: {}", address as usize + 16 * i, hex(line));
+        }
+        return;
+    };
+    let value = u32::from_le_bytes(data.try_into().unwrap());
+    println!("{name} @ {address:#010x} = {value:#010x}");
+    for field in register.fields() {
+        let (offset, width) = (field.bit_offset(), field.bit_width());
+        let mask = if width >= 32 { u32::MAX } else { (1 << width) - 1 };
+        let bits = match width {
+            1 => format!("{offset}"),
+            _ => format!("{}:{offset}", offset + width - 1),
+        };
+        println!("  {:<12} [{bits}] = {:#x}", field.name, (value >> offset) & mask);
+    }
+}
+
+fn report<T>(res: Result<Result<T, MemoryError>, HostErr<WireError>>, ok: impl FnOnce(T)) {
+    match res {
+        Ok(Ok(t)) => ok(t),
+        Ok(Err(e)) => eprintln!("Memory error: {e:?}"),
+        Err(e) => eprintln!("Request failed: {e:?}"),
+    }
+}
@@ -0,0 +1,156 @@
+//! The `pattern` subcommand, plays words on pins of the device with its
+//! pattern generator
+//!
+//! Patterns are text files of words, a byte each, separated by whitespace.
+//! Words are decimal, `0x` hex or `0b` binary, and `#` starts a comment.
+
+use std::path::PathBuf;
+
+use clap::{Subcommand, ValueEnum};
+use postcard_rpc::{
+    host_client::{HostClient, HostErr},
+    standard_icd::WireError,
+};
+use template_icd::{
+    BulkTarget, LogicEdge, LogicPins, LogicPort, PatternError, PatternPlay, PatternPlayEndpoint, PatternRepeat,
+    PatternStatusEndpoint, PatternStopEndpoint, PATTERN_MAX_WORDS,
+};
+
+use crate::{bulk, logic::Port, parse_int};
+
+#[derive(Subcommand)]
+pub enum PatternCommand {
+    /// Upload the pattern in `file`, then play it
+    Play {
+        #[arg(value_enum)]
+        port: Port,
+        /// Pins 8 to 15 rather than 0 to 7
+        #[arg(long)]
+        high_byte: bool,
+        /// The channels to play, 0 to 7
+        #[arg(long, value_delimiter = ',', default_values_t = [0, 1, 2, 3, 4, 5, 6, 7])]
+        channels: Vec<u8>,
+        /// Words per second
+        #[arg(long, default_value_t = 1_000_000)]
+        rate: u32,
+        /// Play it this many times, once without
+        #[arg(long, conflicts_with = "continuous")]
+        times: Option<u32>,
+        /// Play it until `pattern stop`
+        #[arg(long)]
+        continuous: bool,
+        /// Wait for this edge on PA10 first
+        #[arg(long, value_enum)]
+        trigger: Option<Edge>,
+        file: PathBuf,
+    },
+    /// Stop playing, the pins go back to what they were before
+    Stop,
+    /// Print what the generator is doing
+    Status,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+pub enum Edge {
+    Rise,
+    Fall,
+    Either,
+}
+
+pub async fn run(client: &HostClient<WireError>, command: PatternCommand) {
+    match command {
+        PatternCommand::Play {
+            port,
+            high_byte,
+            channels,
+            rate,
+            times,
+            continuous,
+            trigger,
+            file,
+        } => {
+            let Some(channels) = channels.iter().try_fold(0u8, |mask, &c| (c < 8).then(|| mask | 1 << c)) else {
+                eprintln!("Channels go from 0 to 7");
+                return;
+            };
+            let words = match std::fs::read_to_string(&file).map_err(|e| e.to_string()).and_then(|s| parse(&s)) {
+                Ok(words) => words,
+                Err(e) => {
+                    eprintln!("Cannot read {}: {e}", file.display());
+                    return;
+                }
+            };
+            if words.is_empty() || words.len() > usize::from(PATTERN_MAX_WORDS) {
+                eprintln!("Patterns have 1 to {PATTERN_MAX_WORDS} words, this one has {}", words.len());
+                return;
+            }
+            let target = BulkTarget::Pattern {
+                words: words.len() as u16,
+            };
+            if let Err(e) = bulk::upload(client, target, &words, |_, _| {}).await {
+                eprintln!("Upload failed: {e:?}");
+                return;
+            }
+            let play = PatternPlay {
+                pins: LogicPins {
+                    port: match port {
+                        Port::A => LogicPort::A,
+                        Port::B => LogicPort::B,
+                        Port::C => LogicPort::C,
+                        Port::D => LogicPort::D,
+                        Port::E => LogicPort::E,
+                        Port::F => LogicPort::F,
+                        Port::G => LogicPort::G,
+                    },
+                    high_byte,
+                    channels,
+                },
+                rate_hz: rate,
+                repeat: match (continuous, times) {
+                    (true, _) => PatternRepeat::Continuous,
+                    (false, Some(times)) => PatternRepeat::Times(times),
+                    (false, None) => PatternRepeat::Once,
+                },
+                trigger: trigger.map(|edge| match edge {
+                    Edge::Rise => LogicEdge::Rising,
+                    Edge::Fall => LogicEdge::Falling,
+                    Edge::Either => LogicEdge::Either,
+                }),
+            };
+            let res = client.send_resp::<PatternPlayEndpoint>(&play).await;
+            report(res, |rate_hz| {
+                println!("Playing {} words at {rate_hz} Hz", words.len());
+            });
+        }
+        PatternCommand::Stop => match client.send_resp::<PatternStopEndpoint>(&()).await {
+            Ok(()) => println!("ok"),
+            Err(e) => eprintln!("Request failed: {e:?}"),
+        },
+        PatternCommand::Status => match client.send_resp::<PatternStatusEndpoint>(&()).await {
+            Ok(status) => println!(
+                "{:?}, {} words uploaded, last played at {} Hz",
+                status.state, status.words, status.rate_hz
+            ),
+            Err(e) => eprintln!("Request failed: {e:?}"),
+        },
+    }
+}
+
+/// The words of a pattern file, see the module docs
+fn parse(text: &str) -> Result<Vec<u8>, String> {
+    text.lines()
+        .flat_map(|line| line.split('#').next().unwrap_or_default().split_whitespace())
+        .map(|word| match word.strip_prefix("0b").or_else(|| word.strip_prefix("0B")) {
+            Some(bits) => u8::from_str_radix(bits, 2).map_err(|e| format!("{word}: {e}")),
+            None => parse_int(word),
+        })
+        .collect()
+}
+
+fn report<T>(res: Result<Result<T, PatternError>, HostErr<WireError>>, ok: impl FnOnce(T)) {
+    match res {
+        Ok(Ok(t)) => ok(t),
+        Ok(Err(e)) => eprintln!("Pattern error: {e:?}"),
+        Err(e) => eprintln!("Request failed: {e:?}"),
+    }
+}
@@ -0,0 +1,28 @@
+//! The `power` subcommand, shows how long the device spent in each power
+//! mode
+
+use postcard_rpc::{host_client::HostClient, standard_icd::WireError};
+use template_icd::{PowerStats, PowerStatsEndpoint};
+
+pub async fn run(client: &HostClient<WireError>) {
+    match client.send_resp::<PowerStatsEndpoint>(&()).await {
+        Ok(stats) => print_stats(&stats),
+        Err(e) => eprintln!("Request failed: {e:?}"),
+    }
+}
+
+fn print_stats(stats: &PowerStats) {
+    let total = (stats.run_ms + stats.sleep_ms + stats.stop_ms).max(1);
+    let line = |name: &str, ms: u64| println!("{name:<6} {ms:>10} ms {:>5.1}%", ms as f64 * 100.0 / total as f64);
+    line("Run", stats.run_ms);
+    line("Sleep", stats.sleep_ms);
+    if stats.low_power {
+        line("Stop", stats.stop_ms);
+        match stats.stop_ready {
+            true => println!("STOP is allowed right now"),
+            false => println!("A peripheral keeps the core out of STOP right now"),
+        }
+    } else {
+        println!("Built without the `low-power` feature, the core never enters STOP");
+    }
+}
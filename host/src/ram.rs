@@ -0,0 +1,33 @@
+//! The `ram` subcommand, shows where the device's RAM goes and how deep
+//! its stack has been
+
+use postcard_rpc::{host_client::HostClient, standard_icd::WireError};
+use template_icd::{RamStats, RamStatsEndpoint};
+
+pub async fn run(client: &HostClient<WireError>) {
+    match client.send_resp::<RamStatsEndpoint>(&()).await {
+        Ok(stats) => print_stats(&stats),
+        Err(e) => eprintln!("Request failed: {e:?}"),
+    }
+}
+
+fn print_stats(stats: &RamStats) {
+    let percent = |part: u32, whole: u32| 100 * part / whole.max(1);
+    println!("RAM:        {:>6} bytes", stats.ram_size);
+    println!(
+        "Statics:    {:>6} bytes ({}%)",
+        stats.statics,
+        percent(stats.statics, stats.ram_size)
+    );
+    println!(
+        "Stack peak: {:>6} of {} bytes ({}%)",
+        stats.stack_peak,
+        stats.stack_size,
+        percent(stats.stack_peak, stats.stack_size)
+    );
+    println!("Free:       {:>6} bytes", stats.free);
+    println!("Buffers:");
+    for b in &stats.buffers {
+        println!("  {:<16} {:>6}", b.name, b.size);
+    }
+}
@@ -0,0 +1,49 @@
+//! The `tasks` subcommand, shows how full the executor's task arena and
+//! pools are, and can keep watching for spawns the device had to refuse
+
+use postcard_rpc::{host_client::HostClient, standard_icd::WireError};
+use template_icd::{ExecutorStats, ExecutorStatsEndpoint, SpawnRejected, SpawnRejectedTopic};
+
+pub async fn run(client: &HostClient<WireError>, watch: bool) {
+    // Subscribed first so nothing is missed between the two
+    let sub = match watch {
+        true => match client.subscribe_multi::<SpawnRejectedTopic>(16).await {
+            Ok(sub) => Some(sub),
+            Err(e) => {
+                eprintln!("Could not subscribe: {e:?}");
+                return;
+            }
+        },
+        false => None,
+    };
+    match client.send_resp::<ExecutorStatsEndpoint>(&()).await {
+        Ok(stats) => print_stats(&stats),
+        Err(e) => {
+            eprintln!("Request failed: {e:?}");
+            return;
+        }
+    }
+
+    let Some(mut sub) = sub else {
+        return;
+    };
+    println!("Watching for rejected spawns...");
+    while let Ok(SpawnRejected { name, rejected }) = sub.recv().await {
+        println!("{name}: spawn rejected, {rejected} since boot");
+    }
+}
+
+fn print_stats(stats: &ExecutorStats) {
+    let percent = 100 * stats.arena_used / stats.arena_size.max(1);
+    println!("Arena: {} / {} bytes used ({percent}%)", stats.arena_used, stats.arena_size);
+    println!(
+        "{:<16} {:>9} {:>10} {:>8} {:>8} {:>6}",
+        "task", "running", "high water", "spawned", "rejected", "bytes"
+    );
+    for p in &stats.pools {
+        println!(
+            "{:<16} {:>3} / {:<3} {:>10} {:>8} {:>8} {:>6}",
+            p.name, p.running, p.size, p.high_water, p.spawned, p.rejected, p.arena_bytes
+        );
+    }
+}
@@ -0,0 +1,127 @@
+//! The `time` subcommand, sets the device's clock from ours
+//!
+//! The device is asked for its uptime a few times over, and the answer
+//! that came back quickest is taken to have been read halfway through its
+//! round trip. Setting the time again later lets the device measure how
+//! fast its clock runs against ours, and correct for it.
+//!
+//! The device is reset every time we attach, which loses its time, so
+//! [`attach`] sets it before any subcommand runs.
+
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use clap::Subcommand;
+use postcard_rpc::{
+    host_client::{HostClient, HostErr},
+    standard_icd::WireError,
+};
+use template_icd::{DeviceTime, TimeNowEndpoint, TimeSet, TimeSetEndpoint, TimeSetResult};
+
+/// Round trips per setting, the quickest one is used
+const SAMPLES: usize = 8;
+
+#[derive(Subcommand)]
+pub enum TimeCommand {
+    /// Compare the device's time with ours
+    Show,
+    /// Set the device's time to ours
+    Sync {
+        /// Keep setting it this often, in seconds, reporting the drift
+        #[arg(long)]
+        every: Option<u64>,
+    },
+}
+
+pub async fn run(client: &HostClient<WireError>, command: TimeCommand) {
+    let res = match command {
+        TimeCommand::Show => show(client).await,
+        TimeCommand::Sync { every: None } => sync(client).await,
+        TimeCommand::Sync { every: Some(secs) } => loop {
+            if let Err(e) = sync(client).await {
+                break Err(e);
+            }
+            tokio::time::sleep(Duration::from_secs(secs)).await;
+        },
+    };
+    if let Err(e) = res {
+        eprintln!("Request failed: {e:?}");
+    }
+}
+
+fn unix_us() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_micros() as u64
+}
+
+/// The device's time, our time at the moment it was read, and the round trip
+async fn sample(client: &HostClient<WireError>) -> Result<(DeviceTime, u64, Duration), HostErr<WireError>> {
+    let mut best = None;
+    for _ in 0..SAMPLES {
+        let sent_us = unix_us();
+        let start = Instant::now();
+        let time = client.send_resp::<TimeNowEndpoint>(&()).await?;
+        let rtt = start.elapsed();
+        if best.as_ref().is_none_or(|(_, _, best_rtt)| rtt < *best_rtt) {
+            best = Some((time, sent_us + rtt.as_micros() as u64 / 2, rtt));
+        }
+    }
+    Ok(best.expect("SAMPLES is not zero"))
+}
+
+async fn show(client: &HostClient<WireError>) -> Result<(), HostErr<WireError>> {
+    let (time, ours, rtt) = sample(client).await?;
+    println!("Uptime:      {}", secs(time.uptime_us));
+    match time.unix_us {
+        Some(theirs) => {
+            println!("Device time: {}", secs(theirs));
+            println!("Host time:   {}", secs(ours));
+            println!("Ahead by:    {:.3} ms", (theirs as i64 - ours as i64) as f64 / 1000.0);
+        }
+        None => println!("Device time: not set"),
+    }
+    println!("Round trip:  {:.3} ms", rtt.as_secs_f64() * 1000.0);
+    Ok(())
+}
+
+/// Sets the device's time to ours, for timestamps in later subcommands
+pub async fn attach(client: &HostClient<WireError>) {
+    match set(client).await {
+        Ok((_, _, Ok(_))) => {}
+        Ok((_, _, Err(e))) => eprintln!("Cannot set the device's time: {e:?}"),
+        Err(e) => eprintln!("Cannot set the device's time: {e:?}"),
+    }
+}
+
+/// The time we set, the round trip it was read with, and the device's answer
+async fn set(client: &HostClient<WireError>) -> Result<(u64, Duration, TimeSetResult), HostErr<WireError>> {
+    let (time, unix_us, rtt) = sample(client).await?;
+    let set = TimeSet {
+        uptime_us: time.uptime_us,
+        unix_us,
+    };
+    let res = client.send_resp::<TimeSetEndpoint>(&set).await?;
+    Ok((unix_us, rtt, res))
+}
+
+async fn sync(client: &HostClient<WireError>) -> Result<(), HostErr<WireError>> {
+    let (unix_us, rtt, res) = set(client).await?;
+    let report = match res {
+        Ok(report) => report,
+        Err(e) => {
+            eprintln!("Time error: {e:?}");
+            return Ok(());
+        }
+    };
+    print!("Set to {}, round trip {:.3} ms", secs(unix_us), rtt.as_secs_f64() * 1000.0);
+    if let Some(error_us) = report.error_us {
+        print!(", was ahead by {:.3} ms", error_us as f64 / 1000.0);
+    }
+    if let Some(drift_ppb) = report.drift_ppb {
+        print!(", drift {:.3} ppm", drift_ppb as f64 / 1000.0);
+    }
+    println!();
+    Ok(())
+}
+
+fn secs(us: u64) -> String {
+    format!("{}.{:06}", us / 1_000_000, us % 1_000_000)
+}
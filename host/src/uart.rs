@@ -0,0 +1,157 @@
+//! The `uart` subcommand, exposes the UART passthrough as a pseudo-terminal
+
+use std::{
+    fs::File,
+    io::{Read, Write},
+    sync::mpsc as std_mpsc,
+};
+
+use clap::{Subcommand, ValueEnum};
+use nix::{
+    pty::openpty,
+    sys::termios::{cfmakeraw, tcgetattr, tcsetattr, SetArg},
+    unistd::ttyname,
+};
+use postcard_rpc::{header::VarSeq, host_client::HostClient, standard_icd::WireError};
+use template_icd::{
+    ConfigureUartEndpoint, UartConfig, UartData, UartParity, UartRxTopic, UartStopBits, UartTxTopic,
+    UART_MAX_CHUNK,
+};
+use tokio::sync::mpsc;
+
+#[derive(Subcommand)]
+pub enum UartCommand {
+    /// Configure the UART and bridge it to a new pseudo-terminal, until
+    /// interrupted. Open the printed path with any serial tool.
+    Bridge {
+        #[arg(long, default_value_t = 115_200)]
+        baud: u32,
+        #[arg(long, value_enum, default_value_t = Parity::None)]
+        parity: Parity,
+        #[arg(long, value_enum, default_value_t = StopBits::One)]
+        stop_bits: StopBits,
+    },
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+pub enum Parity {
+    None,
+    Even,
+    Odd,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+pub enum StopBits {
+    #[value(name = "1")]
+    One,
+    #[value(name = "0.5")]
+    Half,
+    #[value(name = "2")]
+    Two,
+    #[value(name = "1.5")]
+    OneAndHalf,
+}
+
+pub async fn run(client: &HostClient<WireError>, command: UartCommand) {
+    match command {
+        UartCommand::Bridge {
+            baud,
+            parity,
+            stop_bits,
+        } => {
+            let config = UartConfig {
+                baud_rate: baud,
+                parity: match parity {
+                    Parity::None => UartParity::None,
+                    Parity::Even => UartParity::Even,
+                    Parity::Odd => UartParity::Odd,
+                },
+                stop_bits: match stop_bits {
+                    StopBits::One => UartStopBits::One,
+                    StopBits::Half => UartStopBits::Half,
+                    StopBits::Two => UartStopBits::Two,
+                    StopBits::OneAndHalf => UartStopBits::OneAndHalf,
+                },
+            };
+            match client.send_resp::<ConfigureUartEndpoint>(&config).await {
+                Ok(Ok(())) => bridge(client).await,
+                Ok(Err(e)) => eprintln!("UART error: {e:?}"),
+                Err(e) => eprintln!("Request failed: {e:?}"),
+            }
+        }
+    }
+}
+
+async fn bridge(client: &HostClient<WireError>) {
+    let pty = match openpty(None, None) {
+        Ok(pty) => pty,
+        Err(e) => {
+            eprintln!("Could not open a pty: {e}");
+            return;
+        }
+    };
+    // Pass bytes through untouched, no echo or line editing
+    if let Ok(mut termios) = tcgetattr(&pty.slave) {
+        cfmakeraw(&mut termios);
+        let _ = tcsetattr(&pty.slave, SetArg::TCSANOW, &termios);
+    }
+    match ttyname(&pty.slave) {
+        Ok(path) => println!("UART bridged to {}", path.display()),
+        Err(e) => eprintln!("Bridging to an unnamed pty: {e}"),
+    }
+    // Keep our own handle on the slave, otherwise reading the master fails
+    // whenever no serial tool has it open
+    let _slave = pty.slave;
+    let mut reader = File::from(pty.master);
+    let Ok(mut writer) = reader.try_clone() else {
+        eprintln!("Could not duplicate the pty handle");
+        return;
+    };
+
+    // The pty is blocking, so both directions get their own thread
+    let (to_device, mut from_pty) = mpsc::channel::<Vec<u8>>(16);
+    std::thread::spawn(move || {
+        let mut buf = [0u8; UART_MAX_CHUNK];
+        while let Ok(n @ 1..) = reader.read(&mut buf) {
+            if to_device.blocking_send(buf[..n].to_vec()).is_err() {
+                break;
+            }
+        }
+    });
+    let (to_pty, from_device) = std_mpsc::channel::<UartData>();
+    std::thread::spawn(move || {
+        for data in from_device {
+            if writer.write_all(&data).is_err() {
+                break;
+            }
+        }
+    });
+
+    let mut sub = match client.subscribe_multi::<UartRxTopic>(64).await {
+        Ok(sub) => sub,
+        Err(e) => {
+            eprintln!("Could not subscribe: {e:?}");
+            return;
+        }
+    };
+    tokio::task::spawn(async move {
+        while let Ok(data) = sub.recv().await {
+            if to_pty.send(data).is_err() {
+                break;
+            }
+        }
+    });
+
+    let mut seq = 0u16;
+    while let Some(chunk) = from_pty.recv().await {
+        // Reads are capped at one chunk, so this always fits
+        let Ok(data) = UartData::from_slice(&chunk) else {
+            continue;
+        };
+        if client.publish::<UartTxTopic>(VarSeq::Seq2(seq), &data).await.is_err() {
+            eprintln!("Device connection closed");
+            break;
+        }
+        seq = seq.wrapping_add(1);
+    }
+}
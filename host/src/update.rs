@@ -0,0 +1,163 @@
+//! The `update` subcommand, writes a new image through the bootloader's DFU
+//! slot and stays attached while it boots on trial to confirm it
+
+use std::{
+    io::{stdout, Write},
+    path::Path,
+    thread::JoinHandle,
+    time::{Duration, Instant},
+};
+
+use object::{
+    elf::PT_LOAD,
+    read::elf::{ElfFile32, ProgramHeader},
+    Endianness,
+};
+use postcard_rpc::{
+    host_client::{HostClient, HostErr},
+    standard_icd::WireError,
+};
+use probe_rs::Session;
+use sha2::{Digest, Sha256};
+use template_icd::{
+    BulkTarget, FirmwareState, GetFirmwareStateEndpoint, GetUniqueIdEndpoint, ResetEndpoint, UpdateError,
+    UpdateStart,
+};
+use tokio::time::sleep;
+
+use crate::{attach_rtt, bulk, hello, start_client};
+
+/// The flash write granularity of the G431
+const WRITE_SIZE: usize = 8;
+
+/// Well inside the firmware's 30 second trial
+const SWAP_TIMEOUT: Duration = Duration::from_secs(20);
+
+pub async fn run(client: HostClient<WireError>, worker: JoinHandle<Session>, elf: &Path) {
+    let (address, image) = match load_image(elf) {
+        Ok(image) => image,
+        Err(e) => {
+            eprintln!("Cannot load {}: {e}", elf.display());
+            return;
+        }
+    };
+    println!("Image of {} bytes at {address:#010x}", image.len());
+
+    let start = UpdateStart {
+        address,
+        size: image.len() as u32,
+        sha256: Sha256::digest(&image).into(),
+    };
+    // Opening erases the slot, which takes a while
+    let res = bulk::upload(&client, BulkTarget::Firmware(start), &image, |done, size| {
+        print!("\rWritten {done} / {size} bytes");
+        let _ = stdout().flush();
+    })
+    .await;
+    println!();
+    if let Err(e) = res {
+        eprintln!("Update failed: {e:?}");
+        return;
+    }
+
+    println!("Resetting into the bootloader...");
+    if let Err(e) = client.send_resp::<ResetEndpoint>(&()).await {
+        eprintln!("Request failed: {e:?}");
+        return;
+    }
+    client.close();
+    let mut session = tokio::task::spawn_blocking(move || worker.join().unwrap()).await.unwrap();
+
+    // Resetting through the probe now would roll the new image back, so
+    // only watch the core until it has left the bootloader
+    if let Err(e) = wait_for_image(&mut session, address).await {
+        eprintln!("New image did not start: {e}");
+        return;
+    }
+    // Let the firmware set up its RTT control block
+    sleep(Duration::from_millis(500)).await;
+    let rtt = match attach_rtt(&mut session).await {
+        Ok(rtt) => rtt,
+        Err(e) => {
+            eprintln!("Cannot attach to the new image: {e:?}");
+            return;
+        }
+    };
+    let (client, _worker) = start_client(session, rtt);
+    hello(&client).await;
+
+    match client.send_resp::<GetFirmwareStateEndpoint>(&()).await {
+        Ok(Ok(FirmwareState::Trial)) => {}
+        Ok(Ok(FirmwareState::Confirmed)) => {
+            eprintln!("The bootloader did not swap the image in");
+            return;
+        }
+        res => {
+            report(res, |_| {});
+            return;
+        }
+    }
+    // Any answer to this confirms the new image
+    if let Err(e) = client.send_resp::<GetUniqueIdEndpoint>(&()).await {
+        eprintln!("Request failed: {e:?}");
+        return;
+    }
+    match client.send_resp::<GetFirmwareStateEndpoint>(&()).await {
+        Ok(Ok(FirmwareState::Confirmed)) => println!("Update confirmed"),
+        Ok(Ok(FirmwareState::Trial)) => eprintln!("Update not confirmed, it rolls back on the next reset"),
+        res => report(res, |_| {}),
+    }
+}
+
+/// The loadable segments of the ELF as one flash image, with gaps left
+/// erased and the end padded to whole flash words
+fn load_image(path: &Path) -> Result<(u32, Vec<u8>), String> {
+    let data = std::fs::read(path).map_err(|e| e.to_string())?;
+    let elf = ElfFile32::<Endianness>::parse(&*data).map_err(|e| e.to_string())?;
+    let endian = elf.endian();
+    let segments = elf
+        .elf_program_headers()
+        .iter()
+        .filter(|ph| ph.p_type(endian) == PT_LOAD && ph.p_filesz(endian) > 0)
+        .map(|ph| {
+            let bytes = ph.data(endian, &*data).map_err(|()| "segment out of bounds".to_string())?;
+            Ok((ph.p_paddr(endian), bytes))
+        })
+        .collect::<Result<Vec<_>, String>>()?;
+    let address = segments.iter().map(|(addr, _)| *addr).min().ok_or("no loadable segments")?;
+
+    let mut image = vec![];
+    for (addr, bytes) in segments {
+        let offset = (addr - address) as usize;
+        if image.len() < offset + bytes.len() {
+            image.resize(offset + bytes.len(), 0xFF);
+        }
+        image[offset..offset + bytes.len()].copy_from_slice(bytes);
+    }
+    image.resize(image.len().next_multiple_of(WRITE_SIZE), 0xFF);
+    Ok((address, image))
+}
+
+/// Poll the program counter until the core runs from the new image, the
+/// bootloader itself lives below it
+async fn wait_for_image(session: &mut Session, address: u32) -> Result<(), probe_rs::Error> {
+    let started = Instant::now();
+    while started.elapsed() < SWAP_TIMEOUT {
+        sleep(Duration::from_millis(200)).await;
+        let mut core = session.core(0)?;
+        let pc = core.halt(Duration::from_millis(100))?.pc;
+        core.run()?;
+        if pc >= address as u64 {
+            return Ok(());
+        }
+    }
+    Err(probe_rs::Error::Timeout)
+}
+
+fn report<T>(res: Result<Result<T, UpdateError>, HostErr<WireError>>, ok: impl FnOnce(T)) {
+    match res {
+        Ok(Ok(t)) => ok(t),
+        Ok(Err(e)) => eprintln!("Update error: {e:?}"),
+        Err(e) => eprintln!("Request failed: {e:?}"),
+    }
+}
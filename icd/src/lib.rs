@@ -14,39 +14,1345 @@ pub struct SleptMillis {
     pub millis: u16,
 }
 
-#[derive(Debug, Serialize, Deserialize, Schema)]
+/// The answer to a spawned request the host cancelled, by publishing its
+/// sequence number to [`CancelTopic`]
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, Schema)]
+pub struct Cancelled;
+
+pub type SleepResult = Result<SleptMillis, Cancelled>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, Schema)]
 pub enum LedState {
     Off,
     On,
 }
 
+/// The LED's duty cycle, see [`DUTY_MAX`]. [`GetLedEndpoint`] reports a
+/// dimmed LED as on.
+pub type LedBrightness = u16;
+
 #[derive(Debug, Serialize, Deserialize, Schema)]
 pub struct HelloWorld {
     pub uptime: u64,
 }
 
+// --- PWM
+
+/// Duty cycles are given in hundredths of a percent, this is 100%
+pub const DUTY_MAX: u16 = 10_000;
+
+/// Timer channels that can be driven as PWM outputs
+///
+/// Channels on the same timer share one frequency.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, Schema)]
+pub enum PwmChannel {
+    /// TIM3 CH1 on PA6
+    Tim3Ch1,
+    /// TIM3 CH3 on PB0
+    Tim3Ch3,
+    /// TIM3 CH4 on PB1
+    Tim3Ch4,
+    /// TIM8 CH1 on PA15, with the complementary CH1N on PA7
+    Tim8Ch1,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, Schema)]
+pub enum PwmPolarity {
+    ActiveHigh,
+    ActiveLow,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, Schema)]
+pub struct ComplementaryOutput {
+    /// Time both outputs are held inactive around each edge
+    pub dead_time_ns: u32,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, Schema)]
+pub struct PwmConfig {
+    pub channel: PwmChannel,
+    pub frequency_hz: u32,
+    pub duty: u16,
+    pub polarity: PwmPolarity,
+    /// Only available on channels that have a CHxN pin
+    pub complementary: Option<ComplementaryOutput>,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, Schema)]
+pub struct PwmDuty {
+    pub channel: PwmChannel,
+    pub duty: u16,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, Schema)]
+pub struct PwmStatus {
+    pub channel: PwmChannel,
+    pub enabled: bool,
+    /// The frequency the timer actually runs at, after rounding
+    pub frequency_hz: u32,
+    pub duty: u16,
+    /// Number of distinct duty steps at this frequency
+    pub resolution: u16,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, Schema)]
+pub enum PwmError {
+    FrequencyOutOfRange,
+    DutyOutOfRange,
+    NoComplementaryOutput,
+    DeadTimeOutOfRange,
+    NotConfigured,
+}
+
+pub type PwmResult = Result<PwmStatus, PwmError>;
+
+// --- I2C
+
+/// Largest payload of a single I2C read or write
+pub const I2C_MAX_PAYLOAD: usize = 255;
+
+pub type I2cData = heapless::Vec<u8, I2C_MAX_PAYLOAD>;
+
+/// 7-bit addresses of the devices that answered a scan
+pub type I2cAddresses = heapless::Vec<u8, 128>;
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, Schema)]
+pub struct I2cConfig {
+    /// Bus speed, 10 kHz to 1 MHz
+    pub frequency_hz: u32,
+    /// Applies to each operation as a whole
+    pub timeout_ms: u32,
+}
+
+/// A register address, sent before the data of a register access
+///
+/// `Word` addresses are sent big-endian.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, Schema)]
+pub enum I2cRegister {
+    Byte(u8),
+    Word(u16),
+}
+
+#[derive(Debug, Serialize, Deserialize, Schema)]
+pub struct I2cWrite {
+    pub address: u8,
+    pub data: I2cData,
+}
+
+#[derive(Debug, Serialize, Deserialize, Schema)]
+pub struct I2cRead {
+    pub address: u8,
+    pub len: u16,
+}
+
+#[derive(Debug, Serialize, Deserialize, Schema)]
+pub struct I2cWriteRead {
+    pub address: u8,
+    pub data: I2cData,
+    pub read_len: u16,
+}
+
+#[derive(Debug, Serialize, Deserialize, Schema)]
+pub struct I2cRegisterRead {
+    pub address: u8,
+    pub register: I2cRegister,
+    pub len: u16,
+}
+
+#[derive(Debug, Serialize, Deserialize, Schema)]
+pub struct I2cRegisterWrite {
+    pub address: u8,
+    pub register: I2cRegister,
+    pub data: I2cData,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, Schema)]
+pub enum I2cError {
+    /// The address or a data byte was not acknowledged
+    Nack,
+    ArbitrationLost,
+    Timeout,
+    /// Misplaced start/stop, overrun or any other bus fault
+    Bus,
+    /// Not a 7-bit address
+    InvalidAddress,
+    /// Zero length, or longer than [`I2C_MAX_PAYLOAD`]
+    InvalidLength,
+    InvalidFrequency,
+}
+
+pub type I2cResult = Result<(), I2cError>;
+pub type I2cReadResult = Result<I2cData, I2cError>;
+pub type I2cScanResult = Result<I2cAddresses, I2cError>;
+
+// --- SPI
+
+/// Room for data in one transaction, what is left of a frame after the
+/// header and the op list
+pub const SPI_MAX_DATA: usize = 896;
+/// Most ops in one transaction
+pub const SPI_MAX_OPS: usize = 16;
+
+pub type SpiData = heapless::Vec<u8, SPI_MAX_DATA>;
+pub type SpiOps = heapless::Vec<SpiOp, SPI_MAX_OPS>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, Schema)]
+pub enum SpiMode {
+    /// CPOL = 0, CPHA = 0
+    Mode0,
+    /// CPOL = 0, CPHA = 1
+    Mode1,
+    /// CPOL = 1, CPHA = 0
+    Mode2,
+    /// CPOL = 1, CPHA = 1
+    Mode3,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, Schema)]
+pub enum SpiBitOrder {
+    MsbFirst,
+    LsbFirst,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, Schema)]
+pub struct SpiConfig {
+    pub mode: SpiMode,
+    /// Rounded down to the nearest available divider of the SPI clock
+    pub frequency_hz: u32,
+    pub bit_order: SpiBitOrder,
+}
+
+/// GPIOs that can be used as chip selects
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, Schema)]
+pub enum SpiCsPin {
+    Pb10,
+    Pb11,
+    Pb12,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, Schema)]
+pub struct SpiCsConfig {
+    pub pin: SpiCsPin,
+    /// Most devices select on a low level
+    pub active_high: bool,
+}
+
+/// One step of a transaction
+///
+/// Bytes to send are taken in order from [`SpiTransaction::data`], bytes
+/// received are appended in order to the reply.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, Schema)]
+pub enum SpiOp {
+    /// Send this many bytes, ignoring what comes back
+    Write(u16),
+    /// Receive this many bytes
+    Read(u16),
+    /// Send and receive this many bytes at the same time
+    Transfer(u16),
+    DelayUs(u32),
+}
+
+#[derive(Debug, Serialize, Deserialize, Schema)]
+pub struct SpiTransaction {
+    /// Held active from before the first op until after the last one
+    pub cs: Option<SpiCsPin>,
+    pub ops: SpiOps,
+    pub data: SpiData,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, Schema)]
+pub enum SpiError {
+    InvalidFrequency,
+    /// The chip select has not been declared with [`DeclareSpiCsEndpoint`]
+    CsNotDeclared,
+    /// The ops do not send exactly the bytes in `data`
+    DataLengthMismatch,
+    /// The ops read more than [`SPI_MAX_DATA`] bytes
+    ReplyTooLarge,
+    Overrun,
+    ModeFault,
+    Bus,
+}
+
+pub type SpiResult = Result<(), SpiError>;
+/// The actual bus frequency
+pub type SpiConfigResult = Result<u32, SpiError>;
+pub type SpiTransactionResult = Result<SpiData, SpiError>;
+
+// --- UART bridge
+
+/// Most bytes carried by one UART topic message
+pub const UART_MAX_CHUNK: usize = 256;
+
+pub type UartData = heapless::Vec<u8, UART_MAX_CHUNK>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, Schema)]
+pub enum UartParity {
+    None,
+    Even,
+    Odd,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, Schema)]
+pub enum UartStopBits {
+    One,
+    Half,
+    Two,
+    OneAndHalf,
+}
+
+/// Data bits are always 8, not counting the parity bit
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, Schema)]
+pub struct UartConfig {
+    pub baud_rate: u32,
+    pub parity: UartParity,
+    pub stop_bits: UartStopBits,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, Schema)]
+pub enum UartError {
+    BaudRateTooLow,
+    BaudRateTooHigh,
+    Unsupported,
+}
+
+pub type UartResult = Result<(), UartError>;
+
+// --- CAN
+
+/// Largest CAN FD payload
+pub const CAN_MAX_DATA: usize = 64;
+/// Filter slots for standard (11-bit) IDs
+pub const CAN_STANDARD_FILTERS: u8 = 28;
+/// Filter slots for extended (29-bit) IDs
+pub const CAN_EXTENDED_FILTERS: u8 = 8;
+
+pub type CanData = heapless::Vec<u8, CAN_MAX_DATA>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, Schema)]
+pub enum CanMode {
+    Normal,
+    /// Frames loop back internally, the bus is left alone
+    InternalLoopback,
+    /// Frames loop back, and are also driven onto the bus
+    ExternalLoopback,
+    /// Listen only, never drives the bus, not even to acknowledge
+    Silent,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, Schema)]
+pub struct CanConfig {
+    pub nominal_bitrate: u32,
+    /// Enables CAN FD with bit rate switching at this data rate, the bus
+    /// is classic CAN only when `None`
+    pub data_bitrate: Option<u32>,
+    pub mode: CanMode,
+    /// Keep frames that match no filter, rather than dropping them
+    pub accept_unmatched: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, Schema)]
+pub enum CanId {
+    Standard(u16),
+    Extended(u32),
+}
+
+/// IDs are 11 or 29 bits, depending on the filter
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, Schema)]
+pub enum CanFilterMatch {
+    Disabled,
+    Exact(u32),
+    Either(u32, u32),
+    Range { from: u32, to: u32 },
+    Mask { id: u32, mask: u32 },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, Schema)]
+pub enum CanFilterAction {
+    Accept,
+    Reject,
+}
+
+/// Filters survive reconfiguration, and can be set before the bus is configured
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, Schema)]
+pub struct CanFilter {
+    pub extended: bool,
+    /// Below [`CAN_STANDARD_FILTERS`] or [`CAN_EXTENDED_FILTERS`]
+    pub slot: u8,
+    pub matching: CanFilterMatch,
+    pub action: CanFilterAction,
+}
+
+/// For remote frames only the length of `data` is used, as the requested length
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, Schema)]
+pub struct CanFrame {
+    pub id: CanId,
+    pub remote: bool,
+    pub fd: bool,
+    /// Bit rate switching, only for FD frames
+    pub brs: bool,
+    pub data: CanData,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, Schema)]
+pub struct CanRxFrame {
+    pub timestamp: Timestamp,
+    pub frame: CanFrame,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, Schema)]
+pub enum CanBusState {
+    ErrorActive,
+    ErrorPassive,
+    BusOff,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, Schema)]
+pub struct CanErrorCounters {
+    /// Transmit and receive error counters, as kept by the controller
+    pub tx_errors: u8,
+    pub rx_errors: u8,
+    pub state: CanBusState,
+    /// Protocol errors seen since the bus was configured
+    pub bus_errors: u32,
+    /// Frames from the host that could not be sent
+    pub tx_dropped: u32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, Schema)]
+pub enum CanError {
+    NotConfigured,
+    InvalidBitrate,
+    InvalidFilterSlot,
+    InvalidId,
+    /// Classic frames carry up to 8 bytes, FD frames one of the FD lengths up to 64
+    InvalidLength,
+    /// An FD frame was sent on a classic CAN bus
+    FdNotEnabled,
+}
+
+pub type CanResult = Result<(), CanError>;
+pub type CanErrorsResult = Result<CanErrorCounters, CanError>;
+
+// --- Config store
+
+/// Longest key, in bytes
+pub const CONFIG_MAX_KEY: usize = 16;
+/// Largest value, in bytes once encoded
+pub const CONFIG_MAX_VALUE: usize = 128;
+/// Most keys a listing returns
+pub const CONFIG_MAX_KEYS: usize = 32;
+
+pub type ConfigKey = heapless::String<CONFIG_MAX_KEY>;
+/// The postcard encoding of the value, the store does not look inside
+pub type ConfigValue = heapless::Vec<u8, CONFIG_MAX_VALUE>;
+pub type ConfigKeys = heapless::Vec<ConfigKey, CONFIG_MAX_KEYS>;
+
+/// Keys the firmware reads at boot, and the type each value is encoded as.
+/// Any other key is stored but otherwise ignored.
+pub mod config_keys {
+    /// `u64`, overrides the ID reported to poststation
+    pub const UNIQUE_ID: &str = "unique_id";
+    /// `u32`, period of the hello topic in milliseconds
+    pub const HEARTBEAT_MS: &str = "heartbeat_ms";
+    /// [`UartConfig`](crate::UartConfig)
+    pub const UART: &str = "uart";
+    /// [`I2cConfig`](crate::I2cConfig)
+    pub const I2C: &str = "i2c";
+    /// [`SpiConfig`](crate::SpiConfig)
+    pub const SPI: &str = "spi";
+    /// [`CanConfig`](crate::CanConfig)
+    pub const CAN: &str = "can";
+    /// `u8`, level of the interrupt running the RPC server, from 0, the
+    /// most urgent, to 15. Applies after a reset.
+    pub const SERVER_PRIORITY: &str = "server_priority";
+    /// `u32`, silence from the host in milliseconds before outputs go to
+    /// their safe states, 0 never
+    pub const HOST_TIMEOUT_MS: &str = "host_timeout_ms";
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, Schema)]
+pub struct ConfigEntry {
+    pub key: ConfigKey,
+    pub value: ConfigValue,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, Schema)]
+pub enum ConfigError {
+    NotFound,
+    /// No room left, even after reclaiming space from old records
+    Full,
+    /// More keys are stored than a listing can carry
+    TooManyKeys,
+    /// A record failed its CRC check, a factory reset recovers
+    Corrupted,
+    /// Writing or erasing the flash failed
+    Flash,
+}
+
+pub type ConfigResult = Result<(), ConfigError>;
+pub type ConfigGetResult = Result<ConfigValue, ConfigError>;
+pub type ConfigListResult = Result<ConfigKeys, ConfigError>;
+
+// --- Firmware update
+
+/// Starts an update, dropping whatever was written by an earlier one. The
+/// image goes over a bulk transfer, see [`BulkTarget::Firmware`].
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, Schema)]
+pub struct UpdateStart {
+    /// Where the image is linked, must be the start of the active slot
+    pub address: u32,
+    /// Image length, padded to a multiple of 8 bytes
+    pub size: u32,
+    /// Checked over the written image before it is marked for boot
+    pub sha256: [u8; 32],
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, Schema)]
+pub enum FirmwareState {
+    Confirmed,
+    /// A new image that has not answered [`GetUniqueIdEndpoint`] yet, any
+    /// reset before it does rolls back to the previous image
+    Trial,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, Schema)]
+pub enum UpdateError {
+    /// The firmware was built without the `dfu` feature
+    Unsupported,
+    /// The image is linked for another address
+    WrongAddress,
+    /// The image is bigger than the update slot
+    TooLarge,
+    /// A new image has to confirm itself before it can be replaced
+    NotConfirmed,
+    NotStarted,
+    /// Image sizes and chunks are whole multiples of 8 bytes
+    Misaligned,
+    HashMismatch,
+    Flash,
+}
+
+pub type UpdateResult = Result<(), UpdateError>;
+pub type FirmwareStateResult = Result<FirmwareState, UpdateError>;
+
+// --- Bulk transfer
+
+/// Most payload bytes carried by one chunk, well inside a 1024-byte frame
+pub const BULK_MAX_CHUNK: usize = 512;
+/// Chunks sent before the sender waits for an ack
+pub const BULK_WINDOW: u32 = 4;
+
+pub type BulkId = u16;
+pub type BulkData = heapless::Vec<u8, BULK_MAX_CHUNK>;
+
+/// What a transfer reads from or writes to, which also decides its direction
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, Schema)]
+pub enum BulkTarget {
+    /// Download a range of internal flash, `offset` counts from its start
+    Flash { offset: u32, size: u32 },
+    /// Upload a firmware image, it is written to the update slot and
+    /// marked for boot when the transfer finishes
+    Firmware(UpdateStart),
+    /// Download raw memory, under the same rules as [`MemoryReadEndpoint`]
+    Memory { address: u32, size: u32 },
+    /// Upload a table for [`DacShape::Table`], `samples` little-endian
+    /// `i16`s
+    DacTable { output: DacOutput, samples: u16 },
+    /// Download the last logic analyzer capture, a byte per sample
+    Logic,
+    /// Upload a pattern for [`PatternPlayEndpoint`], a byte per word
+    Pattern { words: u16 },
+}
+
+/// Chunks are sized [`BULK_MAX_CHUNK`], except for the last one
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, Schema)]
+pub struct BulkInfo {
+    pub id: BulkId,
+    pub size: u32,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, Schema)]
+pub struct BulkChunk {
+    pub id: BulkId,
+    pub offset: u32,
+    pub data: BulkData,
+}
+
+/// Acknowledges every byte before `next`
+///
+/// Receivers ack after each window, and right away when a chunk arrives
+/// past `next`. The sender then goes back and resends from `next`. For
+/// downloads each ack from the host also asks for the window after it, and
+/// the ack for the last window is what completes the transfer.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, Schema)]
+pub struct BulkAck {
+    pub id: BulkId,
+    pub next: u32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, Schema)]
+pub struct BulkFinish {
+    pub id: BulkId,
+    /// CRC-32 of the whole payload as the host sent or received it, the
+    /// ISO-HDLC variant used by zip and Ethernet
+    pub crc: u32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, Schema)]
+pub enum BulkError {
+    /// Not the open transfer, only one is open at a time and opening
+    /// another drops it
+    UnknownTransfer,
+    /// The range does not fit the target, or the basic firmware leaves the
+    /// target out
+    OutOfRange,
+    /// Finishing before every byte was acked
+    Incomplete,
+    CrcMismatch,
+    /// Reading the flash failed
+    Flash,
+    /// The firmware update refused the image
+    Update(UpdateError),
+    Memory(MemoryError),
+    Logic(LogicError),
+    Pattern(PatternError),
+}
+
+pub type BulkResult = Result<(), BulkError>;
+pub type BulkOpenResult = Result<BulkInfo, BulkError>;
+/// The ack for everything received so far, to resume from after a timeout
+pub type BulkStatusResult = Result<BulkAck, BulkError>;
+
+// --- Memory access
+
+/// Most bytes read or written by one request, bigger reads go over a bulk
+/// transfer with [`BulkTarget::Memory`]
+pub const MEMORY_MAX_DATA: usize = 256;
+/// Unlocks writes when sent to [`MemoryUnlockEndpoint`], any other value
+/// locks them again
+pub const MEMORY_UNLOCK_KEY: u32 = 0x504F_4B45;
+
+pub type MemoryData = heapless::Vec<u8, MEMORY_MAX_DATA>;
+pub type MemoryRegionName = heapless::String<16>;
+pub type MemoryRegions = heapless::Vec<MemoryRegion, 16>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, Schema)]
+pub enum MemoryAccess {
+    Bytes,
+    /// Whole aligned 32-bit words only, as peripheral registers need
+    Words,
+}
+
+/// A range the firmware allows access to, every access has to fit in one
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, Schema)]
+pub struct MemoryRegion {
+    pub name: MemoryRegionName,
+    pub start: u32,
+    pub size: u32,
+    pub access: MemoryAccess,
+    pub writable: bool,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, Schema)]
+pub struct MemoryRead {
+    pub address: u32,
+    /// At most [`MEMORY_MAX_DATA`]
+    pub len: u16,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, Schema)]
+pub struct MemoryWrite {
+    pub address: u32,
+    pub data: MemoryData,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, Schema)]
+pub enum MemoryError {
+    /// Not inside one of the allowed regions
+    NotAllowed,
+    /// The region is word access only, and this was not whole aligned words
+    Misaligned,
+    TooLong,
+    ReadOnly,
+    /// The firmware was built without the `memory-write` feature
+    WritesDisabled,
+    /// Writes have to be unlocked first
+    Locked,
+    /// Nothing answered at the address, the access was dropped
+    BusFault,
+}
+
+pub type MemoryResult = Result<(), MemoryError>;
+pub type MemoryReadResult = Result<MemoryData, MemoryError>;
+
+// --- Executor
+
+pub type TaskName = heapless::String<24>;
+pub type TaskPools = heapless::Vec<TaskPoolStats, 24>;
+
+/// One `#[embassy_executor::task]` function and its pool of slots
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, Schema)]
+pub struct TaskPoolStats {
+    pub name: TaskName,
+    /// Slots in the pool, so instances that can run at once
+    pub size: u8,
+    pub running: u8,
+    /// Most instances running at once since boot
+    pub high_water: u8,
+    pub spawned: u32,
+    /// Spawns refused because every slot was taken
+    pub rejected: u32,
+    /// Arena bytes the whole pool takes, once something has been spawned
+    /// from it. Zero until then.
+    pub arena_bytes: u32,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, Schema)]
+pub struct ExecutorStats {
+    /// Set by the `task-arena-size-*` feature of embassy-executor
+    pub arena_size: u32,
+    /// The pools allocated so far, not counting alignment padding
+    pub arena_used: u32,
+    /// The pools spawned from so far, most recent first
+    pub pools: TaskPools,
+}
+
+/// Published when a spawn finds its pool full
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, Schema)]
+pub struct SpawnRejected {
+    pub name: TaskName,
+    /// Rejections from this pool since boot
+    pub rejected: u32,
+}
+
+// --- RAM
+
+pub type RamBufferName = heapless::String<16>;
+pub type RamBuffers = heapless::Vec<RamBuffer, 12>;
+
+/// A statically allocated buffer, by the name used in the firmware
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, Schema)]
+pub struct RamBuffer {
+    pub name: RamBufferName,
+    pub size: u32,
+}
+
+/// RAM use in bytes. The stack is painted at boot, so its peak covers
+/// everything since then, interrupts included.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, Schema)]
+pub struct RamStats {
+    pub ram_size: u32,
+    /// `.data`, `.bss` and `.uninit`, the buffers below included
+    pub statics: u32,
+    /// Between the statics and the top of RAM, the most the stack can grow
+    pub stack_size: u32,
+    pub stack_peak: u32,
+    /// Never touched by the stack so far
+    pub free: u32,
+    pub buffers: RamBuffers,
+}
+
+// --- Clocks
+
+pub type KernelClockName = heapless::String<8>;
+pub type KernelClocks = heapless::Vec<KernelClock, 12>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, Schema)]
+pub enum ClockSource {
+    Hsi16,
+    /// A crystal of this frequency
+    Hse { hz: u32 },
+}
+
+/// The clock a peripheral runs from, after its kernel clock mux
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, Schema)]
+pub struct KernelClock {
+    pub name: KernelClockName,
+    pub hz: u32,
+}
+
+/// The PLL runs from `source`. Timer clocks are doubled from their APB
+/// when it is divided.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, Schema)]
+pub struct ClockTree {
+    pub source: ClockSource,
+    pub sysclk_hz: u32,
+    pub hclk_hz: u32,
+    pub pclk1_hz: u32,
+    pub pclk2_hz: u32,
+    pub kernels: KernelClocks,
+}
+
+// --- Power
+
+/// Milliseconds since boot in each power mode, as seen by the thread mode
+/// executor. An idle stretch counts as STOP when the executor entered STOP
+/// for it.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, Schema)]
+pub struct PowerStats {
+    /// Built with the `low-power` feature, without it the core only sleeps
+    pub low_power: bool,
+    pub run_ms: u64,
+    pub sleep_ms: u64,
+    pub stop_ms: u64,
+    /// No peripheral driver keeps the core out of STOP right now
+    pub stop_ready: bool,
+}
+
+// --- Jobs
+
+pub type JobId = u16;
+
+/// The operations that run as jobs
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, Schema)]
+pub enum JobRequest {
+    Sleep { millis: u32 },
+    /// CRC-32 (ISO-HDLC) over a range of internal flash, offsets from its start
+    FlashCrc { offset: u32, size: u32 },
+    /// Keeps the thread mode executor busy, to measure request latency under load
+    Load { millis: u32 },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, Schema)]
+pub enum JobOutput {
+    Slept { millis: u32 },
+    FlashCrc(u32),
+    Busy { millis: u32 },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, Schema)]
+pub enum JobError {
+    /// Finished too long ago to be remembered, or never started
+    UnknownJob,
+    /// The job has already ended
+    NotRunning,
+    OutOfRange,
+    Flash,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, Schema)]
+pub enum JobStatus {
+    /// `done` and `total` are in units of the job, bytes or milliseconds
+    Running { done: u32, total: u32 },
+    Finished(JobOutput),
+    Failed(JobError),
+    Cancelled,
+}
+
+/// Published on every change of a job's status
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, Schema)]
+pub struct JobUpdate {
+    pub id: JobId,
+    pub status: JobStatus,
+}
+
+pub type JobResult = Result<(), JobError>;
+pub type JobStatusResult = Result<JobStatus, JobError>;
+
+// --- Host liveness
+
+/// Published once the host has been silent for
+/// [`config_keys::HOST_TIMEOUT_MS`], after the outputs went to their safe
+/// states
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, Schema)]
+pub struct HostLost {
+    pub silent_ms: u32,
+}
+
+/// Sent by a host when it attaches, with an ID of its choosing for the
+/// session
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, Schema)]
+pub struct HostHello {
+    pub session: u32,
+}
+
+/// Published when a new session starts, topic sequence numbers start over
+/// from here
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, Schema)]
+pub struct Connected {
+    pub unique_id: u64,
+    pub session: u32,
+}
+
+// --- Time
+
+/// When something happened on the device, in absolute time once the host
+/// has set it with [`TimeSetEndpoint`]
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, Schema)]
+pub enum Timestamp {
+    /// Microseconds since the device booted
+    Uptime(u64),
+    /// Microseconds since the Unix epoch
+    Unix(u64),
+}
+
+/// The device's clocks at the moment it answered
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, Schema)]
+pub struct DeviceTime {
+    pub uptime_us: u64,
+    /// `None` until the host has set the time
+    pub unix_us: Option<u64>,
+}
+
+/// The Unix time at a moment of the device's uptime, as the host worked
+/// it out from round trips to [`TimeNowEndpoint`]
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, Schema)]
+pub struct TimeSet {
+    pub uptime_us: u64,
+    pub unix_us: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, Schema)]
+pub struct TimeSetReport {
+    /// How far ahead of the new time the device's own was, `None` when the
+    /// time wasn't set before
+    pub error_us: Option<i64>,
+    /// How much faster the device's clock runs than the host's, in parts per
+    /// billion. `None` until two settings are far enough apart to tell.
+    pub drift_ppb: Option<i32>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, Schema)]
+pub enum TimeError {
+    /// A time past `i64::MAX` microseconds, or one so far from the first
+    /// setting that the drift can't be worked out. The setting is ignored.
+    OutOfRange,
+}
+
+pub type TimeSetResult = Result<TimeSetReport, TimeError>;
+
+// --- DAC
+
+/// VREF+ on the board, what a full-scale DAC code puts out
+pub const DAC_VREF_MV: u16 = 3300;
+/// Samples in the longest period a waveform can have
+pub const DAC_MAX_SAMPLES: u16 = 256;
+/// As fast as the DAC's output buffer settles
+pub const DAC_MAX_RATE_HZ: u32 = 1_000_000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, Schema)]
+pub enum DacOutput {
+    /// DAC1 OUT1 on PA4
+    Out1,
+    /// DAC1 OUT2 on PA5
+    Out2,
+}
+
+/// Hold a level, stopping any waveform right away
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, Schema)]
+pub struct DacLevel {
+    pub output: DacOutput,
+    pub millivolts: u16,
+}
+
+/// One period, swinging `amplitude_mv` either side of the offset
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, Schema)]
+pub enum DacShape {
+    /// Rising from the offset
+    Sine,
+    /// Rising from the offset
+    Triangle,
+    /// High for the first half
+    Square,
+    /// As uploaded with [`BulkTarget::DacTable`], -32767 and 32767 are the
+    /// troughs and peaks
+    Table,
+}
+
+/// Playing again on an output that plays as many samples carries on from
+/// the same point of the period, without a glitch
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, Schema)]
+pub struct DacWave {
+    pub output: DacOutput,
+    pub shape: DacShape,
+    /// Samples in a period, the length of the table for [`DacShape::Table`]
+    pub samples: u16,
+    pub sample_rate_hz: u32,
+    pub amplitude_mv: u16,
+    pub offset_mv: u16,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, Schema)]
+pub enum DacError {
+    /// Below 0 V or above [`DAC_VREF_MV`] somewhere
+    OutOfRange,
+    /// Fewer than two, or more than [`DAC_MAX_SAMPLES`]
+    Samples,
+    /// Zero, or above [`DAC_MAX_RATE_HZ`]
+    SampleRate,
+    /// The output has no table of that many samples
+    NoTable,
+}
+
+pub type DacResult = Result<(), DacError>;
+
+// --- Capture
+
+/// Longest window a measurement is averaged over
+pub const CAPTURE_MAX_WINDOW_MS: u32 = 60_000;
+
+/// The inputs of TIM4, only one measures at a time
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Schema)]
+pub enum CaptureInput {
+    /// PB6
+    Ch1,
+    /// PB7
+    Ch2,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Schema)]
+pub enum CaptureMode {
+    /// Rising edges are captured on a free-running counter, giving frequency
+    /// and period
+    InputCapture,
+    /// Rising edges restart the counter and the other channel captures
+    /// falling edges, adding pulse widths and duty cycle
+    PwmInput,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, Schema)]
+pub struct CaptureRequest {
+    pub input: CaptureInput,
+    pub mode: CaptureMode,
+    pub window_ms: u32,
+}
+
+/// Time spent high and low, averaged like the period
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, Schema)]
+pub struct CapturePulse {
+    pub high_ns: u64,
+    pub low_ns: u64,
+    /// Of the period spent high, in millionths
+    pub duty_ppm: u32,
+}
+
+/// Averages over the periods that ended in the window, all zero without
+/// one. They are only as accurate as the timer clock, see [`ClockTree`].
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, Schema)]
+pub struct CaptureMeasurement {
+    pub input: CaptureInput,
+    pub mode: CaptureMode,
+    pub window_ms: u32,
+    /// What the edges were timed with
+    pub timer_hz: u32,
+    pub periods: u32,
+    /// Edges that came too close together to be told apart, the periods
+    /// around them are left out
+    pub missed: u32,
+    pub frequency_millihz: u64,
+    pub period_ns: u64,
+    /// With [`CaptureMode::PwmInput`]
+    pub pulse: Option<CapturePulse>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, Schema)]
+pub enum CaptureError {
+    /// Already measuring another input, or in the other mode
+    Busy,
+    /// Zero, or above [`CAPTURE_MAX_WINDOW_MS`]
+    Window,
+}
+
+/// Publish a measurement on [`CaptureTopic`] every window, or stop with `None`
+pub type CaptureStream = Option<CaptureRequest>;
+
+pub type CaptureResult = Result<(), CaptureError>;
+pub type CaptureMeasureResult = Result<CaptureMeasurement, CaptureError>;
+
+// --- Encoder
+
+/// Shortest period [`EncoderTopic`] publishes at
+pub const ENCODER_MIN_PUBLISH_MS: u32 = 10;
+
+/// For the encoder on PA0 (A) and PA1 (B), counted on every edge of both
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, Schema)]
+pub struct EncoderConfig {
+    /// Count the other way round
+    pub reversed: bool,
+    /// The input filter, 0 for none up to 15 for an edge that holds for 8
+    /// samples at a 32nd of the timer clock, as in the reference manual
+    pub filter: u8,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, Schema)]
+pub struct EncoderReading {
+    /// Counts since zeroing, extended past the timer's 32 bits so it never
+    /// wraps
+    pub position: i64,
+    /// Counts per second over the last publishing period, or the last
+    /// second while not publishing
+    pub velocity_cps: i32,
+    pub timestamp: Timestamp,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, Schema)]
+pub enum EncoderError {
+    /// Above 15
+    Filter,
+    /// Below [`ENCODER_MIN_PUBLISH_MS`]
+    PublishPeriod,
+}
+
+/// Milliseconds between readings on [`EncoderTopic`], or 0 to stop
+pub type EncoderPublish = u32;
+
+pub type EncoderResult = Result<(), EncoderError>;
+
+// --- Logic analyzer
+
+/// Samples one capture holds at most, a byte each
+pub const LOGIC_MAX_SAMPLES: u32 = 4096;
+/// Faster than this, samples tend to be lost while the firmware looks for
+/// the trigger, see [`LogicError::Overrun`]
+pub const LOGIC_MAX_RATE_HZ: u32 = 2_000_000;
+/// Longest a capture waits for its trigger, and longest its samples may
+/// take at the requested rate
+pub const LOGIC_MAX_TIMEOUT_MS: u32 = 60_000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, Schema)]
+pub enum LogicPort {
+    A,
+    B,
+    C,
+    D,
+    E,
+    F,
+    G,
+}
+
+/// Eight pins of one port, channel `n` is pin `n` of the low byte or
+/// `n + 8` of the high byte
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, Schema)]
+pub struct LogicPins {
+    pub port: LogicPort,
+    pub high_byte: bool,
+    /// The channels to look at, a bit each. Masked pins still in their
+    /// reset analog mode are made inputs for the capture, the others are
+    /// read as whatever drives them, so buses in use can be watched.
+    pub channels: u8,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, Schema)]
+pub enum LogicEdge {
+    Rising,
+    Falling,
+    Either,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, Schema)]
+pub enum LogicTrigger {
+    /// Capture right away, without pre-trigger samples
+    Immediate,
+    Edge { channel: u8, edge: LogicEdge },
+    /// The first sample with `sample & mask == value`
+    Pattern { mask: u8, value: u8 },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, Schema)]
+pub struct LogicRequest {
+    pub pins: LogicPins,
+    pub rate_hz: u32,
+    pub samples: u32,
+    /// Samples kept from before the trigger, the trigger is only looked for
+    /// once there are this many
+    pub pretrigger: u32,
+    pub trigger: LogicTrigger,
+    pub timeout_ms: u32,
+}
+
+/// A finished capture, its samples are read with [`BulkTarget::Logic`]
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, Schema)]
+pub struct LogicCapture {
+    pub pins: LogicPins,
+    /// The rate the timer got closest to
+    pub rate_hz: u32,
+    pub samples: u32,
+    /// The index of the triggering sample, unless [`LogicTrigger::Immediate`]
+    pub trigger: Option<u32>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, Schema)]
+pub enum LogicError {
+    /// Capturing already, or reading out the last capture meanwhile
+    Busy,
+    /// No channels
+    Pins,
+    /// Zero, or above [`LOGIC_MAX_RATE_HZ`], or so slow the samples take
+    /// longer than [`LOGIC_MAX_TIMEOUT_MS`]
+    Rate,
+    /// Zero, or above [`LOGIC_MAX_SAMPLES`]
+    Samples,
+    /// More than the samples, or a trigger on a channel not captured
+    Trigger,
+    /// The trigger didn't come in time, zero or above [`LOGIC_MAX_TIMEOUT_MS`]
+    /// is refused up front
+    Timeout,
+    /// The firmware fell behind the samples
+    Overrun,
+    /// Nothing captured to read out
+    NoCapture,
+}
+
+pub type LogicResult = Result<LogicCapture, LogicError>;
+
+// --- Pattern generator
+
+/// Words a pattern holds at most, a byte each
+pub const PATTERN_MAX_WORDS: u16 = 1024;
+pub const PATTERN_MAX_RATE_HZ: u32 = 2_000_000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, Schema)]
+pub enum PatternRepeat {
+    Once,
+    /// Back to back, without a gap between
+    Times(u32),
+    /// Until [`PatternStopEndpoint`]. Patterns over a quarter of
+    /// [`PATTERN_MAX_WORDS`] are streamed, and can end in
+    /// [`PatternState::Underrun`] at high rates.
+    Continuous,
+}
+
+/// Plays the pattern uploaded with [`BulkTarget::Pattern`], replacing
+/// whatever plays
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, Schema)]
+pub struct PatternPlay {
+    /// Channel `n` plays bit `n` of each word. The pins have to be analog
+    /// or inputs, and are push-pull outputs from now until
+    /// [`PatternStopEndpoint`]. Other pins of the port are left alone.
+    pub pins: LogicPins,
+    pub rate_hz: u32,
+    pub repeat: PatternRepeat,
+    /// Wait for this edge on PA10 before starting, the first word follows
+    /// it by a few microseconds and a sample period
+    pub trigger: Option<LogicEdge>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, Schema)]
+pub enum PatternState {
+    Idle,
+    /// Waiting for the trigger
+    Armed,
+    Playing,
+    /// The pins hold the last word
+    Done,
+    /// The firmware fell behind and stopped, the pins hold what played last
+    Underrun,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, Schema)]
+pub struct PatternStatus {
+    pub state: PatternState,
+    /// The rate the timer got closest to, for the last pattern played
+    pub rate_hz: u32,
+    /// In the uploaded pattern, zero without one
+    pub words: u16,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, Schema)]
+pub enum PatternError {
+    /// Nothing uploaded
+    NoPattern,
+    /// No channels, PA10 among them, or a pin in use as an output or by a
+    /// peripheral
+    Pins,
+    /// Zero, or above [`PATTERN_MAX_RATE_HZ`]
+    Rate,
+    /// Zero times
+    Repeat,
+    /// Uploading while a pattern plays or waits for its trigger
+    Busy,
+}
+
+/// The rate the timer got closest to
+pub type PatternResult = Result<u32, PatternError>;
+
 // ---
 
 // Endpoints spoken by our device
 //
-// GetUniqueIdEndpoint is mandatory, the others are examples
+// GetUniqueIdEndpoint is mandatory, the others are examples. The `basic`
+// feature leaves out what the basic firmware doesn't serve, so their
+// schemas don't take up its flash.
 endpoints! {
     list = ENDPOINT_LIST;
-    | EndpointTy                | RequestTy     | ResponseTy            | Path                          |
-    | ----------                | ---------     | ----------            | ----                          |
-    | GetUniqueIdEndpoint       | ()            | u64                   | "poststation/unique_id/get"   |
-    | RebootToPicoBoot          | ()            | ()                    | "template/picoboot/reset"     |
-    | SleepEndpoint             | SleepMillis   | SleptMillis           | "template/sleep"              |
-    | SetLedEndpoint            | LedState      | ()                    | "template/led/set"            |
-    | GetLedEndpoint            | ()            | LedState              | "template/led/get"            |
+    | EndpointTy                | RequestTy     | ResponseTy            | Path                          | Cfg                           |
+    | ----------                | ---------     | ----------            | ----                          | ---                           |
+    | GetUniqueIdEndpoint       | ()            | u64                   | "poststation/unique_id/get"   |                               |
+    | RebootToPicoBoot          | ()            | ()                    | "template/picoboot/reset"     |                               |
+    | SleepEndpoint             | SleepMillis   | SleptMillis           | "template/sleep"              |                               |
+    | CancellableSleepEndpoint  | SleepMillis   | SleepResult           | "template/sleep/cancellable"  | cfg(not(feature = "basic"))   |
+    | SetLedEndpoint            | LedState      | ()                    | "template/led/set"            |                               |
+    | GetLedEndpoint            | ()            | LedState              | "template/led/get"            |                               |
+    | SetLedBrightnessEndpoint  | LedBrightness | ()                    | "template/led/brightness"     |                               |
+    | ConfigurePwmEndpoint      | PwmConfig     | PwmResult             | "template/pwm/configure"      | cfg(not(feature = "basic"))   |
+    | SetPwmDutyEndpoint        | PwmDuty       | PwmResult             | "template/pwm/duty/set"       | cfg(not(feature = "basic"))   |
+    | DisablePwmEndpoint        | PwmChannel    | PwmResult             | "template/pwm/disable"        | cfg(not(feature = "basic"))   |
+    | ConfigureI2cEndpoint      | I2cConfig     | I2cResult             | "template/i2c/configure"      | cfg(not(feature = "basic"))   |
+    | I2cWriteEndpoint          | I2cWrite      | I2cResult             | "template/i2c/write"          | cfg(not(feature = "basic"))   |
+    | I2cReadEndpoint           | I2cRead       | I2cReadResult         | "template/i2c/read"           | cfg(not(feature = "basic"))   |
+    | I2cWriteReadEndpoint      | I2cWriteRead  | I2cReadResult         | "template/i2c/write_read"     | cfg(not(feature = "basic"))   |
+    | I2cReadRegisterEndpoint   | I2cRegisterRead   | I2cReadResult     | "template/i2c/reg/read"       | cfg(not(feature = "basic"))   |
+    | I2cWriteRegisterEndpoint  | I2cRegisterWrite  | I2cResult         | "template/i2c/reg/write"      | cfg(not(feature = "basic"))   |
+    | I2cScanEndpoint           | ()            | I2cScanResult         | "template/i2c/scan"           | cfg(not(feature = "basic"))   |
+    | ConfigureSpiEndpoint      | SpiConfig     | SpiConfigResult       | "template/spi/configure"      | cfg(not(feature = "basic"))   |
+    | DeclareSpiCsEndpoint      | SpiCsConfig   | SpiResult             | "template/spi/cs/declare"     | cfg(not(feature = "basic"))   |
+    | SpiTransactionEndpoint    | SpiTransaction    | SpiTransactionResult  | "template/spi/transaction" | cfg(not(feature = "basic"))   |
+    | ConfigureUartEndpoint     | UartConfig    | UartResult            | "template/uart/configure"     | cfg(not(feature = "basic"))   |
+    | ConfigureCanEndpoint      | CanConfig     | CanResult             | "template/can/configure"      | cfg(not(feature = "basic"))   |
+    | SetCanFilterEndpoint      | CanFilter     | CanResult             | "template/can/filter/set"     | cfg(not(feature = "basic"))   |
+    | GetCanErrorsEndpoint      | ()            | CanErrorsResult       | "template/can/errors/get"     | cfg(not(feature = "basic"))   |
+    | GetConfigEndpoint         | ConfigKey     | ConfigGetResult       | "template/config/get"         | cfg(not(feature = "basic"))   |
+    | SetConfigEndpoint         | ConfigEntry   | ConfigResult          | "template/config/set"         | cfg(not(feature = "basic"))   |
+    | DeleteConfigEndpoint      | ConfigKey     | ConfigResult          | "template/config/delete"      | cfg(not(feature = "basic"))   |
+    | ListConfigEndpoint        | ()            | ConfigListResult      | "template/config/list"        | cfg(not(feature = "basic"))   |
+    | FactoryResetEndpoint      | ()            | ConfigResult          | "template/config/factory_reset" | cfg(not(feature = "basic"))   |
+    | GetFirmwareStateEndpoint  | ()            | FirmwareStateResult   | "template/update/state"       |                               |
+    | ResetEndpoint             | ()            | ()                    | "template/reset"              |                               |
+    | BulkOpenEndpoint          | BulkTarget    | BulkOpenResult        | "template/bulk/open"          |                               |
+    | BulkStatusEndpoint        | BulkId        | BulkStatusResult      | "template/bulk/status"        |                               |
+    | BulkFinishEndpoint        | BulkFinish    | BulkResult            | "template/bulk/finish"        |                               |
+    | BulkAbortEndpoint         | BulkId        | BulkResult            | "template/bulk/abort"         |                               |
+    | MemoryRegionsEndpoint     | ()            | MemoryRegions         | "template/memory/regions"     | cfg(not(feature = "basic"))   |
+    | MemoryReadEndpoint        | MemoryRead    | MemoryReadResult      | "template/memory/read"        | cfg(not(feature = "basic"))   |
+    | MemoryUnlockEndpoint      | u32           | MemoryResult          | "template/memory/unlock"      | cfg(not(feature = "basic"))   |
+    | MemoryWriteEndpoint       | MemoryWrite   | MemoryResult          | "template/memory/write"       | cfg(not(feature = "basic"))   |
+    | ExecutorStatsEndpoint     | ()            | ExecutorStats         | "template/executor/stats"     | cfg(not(feature = "basic"))   |
+    | RamStatsEndpoint          | ()            | RamStats              | "template/ram/stats"          | cfg(not(feature = "basic"))   |
+    | ClockTreeEndpoint         | ()            | ClockTree             | "template/clocks"             | cfg(not(feature = "basic"))   |
+    | PowerStatsEndpoint        | ()            | PowerStats            | "template/power/stats"        |                               |
+    | JobStartEndpoint          | JobRequest    | JobId                 | "template/job/start"          | cfg(not(feature = "basic"))   |
+    | JobStatusEndpoint         | JobId         | JobStatusResult       | "template/job/status"         | cfg(not(feature = "basic"))   |
+    | JobCancelEndpoint         | JobId         | JobResult             | "template/job/cancel"         | cfg(not(feature = "basic"))   |
+    | HostHelloEndpoint         | HostHello     | ()                    | "template/host/hello"         |                               |
+    | TimeNowEndpoint           | ()            | DeviceTime            | "template/time/now"           | cfg(not(feature = "basic"))   |
+    | TimeSetEndpoint           | TimeSet       | TimeSetResult         | "template/time/set"           | cfg(not(feature = "basic"))   |
+    | DacSetEndpoint            | DacLevel      | DacResult             | "template/dac/set"            | cfg(not(feature = "basic"))   |
+    | DacPlayEndpoint           | DacWave       | DacResult             | "template/dac/play"           | cfg(not(feature = "basic"))   |
+    | DacStopEndpoint           | DacOutput     | ()                    | "template/dac/stop"           | cfg(not(feature = "basic"))   |
+    | CaptureMeasureEndpoint    | CaptureRequest | CaptureMeasureResult | "template/capture/measure"   | cfg(not(feature = "basic"))   |
+    | CaptureStreamEndpoint     | CaptureStream | CaptureResult         | "template/capture/stream"     | cfg(not(feature = "basic"))   |
+    | EncoderConfigureEndpoint  | EncoderConfig | EncoderResult         | "template/encoder/configure"  | cfg(not(feature = "basic"))   |
+    | EncoderReadEndpoint       | ()            | EncoderReading        | "template/encoder/read"       | cfg(not(feature = "basic"))   |
+    | EncoderZeroEndpoint       | ()            | ()                    | "template/encoder/zero"       | cfg(not(feature = "basic"))   |
+    | EncoderPublishEndpoint    | EncoderPublish | EncoderResult        | "template/encoder/publish"    | cfg(not(feature = "basic"))   |
+    | LogicCaptureEndpoint      | LogicRequest  | LogicResult           | "template/logic/capture"      | cfg(not(feature = "basic"))   |
+    | PatternPlayEndpoint       | PatternPlay   | PatternResult         | "template/pattern/play"       | cfg(not(feature = "basic"))   |
+    | PatternStopEndpoint       | ()            | ()                    | "template/pattern/stop"       | cfg(not(feature = "basic"))   |
+    | PatternStatusEndpoint     | ()            | PatternStatus         | "template/pattern/status"     | cfg(not(feature = "basic"))   |
 }
 
 // incoming topics handled by our device
 topics! {
     list = TOPICS_IN_LIST;
     direction = TopicDirection::ToServer;
-    | TopicTy                   | MessageTy     | Path              |
-    | -------                   | ---------     | ----              |
+    | TopicTy                   | MessageTy     | Path              | Cfg                           |
+    | -------                   | ---------     | ----              | ---                           |
+    | UartTxTopic               | UartData      | "template/uart/tx" | cfg(not(feature = "basic"))   |
+    | CanTxTopic                | CanFrame      | "template/can/tx" | cfg(not(feature = "basic"))   |
+    | BulkUploadTopic           | BulkChunk     | "template/bulk/upload" |                               |
+    | BulkDownloadAckTopic      | BulkAck       | "template/bulk/download/ack" |                               |
+    | CancelTopic               | u32           | "template/cancel" | cfg(not(feature = "basic"))   |
 }
 
 // outgoing topics handled by our device
@@ -56,4 +1362,14 @@ topics! {
     | TopicTy                   | MessageTy     | Path              | Cfg                           |
     | -------                   | ---------     | ----              | ---                           |
     | HelloTopic                | u64    | "hello"           |                               |
+    | UartRxTopic               | UartData      | "template/uart/rx" | cfg(not(feature = "basic"))   |
+    | CanRxTopic                | CanRxFrame    | "template/can/rx" | cfg(not(feature = "basic"))   |
+    | BulkDownloadTopic         | BulkChunk     | "template/bulk/download" |                               |
+    | BulkUploadAckTopic        | BulkAck       | "template/bulk/upload/ack" |                               |
+    | SpawnRejectedTopic        | SpawnRejected | "template/executor/rejected" |                               |
+    | JobTopic                  | JobUpdate     | "template/job"    | cfg(not(feature = "basic"))   |
+    | HostLostTopic             | HostLost      | "template/host/lost" |                               |
+    | ConnectTopic              | Connected     | "template/host/connect" |                               |
+    | CaptureTopic              | CaptureMeasurement | "template/capture" | cfg(not(feature = "basic"))   |
+    | EncoderTopic              | EncoderReading | "template/encoder" | cfg(not(feature = "basic"))   |
 }
@@ -1,12 +1,15 @@
-//! This build script copies the `memory.x` file from the crate root into
-//! a directory where the linker can always find it at build time.
-//! For many projects this is optional, as the linker always searches the
-//! project root directory -- wherever `Cargo.toml` is. However, if you
-//! are using a workspace or have a more complicated build setup, this
-//! build script becomes required. Additionally, by requesting that
-//! Cargo re-run the build script whenever `memory.x` is changed,
-//! updating `memory.x` ensures a rebuild of the application with the
-//! new memory settings.
+//! This build script copies the linker layout, `memory-full.x` or
+//! `memory-dfu.x` with the `dfu` feature, into a directory where the
+//! linker finds it as `memory.x`. Cargo re-runs it whenever either layout
+//! changes, so editing one always rebuilds the application.
+//!
+//! It also sets the `full` cfg, unless `dfu` or `low-power` is enabled.
+//! Without it the firmware is the basic one, see `basic` in the ICD, which
+//! the active slot is too small for more than, and which holds no
+//! peripheral clocks that would keep the core out of STOP. It leaves out
+//! the peripheral bridges, memory access, jobs, editing the config store
+//! and most of the diagnostics. Settings already in the store still apply
+//! at boot.
 
 use std::env;
 use std::fs::File;
@@ -17,17 +20,28 @@ fn main() {
     // Put `memory.x` in our output directory and ensure it's
     // on the linker search path.
     let out = &PathBuf::from(env::var_os("OUT_DIR").unwrap());
+    // With the `dfu` feature the firmware goes in the bootloader's active slot
+    let memory_x: &[u8] = match env::var_os("CARGO_FEATURE_DFU") {
+        Some(_) => include_bytes!("memory-dfu.x"),
+        None => include_bytes!("memory-full.x"),
+    };
     File::create(out.join("memory.x"))
         .unwrap()
-        .write_all(include_bytes!("memory.x"))
+        .write_all(memory_x)
         .unwrap();
     println!("cargo:rustc-link-search={}", out.display());
 
+    println!("cargo:rustc-check-cfg=cfg(full)");
+    if env::var_os("CARGO_FEATURE_DFU").is_none() && env::var_os("CARGO_FEATURE_LOW_POWER").is_none() {
+        println!("cargo:rustc-cfg=full");
+    }
+
     // By default, Cargo will re-run a build script whenever
-    // any file in the project changes. By specifying `memory.x`
+    // any file in the project changes. By specifying the layouts
     // here, we ensure the build script is only re-run when
-    // `memory.x` is changed.
-    println!("cargo:rerun-if-changed=memory.x");
+    // one of them is changed.
+    println!("cargo:rerun-if-changed=memory-full.x");
+    println!("cargo:rerun-if-changed=memory-dfu.x");
 
     println!("cargo:rustc-link-arg-bins=--nmagic");
     println!("cargo:rustc-link-arg-bins=-Tlink.x");
@@ -0,0 +1,29 @@
+/* Layout for A/B firmware updates, used with the `dfu` feature. Must
+   match bootloader/memory.x, and the image has to fit the active slot,
+   which the ASSERT below checks. The bootloader takes under 7K. */
+
+MEMORY
+{
+  /* NOTE 1 K = 1 KiBi = 1024 bytes */
+  BOOTLOADER       : ORIGIN = 0x08000000, LENGTH = 8K
+  BOOTLOADER_STATE : ORIGIN = 0x08002000, LENGTH = 2K
+  FLASH            : ORIGIN = 0x08002800, LENGTH = 54K
+  DFU              : ORIGIN = 0x08010000, LENGTH = 56K
+  /* The last 8K hold the config store, see src/store.rs */
+  RAM              : ORIGIN = 0x20000000, LENGTH = 32K
+}
+
+/* Offsets from the start of flash */
+__bootloader_state_start = ORIGIN(BOOTLOADER_STATE) - ORIGIN(BOOTLOADER);
+__bootloader_state_end = ORIGIN(BOOTLOADER_STATE) + LENGTH(BOOTLOADER_STATE) - ORIGIN(BOOTLOADER);
+
+__bootloader_active_start = ORIGIN(FLASH) - ORIGIN(BOOTLOADER);
+__bootloader_active_end = ORIGIN(FLASH) + LENGTH(FLASH) - ORIGIN(BOOTLOADER);
+
+__bootloader_dfu_start = ORIGIN(DFU) - ORIGIN(BOOTLOADER);
+__bootloader_dfu_end = ORIGIN(DFU) + LENGTH(DFU) - ORIGIN(BOOTLOADER);
+
+/* The swap needs a page more in DFU than in the active slot */
+ASSERT(LENGTH(DFU) >= LENGTH(FLASH) + 2K, "DFU has to be a page bigger than the active slot");
+/* `__veneer_limit` ends the last section cortex-m-rt puts in flash */
+ASSERT(__veneer_limit <= ORIGIN(FLASH) + LENGTH(FLASH), "The firmware does not fit the active slot, see the dfu feature in Cargo.toml");
@@ -0,0 +1,13 @@
+/* Change this as required for your MCU. There is deliberately no memory.x
+   in the crate root, the linker would pick it over the one build.rs writes */
+
+MEMORY
+{
+  /* NOTE 1 K = 1 KiBi = 1024 bytes */
+  /* The last 8K hold the config store, see src/store.rs */
+  FLASH : ORIGIN = 0x08000000, LENGTH = 120K
+  RAM : ORIGIN = 0x20000000, LENGTH = 32K
+}
+
+/* `__veneer_limit` ends the last section cortex-m-rt puts in flash */
+ASSERT(__veneer_limit <= ORIGIN(FLASH) + LENGTH(FLASH), "The firmware does not fit the flash before the config store");
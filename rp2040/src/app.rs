@@ -1,9 +1,39 @@
 //! A basic postcard-rpc/poststation-compatible application
 
-use crate::{handlers::{get_led, set_led, sleep_handler, unique_id}, impls::{RttRx, RttTx}};
-use embassy_stm32::gpio::Output;
-use embassy_sync::blocking_mutex::raw::ThreadModeRawMutex;
-use embassy_executor::{SpawnError, SpawnToken, Spawner};
+use crate::{
+    bulk::BulkTransfers,
+    handlers::{
+        bulk_abort, bulk_download_ack, bulk_finish, bulk_open, bulk_status, bulk_upload, get_firmware_state, get_led,
+        host_hello, power_stats, reset_handler, set_led, set_led_brightness, sleep_handler, unique_id,
+    },
+    impls::{RttRx, RttTx},
+    led::Led,
+    store::ConfigStore,
+    tasks,
+    update::FirmwareUpdate,
+    Shared, SharedFlash,
+};
+#[cfg(full)]
+use crate::{
+    can::CanBridge,
+    dac::DacOutputs,
+    handlers::{
+        cancel_request, cancellable_sleep_handler, capture_measure, capture_stream, can_tx, clock_tree, configure_can,
+        configure_i2c, delete_config, factory_reset, get_config, list_config, set_config, configure_pwm, configure_spi, configure_uart, dac_play, dac_set, dac_stop, declare_spi_cs,
+        disable_pwm, encoder_configure, encoder_publish, encoder_read, encoder_zero, executor_stats, get_can_errors,
+        i2c_read, i2c_read_register, i2c_scan, i2c_write, i2c_write_read, i2c_write_register, job_cancel, job_start,
+        job_status, logic_capture, memory_read, memory_regions, memory_unlock, memory_write, pattern_play,
+        pattern_status, pattern_stop, ram_stats, set_can_filter, set_pwm_duty, spi_transaction, time_now, time_set,
+        uart_tx,
+    },
+    i2c::I2cBridge,
+    memory::Memory,
+    pwm::PwmOutputs,
+    spi::SpiBridge,
+    uart::UartBridge,
+};
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_executor::{SendSpawner, SpawnError};
 
 use postcard_rpc::{
     define_dispatch,
@@ -11,7 +41,22 @@ use postcard_rpc::{
 };
 use static_cell::ConstStaticCell;
 use template_icd::{
-    GetLedEndpoint, GetUniqueIdEndpoint, RebootToPicoBoot, SetLedEndpoint, SleepEndpoint,
+    BulkAbortEndpoint, BulkDownloadAckTopic, BulkFinishEndpoint, BulkOpenEndpoint, BulkStatusEndpoint, BulkUploadTopic,
+    GetFirmwareStateEndpoint, GetLedEndpoint, GetUniqueIdEndpoint, HostHelloEndpoint, PowerStatsEndpoint,
+    RebootToPicoBoot, SetLedBrightnessEndpoint, SetLedEndpoint, SleepEndpoint, ResetEndpoint,
+};
+#[cfg(full)]
+use template_icd::{
+    CancelTopic, CancellableSleepEndpoint, CaptureMeasureEndpoint, CaptureStreamEndpoint, CanTxTopic,
+    ClockTreeEndpoint, ConfigureCanEndpoint, ConfigureI2cEndpoint, ConfigurePwmEndpoint, ConfigureSpiEndpoint,
+    ConfigureUartEndpoint, DacPlayEndpoint, DeleteConfigEndpoint, FactoryResetEndpoint, GetConfigEndpoint,
+    ListConfigEndpoint, SetConfigEndpoint, DacSetEndpoint, DacStopEndpoint, DeclareSpiCsEndpoint, DisablePwmEndpoint,
+    EncoderConfigureEndpoint, EncoderPublishEndpoint, EncoderReadEndpoint, EncoderZeroEndpoint,
+    ExecutorStatsEndpoint, GetCanErrorsEndpoint, I2cReadEndpoint, I2cReadRegisterEndpoint, I2cScanEndpoint,
+    I2cWriteEndpoint, I2cWriteReadEndpoint, I2cWriteRegisterEndpoint, JobCancelEndpoint, JobStartEndpoint,
+    JobStatusEndpoint, LogicCaptureEndpoint, MemoryReadEndpoint, MemoryRegionsEndpoint, MemoryUnlockEndpoint,
+    MemoryWriteEndpoint, PatternPlayEndpoint, PatternStatusEndpoint, PatternStopEndpoint, RamStatsEndpoint,
+    SetCanFilterEndpoint, SetPwmDutyEndpoint, SpiTransactionEndpoint, TimeNowEndpoint, TimeSetEndpoint, UartTxTopic,
 };
 use template_icd::{ENDPOINT_LIST, TOPICS_IN_LIST, TOPICS_OUT_LIST};
 
@@ -23,7 +68,28 @@ pub struct Context {
     /// We'll use this unique ID to identify ourselves to the poststation
     /// server. This should be unique per device.
     pub unique_id: u64,
-    pub led: Output<'static>,
+    /// Shared with spawned handlers, see [`TaskContext`]
+    pub led: &'static Shared<Led>,
+    /// Shared with the host monitor, see [`liveness`](crate::liveness)
+    #[cfg(full)]
+    pub pwm: &'static Shared<PwmOutputs>,
+    #[cfg(full)]
+    pub i2c: &'static Shared<I2cBridge>,
+    #[cfg(full)]
+    pub spi: &'static Shared<SpiBridge>,
+    #[cfg(full)]
+    pub dac: &'static Shared<DacOutputs>,
+    #[cfg(full)]
+    pub uart: UartBridge,
+    #[cfg(full)]
+    pub can: CanBridge,
+    pub store: ConfigStore,
+    pub update: FirmwareUpdate,
+    /// Shared with the store and the update, for reading out
+    pub flash: &'static SharedFlash,
+    pub bulk: BulkTransfers,
+    #[cfg(full)]
+    pub memory: Memory,
 }
 
 impl SpawnContext for Context {
@@ -32,12 +98,30 @@ impl SpawnContext for Context {
     fn spawn_ctxt(&mut self) -> Self::SpawnCtxt {
         TaskContext {
             unique_id: self.unique_id,
+            led: self.led,
+            #[cfg(full)]
+            i2c: self.i2c,
+            #[cfg(full)]
+            spi: self.spi,
+            #[cfg(full)]
+            dac: self.dac,
+            flash: self.flash,
         }
     }
 }
 
+/// What spawned handlers get, peripherals they share with the server
+/// have to be locked first
 pub struct TaskContext {
     pub unique_id: u64,
+    pub led: &'static Shared<Led>,
+    #[cfg(full)]
+    pub i2c: &'static Shared<I2cBridge>,
+    #[cfg(full)]
+    pub spi: &'static Shared<SpiBridge>,
+    #[cfg(full)]
+    pub dac: &'static Shared<DacOutputs>,
+    pub flash: &'static SharedFlash,
 }
 
 // Type Aliases
@@ -48,7 +132,7 @@ pub struct TaskContext {
 /// control the largest frames we can send or receive.
 pub type BufStorage = PacketBuffers<1024, 1024>;
 /// AppTx is the type of our sender, which is how we send information to the client
-pub type AppTx = RttTx<ThreadModeRawMutex>;
+pub type AppTx = RttTx<CriticalSectionRawMutex>;
 /// AppRx is the type of our receiver, which is how we receive information from the client
 pub type AppRx = RttRx;
 /// AppServer is the type of the postcard-rpc server we are using
@@ -58,6 +142,7 @@ pub type AppServer = Server<AppTx, AppRx, WireRxBuf, MyApp>;
 pub static PBUFS: ConstStaticCell<BufStorage> = ConstStaticCell::new(BufStorage::new());
 
 // This macro defines your application
+#[cfg(full)]
 define_dispatch! {
     // You can set the name of your app to any valid Rust type name. We use
     // "MyApp" here. You'll use this in `main` to create an instance of the
@@ -95,8 +180,65 @@ define_dispatch! {
         | GetUniqueIdEndpoint       | blocking  | unique_id                     |
         // | RebootToPicoBoot          | blocking  | picoboot_reset                |
         | SleepEndpoint             | spawn     | sleep_handler                 |
-        | SetLedEndpoint            | blocking  | set_led                       |
-        | GetLedEndpoint            | blocking  | get_led                       |
+        | CancellableSleepEndpoint  | spawn     | cancellable_sleep_handler     |
+        | SetLedEndpoint            | async     | set_led                       |
+        | GetLedEndpoint            | async     | get_led                       |
+        | SetLedBrightnessEndpoint  | async     | set_led_brightness            |
+        | ConfigurePwmEndpoint      | async     | configure_pwm                 |
+        | SetPwmDutyEndpoint        | async     | set_pwm_duty                  |
+        | DisablePwmEndpoint        | async     | disable_pwm                   |
+        | ConfigureI2cEndpoint      | async     | configure_i2c                 |
+        | I2cWriteEndpoint          | async     | i2c_write                     |
+        | I2cReadEndpoint           | async     | i2c_read                      |
+        | I2cWriteReadEndpoint      | async     | i2c_write_read                |
+        | I2cReadRegisterEndpoint   | async     | i2c_read_register             |
+        | I2cWriteRegisterEndpoint  | async     | i2c_write_register            |
+        | I2cScanEndpoint           | async     | i2c_scan                      |
+        | ConfigureSpiEndpoint      | async     | configure_spi                 |
+        | DeclareSpiCsEndpoint      | async     | declare_spi_cs                |
+        | SpiTransactionEndpoint    | async     | spi_transaction               |
+        | ConfigureUartEndpoint     | async     | configure_uart                |
+        | ConfigureCanEndpoint      | async     | configure_can                 |
+        | SetCanFilterEndpoint      | async     | set_can_filter                |
+        | GetCanErrorsEndpoint      | async     | get_can_errors                |
+        | GetConfigEndpoint         | async     | get_config                    |
+        | SetConfigEndpoint         | async     | set_config                    |
+        | DeleteConfigEndpoint      | async     | delete_config                 |
+        | ListConfigEndpoint        | async     | list_config                   |
+        | FactoryResetEndpoint      | async     | factory_reset                 |
+        | GetFirmwareStateEndpoint  | blocking  | get_firmware_state            |
+        | ResetEndpoint             | spawn     | reset_handler                 |
+        | BulkOpenEndpoint          | blocking  | bulk_open                     |
+        | BulkStatusEndpoint        | blocking  | bulk_status                   |
+        | BulkFinishEndpoint        | blocking  | bulk_finish                   |
+        | BulkAbortEndpoint         | blocking  | bulk_abort                    |
+        | MemoryRegionsEndpoint     | blocking  | memory_regions                |
+        | MemoryReadEndpoint        | blocking  | memory_read                   |
+        | MemoryUnlockEndpoint      | blocking  | memory_unlock                 |
+        | MemoryWriteEndpoint       | blocking  | memory_write                  |
+        | ExecutorStatsEndpoint     | blocking  | executor_stats                |
+        | RamStatsEndpoint          | blocking  | ram_stats                     |
+        | ClockTreeEndpoint         | blocking  | clock_tree                    |
+        | PowerStatsEndpoint        | blocking  | power_stats                   |
+        | JobStartEndpoint          | spawn     | job_start                     |
+        | JobStatusEndpoint         | blocking  | job_status                    |
+        | JobCancelEndpoint         | blocking  | job_cancel                    |
+        | HostHelloEndpoint         | spawn     | host_hello                    |
+        | TimeNowEndpoint           | blocking  | time_now                      |
+        | TimeSetEndpoint           | blocking  | time_set                      |
+        | DacSetEndpoint            | async     | dac_set                       |
+        | DacPlayEndpoint           | async     | dac_play                      |
+        | DacStopEndpoint           | spawn     | dac_stop                      |
+        | CaptureMeasureEndpoint    | spawn     | capture_measure               |
+        | CaptureStreamEndpoint     | blocking  | capture_stream                |
+        | EncoderConfigureEndpoint  | blocking  | encoder_configure             |
+        | EncoderReadEndpoint       | blocking  | encoder_read                  |
+        | EncoderZeroEndpoint       | blocking  | encoder_zero                  |
+        | EncoderPublishEndpoint    | blocking  | encoder_publish               |
+        | LogicCaptureEndpoint      | spawn     | logic_capture                 |
+        | PatternPlayEndpoint       | blocking  | pattern_play                  |
+        | PatternStopEndpoint       | blocking  | pattern_stop                  |
+        | PatternStatusEndpoint     | blocking  | pattern_status                |
     };
 
     // Topics IN are messages we receive from the client, but that we do not reply
@@ -109,6 +251,11 @@ define_dispatch! {
 
         | TopicTy                   | kind      | handler                       |
         | ----------                | ----      | -------                       |
+        | UartTxTopic               | async     | uart_tx                       |
+        | CanTxTopic                | async     | can_tx                        |
+        | BulkUploadTopic           | async     | bulk_upload                   |
+        | BulkDownloadAckTopic      | async     | bulk_download_ack             |
+        | CancelTopic               | blocking  | cancel_request                |
     };
 
     // Topics OUT are the messages we send to the client whenever we'd like. Since
@@ -119,6 +266,50 @@ define_dispatch! {
     };
 }
 
+// The basic firmware, see build.rs, serves what the ICD's `basic` feature
+// leaves in
+#[cfg(not(full))]
+define_dispatch! {
+    app: MyApp;
+    spawn_fn: embassy_spawn;
+    tx_impl: AppTx;
+    spawn_impl: EUsbWireSpawn;
+    context: Context;
+
+    endpoints: {
+        list: ENDPOINT_LIST;
+
+        | EndpointTy                | kind      | handler                       |
+        | ----------                | ----      | -------                       |
+        | GetUniqueIdEndpoint       | blocking  | unique_id                     |
+        | SleepEndpoint             | spawn     | sleep_handler                 |
+        | SetLedEndpoint            | async     | set_led                       |
+        | GetLedEndpoint            | async     | get_led                       |
+        | SetLedBrightnessEndpoint  | async     | set_led_brightness            |
+        | GetFirmwareStateEndpoint  | blocking  | get_firmware_state            |
+        | ResetEndpoint             | spawn     | reset_handler                 |
+        | BulkOpenEndpoint          | blocking  | bulk_open                     |
+        | BulkStatusEndpoint        | blocking  | bulk_status                   |
+        | BulkFinishEndpoint        | blocking  | bulk_finish                   |
+        | BulkAbortEndpoint         | blocking  | bulk_abort                    |
+        | PowerStatsEndpoint        | blocking  | power_stats                   |
+        | HostHelloEndpoint         | spawn     | host_hello                    |
+    };
+
+    topics_in: {
+        list: TOPICS_IN_LIST;
+
+        | TopicTy                   | kind      | handler                       |
+        | ----------                | ----      | -------                       |
+        | BulkUploadTopic           | async     | bulk_upload                   |
+        | BulkDownloadAckTopic      | async     | bulk_download_ack             |
+    };
+
+    topics_out: {
+        list: TOPICS_OUT_LIST;
+    };
+}
+
 //////////////////////////////////////////////////////////////////////////////
 // SPAWN
 //////////////////////////////////////////////////////////////////////////////
@@ -126,12 +317,13 @@ define_dispatch! {
 /// A [`WireSpawn`] impl using the embassy executor
 #[derive(Clone)]
 pub struct EUsbWireSpawn {
-    /// The embassy-executor spawner
-    pub spawner: Spawner,
+    /// The embassy-executor spawner, for the thread mode executor while
+    /// the server itself runs on an interrupt executor
+    pub spawner: SendSpawner,
 }
 
-impl From<Spawner> for EUsbWireSpawn {
-    fn from(value: Spawner) -> Self {
+impl From<SendSpawner> for EUsbWireSpawn {
+    fn from(value: SendSpawner) -> Self {
         Self { spawner: value }
     }
 }
@@ -139,20 +331,20 @@ impl From<Spawner> for EUsbWireSpawn {
 impl WireSpawn for EUsbWireSpawn {
     type Error = SpawnError;
 
-    type Info = Spawner;
+    type Info = SendSpawner;
 
     fn info(&self) -> &Self::Info {
         &self.spawner
     }
 }
 
-/// Attempt to spawn the given token
-pub fn embassy_spawn<Sp, S: Sized>(sp: &Sp, tok: SpawnToken<S>) -> Result<(), Sp::Error>
+/// Attempt to spawn the given token, counting it against its task pool
+pub fn embassy_spawn<Sp, S: Send>(sp: &Sp, tok: tasks::Counted<S>) -> Result<(), Sp::Error>
 where
-    Sp: WireSpawn<Error = SpawnError, Info = Spawner>,
+    Sp: WireSpawn<Error = SpawnError, Info = SendSpawner>,
 {
     let info = sp.info();
-    info.spawn(tok)
+    tasks::spawn_send(info, tok)
 }
 
 
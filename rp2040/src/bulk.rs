@@ -0,0 +1,215 @@
+//! Chunked transfers for payloads bigger than one frame
+//!
+//! Upload chunks arrive on [`BulkUploadTopic`](template_icd::BulkUploadTopic)
+//! and are acked every [`BULK_WINDOW`] chunks. Downloads run the other way,
+//! each ack from the host asking for the next window. Acks are cumulative,
+//! so a lost chunk or ack costs a resend from the last acked offset.
+//!
+//! Subsystems join in with a [`BulkTarget`] variant and a [`BulkSource`]
+//! or [`BulkSink`], matched on in the bulk handlers.
+
+use crc::{Crc, Digest, CRC_32_ISO_HDLC};
+use embassy_stm32::flash::BANK1_REGION;
+use postcard_rpc::server::Sender;
+use template_icd::{
+    BulkAck, BulkChunk, BulkData, BulkDownloadTopic, BulkError, BulkFinish, BulkId, BulkInfo, BulkResult,
+    BulkStatusResult, BulkTarget, BulkUploadAckTopic, BULK_MAX_CHUNK, BULK_WINDOW,
+};
+
+use crate::{app::AppTx, session::TopicSeq, SharedFlash};
+
+/// CRC-32 as zlib computes it, also used by [`jobs`](crate::jobs)
+pub static CRC: Crc<u32> = Crc::<u32>::new(&CRC_32_ISO_HDLC);
+
+/// Where a download reads from
+pub trait BulkSource {
+    /// `offset` counts from the start of the transfer
+    fn read(&mut self, offset: u32, buf: &mut [u8]) -> BulkResult;
+}
+
+/// Where an upload writes to
+pub trait BulkSink {
+    /// Chunks come in order and without gaps, each one once
+    fn write(&mut self, offset: u32, data: &[u8]) -> BulkResult;
+}
+
+/// A range of internal flash
+pub struct FlashSource {
+    flash: &'static SharedFlash,
+    base: u32,
+}
+
+impl FlashSource {
+    pub fn new(flash: &'static SharedFlash, base: u32) -> Self {
+        Self { flash, base }
+    }
+
+    /// The size of the range, if it lies within the flash
+    pub fn check_range(offset: u32, size: u32) -> Result<u32, BulkError> {
+        match offset.checked_add(size) {
+            Some(end) if end <= BANK1_REGION.size => Ok(size),
+            _ => Err(BulkError::OutOfRange),
+        }
+    }
+}
+
+impl BulkSource for FlashSource {
+    fn read(&mut self, offset: u32, buf: &mut [u8]) -> BulkResult {
+        self.flash
+            .lock(|flash| flash.borrow_mut().blocking_read(self.base + offset, buf))
+            .map_err(|_| BulkError::Flash)
+    }
+}
+
+struct Transfer {
+    id: BulkId,
+    target: BulkTarget,
+    size: u32,
+    /// Everything before this has been acked
+    next: u32,
+    /// CRC over the payload up to `crc_at`. Uploads only take chunks at
+    /// `next`, downloads only count the first send of each byte.
+    crc: Digest<'static, u32>,
+    crc_at: u32,
+    /// Upload chunks taken since the last ack
+    unacked: u32,
+    /// A gap has been acked already, so the rest of the window is dropped quietly
+    gap: bool,
+    /// The target failed, the host finds out from the status
+    failed: Option<BulkError>,
+}
+
+#[derive(Default)]
+pub struct BulkTransfers {
+    next_id: BulkId,
+    open: Option<Transfer>,
+    seq: TopicSeq,
+}
+
+impl BulkTransfers {
+    /// Drops any transfer that is still open, the caller has checked the
+    /// target and prepared it
+    pub fn open(&mut self, target: BulkTarget, size: u32) -> BulkInfo {
+        let id = self.next_id;
+        self.next_id = self.next_id.wrapping_add(1);
+        self.open = Some(Transfer {
+            id,
+            target,
+            size,
+            next: 0,
+            crc: CRC.digest(),
+            crc_at: 0,
+            unacked: 0,
+            gap: false,
+            failed: None,
+        });
+        BulkInfo { id, size }
+    }
+
+    pub fn target(&self, id: BulkId) -> Option<BulkTarget> {
+        self.open.as_ref().filter(|t| t.id == id).map(|t| t.target)
+    }
+
+    pub fn status(&self, id: BulkId) -> BulkStatusResult {
+        let transfer = self.open.as_ref().filter(|t| t.id == id).ok_or(BulkError::UnknownTransfer)?;
+        match transfer.failed {
+            Some(e) => Err(e),
+            None => Ok(BulkAck { id, next: transfer.next }),
+        }
+    }
+
+    pub fn abort(&mut self, id: BulkId) -> BulkResult {
+        self.target(id).ok_or(BulkError::UnknownTransfer)?;
+        self.open = None;
+        Ok(())
+    }
+
+    /// Checks the CRC and closes the transfer, returning the target so the
+    /// caller can commit an upload
+    pub fn finish(&mut self, arg: &BulkFinish) -> Result<BulkTarget, BulkError> {
+        self.status(arg.id)?;
+        let transfer = self.open.take().ok_or(BulkError::UnknownTransfer)?;
+        if transfer.next != transfer.size {
+            self.open = Some(transfer);
+            return Err(BulkError::Incomplete);
+        }
+        if transfer.crc.finalize() != arg.crc {
+            return Err(BulkError::CrcMismatch);
+        }
+        Ok(transfer.target)
+    }
+
+    /// Take an upload chunk, acking when a window is complete or a chunk went missing
+    pub async fn receive(&mut self, chunk: &BulkChunk, sink: &mut impl BulkSink, sender: &Sender<AppTx>) {
+        let Some(transfer) = self.open.as_mut().filter(|t| t.id == chunk.id && t.failed.is_none()) else {
+            return;
+        };
+        if chunk.offset != transfer.next {
+            // Repeats of acked chunks need no answer, the sender has moved on
+            if chunk.offset < transfer.next || transfer.gap {
+                return;
+            }
+            transfer.gap = true;
+        } else {
+            let end = chunk.offset + chunk.data.len() as u32;
+            if chunk.data.is_empty() || end > transfer.size {
+                transfer.failed = Some(BulkError::OutOfRange);
+                return;
+            }
+            if let Err(e) = sink.write(chunk.offset, &chunk.data) {
+                transfer.failed = Some(e);
+                return;
+            }
+            transfer.crc.update(&chunk.data);
+            transfer.crc_at = end;
+            transfer.next = end;
+            transfer.gap = false;
+            transfer.unacked += 1;
+            if transfer.unacked < BULK_WINDOW && end < transfer.size {
+                return;
+            }
+        }
+        transfer.unacked = 0;
+        let ack = BulkAck {
+            id: transfer.id,
+            next: transfer.next,
+        };
+        let _ = sender.publish::<BulkUploadAckTopic>(self.seq.advance(), &ack).await;
+    }
+
+    /// Send the window following an ack from the host
+    pub async fn send(&mut self, ack: &BulkAck, source: &mut impl BulkSource, sender: &Sender<AppTx>) {
+        let Some(transfer) = self.open.as_mut().filter(|t| t.id == ack.id && t.failed.is_none()) else {
+            return;
+        };
+        // The host can't have more than was sent
+        if ack.next > transfer.crc_at {
+            return;
+        }
+        transfer.next = transfer.next.max(ack.next);
+
+        let end = transfer.size.min(ack.next + BULK_WINDOW * BULK_MAX_CHUNK as u32);
+        let mut offset = ack.next;
+        while offset < end {
+            let len = (end - offset).min(BULK_MAX_CHUNK as u32);
+            let mut data = BulkData::new();
+            // Never more than the capacity
+            let _ = data.resize(len as usize, 0);
+            if let Err(e) = source.read(offset, &mut data) {
+                transfer.failed = Some(e);
+                return;
+            }
+            if offset == transfer.crc_at {
+                transfer.crc.update(&data);
+                transfer.crc_at += len;
+            }
+            let chunk = BulkChunk {
+                id: transfer.id,
+                offset,
+                data,
+            };
+            let _ = sender.publish::<BulkDownloadTopic>(self.seq.advance(), &chunk).await;
+            offset += len;
+        }
+    }
+}
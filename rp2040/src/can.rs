@@ -0,0 +1,362 @@
+//! CAN FD bridge on FDCAN1
+//!
+//! [`can_task`] owns the controller and every handle of its driver, on the
+//! thread mode executor. The server only holds a [`CanBridge`], which
+//! passes its requests over to the task.
+
+use core::{
+    fmt::Debug,
+    sync::atomic::{AtomicU32, Ordering},
+};
+
+use embassy_futures::select::{select, Either};
+use embassy_stm32::{
+    can::{
+        config::{GlobalFilter, NonMatchingFilter},
+        enums::{BusError, BusErrorMode},
+        filter::{
+            Action, ExtendedFilter, ExtendedFilterSlot, FilterType, StandardFilter, StandardFilterSlot,
+            EXTENDED_FILTER_MAX, STANDARD_FILTER_MAX,
+        },
+        frame::{FdFrame, Header},
+        util::calc_can_timings,
+        CanConfigurator, CanRx, CanTx, OperatingMode, Properties,
+    },
+    peripherals::{FDCAN1, PA11, PA12},
+    rcc,
+};
+use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, channel::Channel, signal::Signal};
+use embassy_time::Timer;
+use embedded_can::{ExtendedId, Id, StandardId};
+use postcard_rpc::server::Sender;
+use template_icd::{
+    CanBusState, CanConfig, CanData, CanError, CanErrorCounters, CanErrorsResult, CanFilter,
+    CanFilterAction, CanFilterMatch, CanFrame, CanId, CanMode, CanResult, CanRxFrame, CanRxTopic,
+};
+
+use crate::{
+    app::AppTx,
+    session::TopicSeq,
+    tasks::pooled_task,
+    wallclock,
+};
+
+/// What the server asks of [`can_task`], one request at a time
+enum Request {
+    Configure(CanConfig),
+    SetFilter(CanFilter),
+    Errors,
+    Send(CanFrame),
+}
+
+static REQUESTS: Channel<CriticalSectionRawMutex, Request, 1> = Channel::new();
+static RESULT: Signal<CriticalSectionRawMutex, CanResult> = Signal::new();
+static ERRORS: Signal<CriticalSectionRawMutex, CanErrorsResult> = Signal::new();
+/// Protocol errors seen by [`can_task`] since the last configure
+static BUS_ERRORS: AtomicU32 = AtomicU32::new(0);
+
+/// The data phase prescaler is narrower than the nominal one
+const MAX_DATA_PRESCALER: u16 = 32;
+
+/// The server's side of the bridge. The driver stays in [`can_task`], so
+/// none of its handles leave the thread mode executor. `&mut self` keeps
+/// the requests one at a time.
+#[derive(Default)]
+pub struct CanBridge(());
+
+impl CanBridge {
+    async fn request(&mut self, request: Request) -> CanResult {
+        RESULT.reset();
+        REQUESTS.send(request).await;
+        RESULT.wait().await
+    }
+
+    /// Restart the controller with a new config, this resets the error
+    /// counters and drops any frames still queued
+    pub async fn configure(&mut self, config: CanConfig) -> CanResult {
+        self.request(Request::Configure(config)).await
+    }
+
+    pub async fn set_filter(&mut self, filter: CanFilter) -> CanResult {
+        self.request(Request::SetFilter(filter)).await
+    }
+
+    /// Queue a frame from the host, failures are counted in [`CanErrorCounters::tx_dropped`]
+    pub async fn send(&mut self, frame: &CanFrame) -> CanResult {
+        self.request(Request::Send(frame.clone())).await
+    }
+
+    pub async fn errors(&mut self) -> CanErrorsResult {
+        ERRORS.reset();
+        REQUESTS.send(Request::Errors).await;
+        ERRORS.wait().await
+    }
+}
+
+/// What carries over from one driver to the next
+struct Bridge {
+    fd: bool,
+    tx_dropped: u32,
+    standard: [StandardFilter; STANDARD_FILTER_MAX as usize],
+    extended: [ExtendedFilter; EXTENDED_FILTER_MAX as usize],
+}
+
+/// The running driver's halves, for requests
+struct Driver<'a, 'd> {
+    tx: &'a mut CanTx<'d>,
+    properties: &'a Properties,
+}
+
+impl Bridge {
+    fn set_filter(&mut self, filter: CanFilter, driver: Option<&Driver>) -> CanResult {
+        let action = match filter.action {
+            CanFilterAction::Accept => Action::StoreInFifo0,
+            CanFilterAction::Reject => Action::Reject,
+        };
+        let slot = filter.slot as usize;
+        if filter.extended {
+            let f = self.extended.get_mut(slot).ok_or(CanError::InvalidFilterSlot)?;
+            f.filter = filter_type(filter.matching, ExtendedId::new, Some)?;
+            f.action = action;
+            if let Some(driver) = driver {
+                driver.properties.set_extended_filter(ExtendedFilterSlot::from(filter.slot), *f);
+            }
+        } else {
+            let f = self.standard.get_mut(slot).ok_or(CanError::InvalidFilterSlot)?;
+            f.filter = filter_type(
+                filter.matching,
+                |id| StandardId::new(u16::try_from(id).ok()?),
+                |m| u16::try_from(m).ok(),
+            )?;
+            f.action = action;
+            if let Some(driver) = driver {
+                driver.properties.set_standard_filter(StandardFilterSlot::from(filter.slot), *f);
+            }
+        }
+        Ok(())
+    }
+
+    async fn send(&mut self, frame: &CanFrame, driver: Option<&mut Driver<'_, '_>>) -> CanResult {
+        let res = self.try_send(frame, driver).await;
+        if res.is_err() {
+            self.tx_dropped += 1;
+        }
+        res
+    }
+
+    async fn try_send(&mut self, frame: &CanFrame, driver: Option<&mut Driver<'_, '_>>) -> CanResult {
+        let Some(driver) = driver else {
+            return Err(CanError::NotConfigured);
+        };
+        if frame.fd && !self.fd {
+            return Err(CanError::FdNotEnabled);
+        }
+        if !frame.fd && frame.data.len() > 8 {
+            return Err(CanError::InvalidLength);
+        }
+        let id = match frame.id {
+            CanId::Standard(id) => StandardId::new(id).map(Id::Standard),
+            CanId::Extended(id) => ExtendedId::new(id).map(Id::Extended),
+        }
+        .ok_or(CanError::InvalidId)?;
+        let len = frame.data.len();
+        let frame = match (frame.remote, frame.fd) {
+            (true, _) => FdFrame::new_remote(id, len),
+            (false, true) => FdFrame::new(Header::new_fd(id, len as u8, false, frame.brs), &frame.data),
+            (false, false) => FdFrame::new(Header::new(id, len as u8, false), &frame.data),
+        }
+        .map_err(|_| CanError::InvalidLength)?;
+        // When the queue is full, a queued frame of lower priority gets
+        // bumped to make room for this one
+        if driver.tx.write_fd(&frame).await.is_some() {
+            self.tx_dropped += 1;
+        }
+        Ok(())
+    }
+
+    fn errors(&self, driver: Option<&Driver>) -> CanErrorsResult {
+        let Some(driver) = driver else {
+            return Err(CanError::NotConfigured);
+        };
+        let properties = driver.properties;
+        Ok(CanErrorCounters {
+            tx_errors: properties.tx_error_count(),
+            rx_errors: properties.rx_error_count(),
+            state: match properties.bus_error_mode() {
+                BusErrorMode::ErrorActive => CanBusState::ErrorActive,
+                BusErrorMode::ErrorPassive => CanBusState::ErrorPassive,
+                BusErrorMode::BusOff => CanBusState::BusOff,
+            },
+            bus_errors: BUS_ERRORS.load(Ordering::Relaxed),
+            tx_dropped: self.tx_dropped,
+        })
+    }
+
+    /// Answers requests until a valid config comes, which is answered once
+    /// the task has started with it
+    async fn serve(&mut self, mut driver: Option<Driver<'_, '_>>) -> CanConfig {
+        loop {
+            match REQUESTS.receive().await {
+                Request::Configure(config) => match check(&config) {
+                    Ok(()) => return config,
+                    Err(e) => RESULT.signal(Err(e)),
+                },
+                Request::SetFilter(filter) => RESULT.signal(self.set_filter(filter, driver.as_ref())),
+                Request::Errors => ERRORS.signal(self.errors(driver.as_ref())),
+                Request::Send(frame) => RESULT.signal(self.send(&frame, driver.as_mut()).await),
+            }
+        }
+    }
+}
+
+/// The driver panics on bitrates it can't reach, so check them first
+fn check(config: &CanConfig) -> CanResult {
+    let clock = rcc::frequency::<FDCAN1>();
+    if calc_can_timings(clock, config.nominal_bitrate).is_none() {
+        return Err(CanError::InvalidBitrate);
+    }
+    if let Some(bitrate) = config.data_bitrate {
+        match calc_can_timings(clock, bitrate) {
+            Some(t) if t.prescaler.get() <= MAX_DATA_PRESCALER => {}
+            _ => return Err(CanError::InvalidBitrate),
+        }
+    }
+    Ok(())
+}
+
+fn filter_type<ID: Copy + Debug, UNIT: Copy + Debug>(
+    matching: CanFilterMatch,
+    id: impl Fn(u32) -> Option<ID>,
+    unit: impl Fn(u32) -> Option<UNIT>,
+) -> Result<FilterType<ID, UNIT>, CanError> {
+    let id = |raw| id(raw).ok_or(CanError::InvalidId);
+    let unit = |raw| unit(raw).ok_or(CanError::InvalidId);
+    Ok(match matching {
+        CanFilterMatch::Disabled => FilterType::Disabled,
+        CanFilterMatch::Exact(a) => FilterType::DedicatedSingle(id(a)?),
+        CanFilterMatch::Either(a, b) => FilterType::DedicatedDual(id(a)?, id(b)?),
+        CanFilterMatch::Range { from, to } => FilterType::Range {
+            from: id(from)?,
+            to: id(to)?,
+        },
+        CanFilterMatch::Mask { id, mask } => FilterType::BitMask {
+            filter: unit(id)?,
+            mask: unit(mask)?,
+        },
+    })
+}
+
+pooled_task! {
+    /// Owns the controller, building a driver for each config. Frames received
+    /// go to the host, and requests from [`CanBridge`] are answered in between.
+    /// The bus starts with `config` when there is a valid one.
+    pub fn can_task(
+        peri: FDCAN1,
+        rx_pin: PA11,
+        tx_pin: PA12,
+        config: Option<CanConfig>,
+        sender: Sender<AppTx>,
+    ) => run, CAN_POOL[1]
+}
+
+async fn run(
+    mut peri: FDCAN1,
+    mut rx_pin: PA11,
+    mut tx_pin: PA12,
+    config: Option<CanConfig>,
+    sender: Sender<AppTx>,
+) {
+    let mut bridge = Bridge {
+        fd: false,
+        tx_dropped: 0,
+        standard: [StandardFilter::disable(); STANDARD_FILTER_MAX as usize],
+        extended: [ExtendedFilter::disable(); EXTENDED_FILTER_MAX as usize],
+    };
+    let mut seq = TopicSeq::new();
+    let mut next = config.filter(|config| check(config).is_ok());
+    loop {
+        let config = match next.take() {
+            Some(config) => config,
+            None => bridge.serve(None).await,
+        };
+
+        // Borrowed for as long as this driver lasts, the next one starts
+        // after it has gone
+        let mut can = CanConfigurator::new(&mut peri, &mut rx_pin, &mut tx_pin, crate::Irqs);
+        let unmatched = match config.accept_unmatched {
+            true => NonMatchingFilter::IntoRxFifo0,
+            false => NonMatchingFilter::Reject,
+        };
+        can.set_config(
+            can.config().set_global_filter(
+                GlobalFilter::default()
+                    .set_handle_standard_frames(unmatched)
+                    .set_handle_extended_frames(unmatched),
+            ),
+        );
+        can.set_bitrate(config.nominal_bitrate);
+        if let Some(bitrate) = config.data_bitrate {
+            can.set_fd_data_bitrate(bitrate, false);
+        }
+        // Creating the configurator cleared the message RAM, filters included
+        can.properties().set_standard_filters(&bridge.standard);
+        can.properties().set_extended_filters(&bridge.extended);
+
+        let mode = match config.mode {
+            CanMode::Normal => OperatingMode::NormalOperationMode,
+            CanMode::InternalLoopback => OperatingMode::InternalLoopbackMode,
+            CanMode::ExternalLoopback => OperatingMode::ExternalLoopbackMode,
+            CanMode::Silent => OperatingMode::BusMonitoringMode,
+        };
+        let (mut tx, mut rx, properties) = can.start(mode).split();
+        BUS_ERRORS.store(0, Ordering::Relaxed);
+        bridge.fd = config.data_bitrate.is_some();
+        bridge.tx_dropped = 0;
+        RESULT.signal(Ok(()));
+
+        let driver = Driver {
+            tx: &mut tx,
+            properties: &properties,
+        };
+        if let Either::Second(config) = select(receive(&mut rx, &sender, &mut seq), bridge.serve(Some(driver))).await {
+            next = Some(config);
+        }
+    }
+}
+
+/// Forward every received frame to the host
+async fn receive(rx: &mut CanRx<'_>, sender: &Sender<AppTx>, seq: &mut TopicSeq) {
+    loop {
+        match rx.read_fd().await {
+            Ok(envelope) => {
+                let (frame, ts) = envelope.parts();
+                let header = frame.header();
+                let mut data = CanData::new();
+                let _ = data.extend_from_slice(&frame.data()[..header.len() as usize]);
+                let id = match *header.id() {
+                    Id::Standard(id) => CanId::Standard(id.as_raw()),
+                    Id::Extended(id) => CanId::Extended(id.as_raw()),
+                };
+                let msg = CanRxFrame {
+                    timestamp: wallclock::timestamp(ts),
+                    frame: CanFrame {
+                        id,
+                        remote: header.rtr(),
+                        fd: header.fdcan(),
+                        brs: header.bit_rate_switching(),
+                        data,
+                    },
+                };
+                let _ = sender.publish::<CanRxTopic>(seq.advance(), &msg).await;
+            }
+            // The driver reports these on every read for as long as the
+            // state lasts, back off rather than spin
+            Err(BusError::BusOff | BusError::BusPassive | BusError::BusWarning) => {
+                Timer::after_millis(1).await;
+            }
+            Err(_) => {
+                BUS_ERRORS.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+}
@@ -0,0 +1,76 @@
+//! Cancelling spawned requests
+//!
+//! The host publishes the sequence number of a request to
+//! [`CancelTopic`](template_icd::CancelTopic), and a spawned handler running
+//! its work through [`cancellable`] stops and answers
+//! [`Cancelled`]. A cancel for a request that isn't running yet, or has
+//! already finished, does nothing.
+
+use core::{cell::RefCell, future::Future};
+
+use embassy_futures::select::{select, Either};
+use embassy_sync::{
+    blocking_mutex::{raw::CriticalSectionRawMutex, Mutex},
+    signal::Signal,
+};
+use postcard_rpc::header::VarSeq;
+use template_icd::Cancelled;
+
+/// Requests that can be cancelled at once, beyond this they just run to the end
+const SLOTS: usize = 8;
+
+static SEQS: Mutex<CriticalSectionRawMutex, RefCell<[Option<u32>; SLOTS]>> = Mutex::new(RefCell::new([None; SLOTS]));
+static SIGNALS: [Signal<CriticalSectionRawMutex, ()>; SLOTS] = [const { Signal::new() }; SLOTS];
+
+/// Frees the slot again when the request is done, however that goes
+struct Registration(Option<usize>);
+
+impl Registration {
+    fn new(seq: u32) -> Self {
+        let slot = SEQS.lock(|seqs| {
+            let mut seqs = seqs.borrow_mut();
+            let slot = seqs.iter().position(Option::is_none)?;
+            seqs[slot] = Some(seq);
+            SIGNALS[slot].reset();
+            Some(slot)
+        });
+        Self(slot)
+    }
+
+    async fn cancelled(&self) {
+        match self.0 {
+            Some(slot) => SIGNALS[slot].wait().await,
+            None => core::future::pending().await,
+        }
+    }
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        if let Some(slot) = self.0 {
+            SEQS.lock(|seqs| seqs.borrow_mut()[slot] = None);
+        }
+    }
+}
+
+/// Run the work of the request `seq`, unless the host cancels it first
+pub async fn cancellable<F: Future>(seq: VarSeq, work: F) -> Result<F::Output, Cancelled> {
+    let seq = match seq {
+        VarSeq::Seq1(seq) => seq.into(),
+        VarSeq::Seq2(seq) => seq.into(),
+        VarSeq::Seq4(seq) => seq,
+    };
+    let registration = Registration::new(seq);
+    match select(work, registration.cancelled()).await {
+        Either::First(output) => Ok(output),
+        Either::Second(()) => Err(Cancelled),
+    }
+}
+
+pub fn cancel(seq: u32) {
+    SEQS.lock(|seqs| {
+        if let Some(slot) = seqs.borrow().iter().position(|s| *s == Some(seq)) {
+            SIGNALS[slot].signal(());
+        }
+    });
+}
@@ -0,0 +1,359 @@
+//! Frequency and pulse width measurement with TIM4 input capture
+//!
+//! Every capture interrupts, and the handler adds the period up, counting
+//! timer overflows in so periods past the 16-bit counter come out whole.
+//! The totals only grow while capture runs, and a measurement takes their
+//! difference over its window. That lets measurements on request share the
+//! timer with the stream on [`CaptureTopic`], as long as they measure the
+//! same input the same way. Edges closer together than the handler takes to
+//! run are missed, which shows in the measurement.
+
+use core::cell::RefCell;
+
+use embassy_futures::select::{select, Either};
+use embassy_stm32::{
+    gpio::Pull,
+    interrupt::typelevel::{self, Binding, Handler, Interrupt},
+    pac::{
+        self,
+        timer::{
+            regs::SrGp16,
+            vals::{CcmrInputCcs, Sms, Ts, Urs},
+        },
+    },
+    peripherals::{PB6, PB7, TIM4},
+    timer::{
+        input_capture::{CapturePin, Ch1, Ch2},
+        low_level,
+    },
+};
+use embassy_sync::{
+    blocking_mutex::{raw::CriticalSectionRawMutex, Mutex},
+    signal::Signal,
+};
+use embassy_time::{Duration, Ticker, Timer};
+use postcard_rpc::server::Sender;
+use static_cell::StaticCell;
+use template_icd::{
+    CaptureError, CaptureInput, CaptureMeasureResult, CaptureMeasurement, CaptureMode, CapturePulse, CaptureRequest,
+    CaptureResult, CaptureStream, CaptureTopic, CAPTURE_MAX_WINDOW_MS,
+};
+
+use crate::{
+    app::AppTx,
+    session::TopicSeq,
+    tasks::pooled_task,
+};
+
+static STATE: Mutex<CriticalSectionRawMutex, RefCell<State>> = Mutex::new(RefCell::new(State {
+    edges: Edges::new(CaptureInput::Ch1, CaptureMode::InputCapture),
+    running: None,
+    users: 0,
+    streaming: false,
+    timer_hz: 0,
+}));
+static STREAM: Signal<CriticalSectionRawMutex, CaptureStream> = Signal::new();
+
+#[derive(Clone, Copy)]
+struct Totals {
+    periods: u64,
+    period_ticks: u64,
+    high_ticks: u64,
+    missed: u64,
+}
+
+/// What the interrupt handler keeps between captures
+struct Edges {
+    /// The channel capturing rising edges, and the one capturing falling
+    /// edges in PWM input mode
+    rise: usize,
+    fall: usize,
+    pwm_input: bool,
+    overflows: u64,
+    /// Where the period under way began, in ticks with overflows counted in
+    start: Option<u64>,
+    /// Ticks from its start to its falling edge
+    high: Option<u64>,
+    totals: Totals,
+}
+
+impl Edges {
+    const fn new(input: CaptureInput, mode: CaptureMode) -> Self {
+        let (rise, fall) = match input {
+            CaptureInput::Ch1 => (0, 1),
+            CaptureInput::Ch2 => (1, 0),
+        };
+        Self {
+            rise,
+            fall,
+            pwm_input: matches!(mode, CaptureMode::PwmInput),
+            overflows: 0,
+            start: None,
+            high: None,
+            totals: Totals {
+                periods: 0,
+                period_ticks: 0,
+                high_ticks: 0,
+                missed: 0,
+            },
+        }
+    }
+
+    fn on_interrupt(&mut self, sr: SrGp16) {
+        let timer = pac::TIM4;
+        let wrapped = sr.uif();
+        let rise = sr.ccif(self.rise);
+        let fall = self.pwm_input && sr.ccif(self.fall);
+        // An overflow that came after the capture finds it near the top of
+        // the count, one before it near the bottom
+        let overflows = self.overflows;
+        let at = |ccr: u16| ((overflows + u64::from(wrapped && ccr < 0x8000)) << 16) + u64::from(ccr);
+
+        // With both edges at once, it isn't known which came first
+        if sr.ccof(self.rise) || sr.ccof(self.fall) || (rise && fall) {
+            self.totals.missed += 1;
+            self.start = None;
+            self.high = None;
+        } else if fall {
+            self.high = Some(at(timer.ccr(self.fall).read().ccr()));
+        }
+
+        if rise {
+            let now = at(timer.ccr(self.rise).read().ccr());
+            if let Some(start) = self.start {
+                let high = self.high.take();
+                if !self.pwm_input || high.is_some() {
+                    self.totals.periods += 1;
+                    self.totals.period_ticks += now - start;
+                    self.totals.high_ticks += high.unwrap_or(0);
+                }
+            }
+            if self.pwm_input {
+                // The rise started the counter over, along with the overflows
+                self.overflows = 0;
+                self.start = Some(0);
+                return;
+            }
+            self.start = Some(now);
+        }
+        if wrapped {
+            self.overflows += 1;
+        }
+    }
+}
+
+struct State {
+    edges: Edges,
+    /// Set while someone measures, the stream counts as one
+    running: Option<(CaptureInput, CaptureMode)>,
+    users: u8,
+    streaming: bool,
+    timer_hz: u32,
+}
+
+impl State {
+    fn start(&mut self, input: CaptureInput, mode: CaptureMode) -> Result<Totals, CaptureError> {
+        match self.running {
+            Some(running) if running != (input, mode) => return Err(CaptureError::Busy),
+            Some(_) => {}
+            None => {
+                // The totals carry on, only ever growing
+                self.edges = Edges {
+                    totals: self.edges.totals,
+                    ..Edges::new(input, mode)
+                };
+                configure(&self.edges);
+                self.running = Some((input, mode));
+            }
+        }
+        self.users += 1;
+        Ok(self.edges.totals)
+    }
+
+    fn release(&mut self) {
+        self.users -= 1;
+        if self.users == 0 {
+            let timer = pac::TIM4;
+            timer.cr1().modify(|w| w.set_cen(false));
+            timer.dier().write(|_| {});
+            self.running = None;
+        }
+    }
+}
+
+fn configure(edges: &Edges) {
+    let timer = pac::TIM4;
+    timer.cr1().modify(|w| w.set_cen(false));
+    timer.dier().write(|_| {});
+    // Inputs only map while their channels are off
+    timer.ccer().write(|_| {});
+    timer.ccmr_input(0).write(|w| {
+        // Named for channel 4, TI4 is a channel's own input and TI3 its
+        // neighbour's
+        w.set_ccs(edges.rise, CcmrInputCcs::TI4);
+        w.set_ccs(edges.fall, CcmrInputCcs::TI3);
+    });
+    timer.ccer().write(|w| {
+        w.set_cce(edges.rise, true);
+        w.set_cce(edges.fall, edges.pwm_input);
+        w.set_ccp(edges.fall, true);
+    });
+    timer.smcr().write(|w| {
+        if edges.pwm_input {
+            w.set_sms(Sms::RESET_MODE);
+            w.set_ts([Ts::TI1FP1, Ts::TI2FP2][edges.rise]);
+        }
+    });
+    timer.psc().write_value(0);
+    timer.arr().write(|w| w.set_arr(u16::MAX));
+    // Resets through the slave mode don't count as overflows
+    timer.cr1().modify(|w| w.set_urs(Urs::COUNTER_ONLY));
+    timer.egr().write(|w| w.set_ug(true));
+    timer.sr().write_value(SrGp16(0));
+    timer.dier().write(|w| {
+        w.set_uie(true);
+        w.set_ccie(edges.rise, true);
+        w.set_ccie(edges.fall, edges.pwm_input);
+    });
+    timer.cr1().modify(|w| w.set_cen(true));
+}
+
+pub struct InterruptHandler;
+
+impl Handler<typelevel::TIM4> for InterruptHandler {
+    unsafe fn on_interrupt() {
+        let timer = pac::TIM4;
+        let sr = timer.sr().read();
+        // Zeroes clear flags, ones leave them be
+        timer.sr().write_value(SrGp16(!sr.0));
+        STATE.lock(|state| state.borrow_mut().edges.on_interrupt(sr));
+    }
+}
+
+/// Keeps the timer clocked and the pins on it, capture starts on request
+pub fn init(tim: TIM4, ch1: PB6, ch2: PB7, _irq: impl Binding<typelevel::TIM4, InterruptHandler>) {
+    type Peripherals = (
+        low_level::Timer<'static, TIM4>,
+        CapturePin<'static, TIM4, Ch1>,
+        CapturePin<'static, TIM4, Ch2>,
+    );
+    static PERIPHERALS: StaticCell<Peripherals> = StaticCell::new();
+
+    let timer = low_level::Timer::new(tim);
+    let timer_hz = timer.get_clock_frequency().0;
+    STATE.lock(|state| state.borrow_mut().timer_hz = timer_hz);
+    PERIPHERALS.init((
+        timer,
+        CapturePin::new_ch1(ch1, Pull::None),
+        CapturePin::new_ch2(ch2, Pull::None),
+    ));
+    typelevel::TIM4::unpend();
+    // SAFETY: the handler only shares state through a critical section
+    unsafe { typelevel::TIM4::enable() };
+}
+
+fn check_window(window_ms: u32) -> CaptureResult {
+    match window_ms {
+        1..=CAPTURE_MAX_WINDOW_MS => Ok(()),
+        _ => Err(CaptureError::Window),
+    }
+}
+
+/// The totals now, and the timer clock they count in
+fn totals() -> (Totals, u32) {
+    STATE.lock(|state| {
+        let state = state.borrow();
+        (state.edges.totals, state.timer_hz)
+    })
+}
+
+/// Averages over what changed between `before` and `after`
+fn measurement(request: &CaptureRequest, before: Totals, after: Totals, timer_hz: u32) -> CaptureMeasurement {
+    let periods = after.periods - before.periods;
+    let period_ticks = u128::from(after.period_ticks - before.period_ticks);
+    let high_ticks = u128::from(after.high_ticks - before.high_ticks);
+    let per_period = u128::from(periods).max(1) * u128::from(timer_hz);
+    let ns = |ticks: u128| (ticks * 1_000_000_000 / per_period) as u64;
+    let period_ns = ns(period_ticks);
+    let pulse = (request.mode == CaptureMode::PwmInput).then(|| CapturePulse {
+        high_ns: ns(high_ticks),
+        low_ns: period_ns - ns(high_ticks),
+        duty_ppm: (high_ticks * 1_000_000 / period_ticks.max(1)) as u32,
+    });
+    CaptureMeasurement {
+        input: request.input,
+        mode: request.mode,
+        window_ms: request.window_ms,
+        timer_hz,
+        periods: periods as u32,
+        missed: (after.missed - before.missed) as u32,
+        frequency_millihz: (u128::from(periods) * u128::from(timer_hz) * 1000 / period_ticks.max(1)) as u64,
+        period_ns,
+        pulse,
+    }
+}
+
+/// Measures over one window
+pub async fn measure(request: CaptureRequest) -> CaptureMeasureResult {
+    check_window(request.window_ms)?;
+    let before = STATE.lock(|state| state.borrow_mut().start(request.input, request.mode))?;
+    Timer::after_millis(request.window_ms.into()).await;
+    let (after, timer_hz) = totals();
+    STATE.lock(|state| state.borrow_mut().release());
+    Ok(measurement(&request, before, after, timer_hz))
+}
+
+/// Starts, changes or stops the stream
+pub fn stream(request: CaptureStream) -> CaptureResult {
+    if let Some(request) = request {
+        check_window(request.window_ms)?;
+    }
+    STATE.lock(|state| {
+        let mut state = state.borrow_mut();
+        if let Some(request) = request {
+            let others = state.users - u8::from(state.streaming);
+            if others > 0 && state.running != Some((request.input, request.mode)) {
+                return Err(CaptureError::Busy);
+            }
+        }
+        if state.streaming {
+            state.release();
+        }
+        state.streaming = request.is_some();
+        if let Some(request) = request {
+            state.start(request.input, request.mode)?;
+        }
+        Ok(())
+    })?;
+    STREAM.signal(request);
+    Ok(())
+}
+
+pooled_task! {
+    /// Publishes a measurement every window while the stream is on
+    pub fn stream_task(sender: Sender<AppTx>) => publish_stream, STREAM_POOL[1]
+}
+
+async fn publish_stream(sender: Sender<AppTx>) {
+    let mut seq = TopicSeq::new();
+    let mut request = None;
+    loop {
+        let Some(current) = request else {
+            request = STREAM.wait().await;
+            continue;
+        };
+        let mut ticker = Ticker::every(Duration::from_millis(current.window_ms.into()));
+        let (mut before, _) = totals();
+        request = loop {
+            match select(STREAM.wait(), ticker.next()).await {
+                Either::First(next) => break next,
+                Either::Second(()) => {
+                    let (after, timer_hz) = totals();
+                    let msg = measurement(&current, before, after, timer_hz);
+                    before = after;
+                    let _ = sender.publish::<CaptureTopic>(seq.advance(), &msg).await;
+                }
+            }
+        };
+    }
+}
@@ -0,0 +1,125 @@
+//! The clock tree
+//!
+//! SYSCLK runs at 170 MHz, the most the G431 allows, from the PLL. The PLL
+//! runs from HSI16, or with the `hse` feature from a crystal on HSE. AHB
+//! and both APBs are undivided, so peripherals see the full 170 MHz.
+//!
+//! With the `low-power` feature SYSCLK is HSI16 itself. The core wakes from
+//! STOP on HSI16, and nothing would bring the PLL back.
+
+use embassy_stm32::{
+    peripherals::{DMA1, FDCAN1, I2C2, SPI1, SPI2, TIM1, TIM2, TIM3, TIM4, TIM8, TIM16, TIM17, USART2},
+    rcc::{self, mux, RccPeripheral},
+    Config,
+};
+#[cfg(not(feature = "low-power"))]
+use embassy_stm32::rcc::{AHBPrescaler, APBPrescaler, Pll, PllMul, PllPreDiv, PllRDiv, PllSource, Sysclk};
+use template_icd::{ClockSource, ClockTree, KernelClock, KernelClockName, KernelClocks};
+
+#[cfg(all(feature = "hse", feature = "low-power"))]
+compile_error!("The `low-power` clock tree runs from HSI16, it can't be combined with `hse`");
+
+/// The crystal on HSE, with the `hse` feature
+#[cfg(feature = "hse")]
+pub const HSE_HZ: u32 = 24_000_000;
+
+/// The PLL input, from which VCO and SYSCLK are multiplied and divided
+#[cfg(not(feature = "low-power"))]
+const PLL_IN_HZ: u32 = 4_000_000;
+#[cfg(not(feature = "low-power"))]
+pub const SYSCLK_HZ: u32 = PLL_IN_HZ * 85 / 2;
+#[cfg(feature = "low-power")]
+pub const SYSCLK_HZ: u32 = 16_000_000;
+
+#[cfg(feature = "hse")]
+const PLL_PREDIV: PllPreDiv = match HSE_HZ / PLL_IN_HZ {
+    2 => PllPreDiv::DIV2,
+    4 => PllPreDiv::DIV4,
+    6 => PllPreDiv::DIV6,
+    _ => panic!("Add the divider from HSE_HZ to 4 MHz"),
+};
+
+/// The `embassy_stm32::init` config for the clock tree
+#[cfg(feature = "low-power")]
+pub fn config() -> Config {
+    let mut config = Config::default();
+    // HSI16 is the reset default, and keeps clocking the kernels of any
+    // peripheral that wakes the core
+    config.rcc.mux.fdcansel = mux::Fdcansel::PCLK1;
+    config
+}
+
+/// The `embassy_stm32::init` config for the clock tree
+#[cfg(not(feature = "low-power"))]
+pub fn config() -> Config {
+    let mut config = Config::default();
+    let rcc = &mut config.rcc;
+    #[cfg(not(feature = "hse"))]
+    let (source, prediv) = (PllSource::HSI, PllPreDiv::DIV4);
+    #[cfg(feature = "hse")]
+    let (source, prediv) = {
+        rcc.hse = Some(rcc::Hse {
+            freq: embassy_stm32::time::Hertz(HSE_HZ),
+            mode: rcc::HseMode::Oscillator,
+        });
+        (PllSource::HSE, PLL_PREDIV)
+    };
+    // 4 MHz in, a 340 MHz VCO, 170 MHz out of R
+    rcc.pll = Some(Pll {
+        source,
+        prediv,
+        mul: PllMul::MUL85,
+        divp: None,
+        divq: None,
+        divr: Some(PllRDiv::DIV2),
+    });
+    rcc.sys = Sysclk::PLL1_R;
+    rcc.ahb_pre = AHBPrescaler::DIV1;
+    rcc.apb1_pre = APBPrescaler::DIV1;
+    rcc.apb2_pre = APBPrescaler::DIV1;
+    // Needed above 150 MHz
+    rcc.boost = true;
+    // FDCAN is clocked from HSE out of reset, which only runs with `hse`
+    rcc.mux.fdcansel = mux::Fdcansel::PCLK1;
+    config
+}
+
+fn kernel<T: RccPeripheral>(name: &str) -> KernelClock {
+    KernelClock {
+        name: KernelClockName::try_from(name).unwrap_or_default(),
+        hz: rcc::frequency::<T>().0,
+    }
+}
+
+/// The clocks as the HAL computed them at init
+pub fn tree() -> ClockTree {
+    #[cfg(not(feature = "hse"))]
+    let source = ClockSource::Hsi16;
+    #[cfg(feature = "hse")]
+    let source = ClockSource::Hse { hz: HSE_HZ };
+
+    // Of the buses, only the peripherals on them know their clocks
+    let hclk_hz = rcc::frequency::<DMA1>().0;
+    let kernels = [
+        kernel::<USART2>("USART2"),
+        kernel::<I2C2>("I2C2"),
+        kernel::<SPI1>("SPI1"),
+        kernel::<FDCAN1>("FDCAN1"),
+        kernel::<TIM1>("TIM1"),
+        kernel::<TIM2>("TIM2"),
+        kernel::<TIM3>("TIM3"),
+        kernel::<TIM4>("TIM4"),
+        kernel::<TIM8>("TIM8"),
+        kernel::<TIM16>("TIM16"),
+        kernel::<TIM17>("TIM17"),
+    ];
+    ClockTree {
+        source,
+        // AHB is undivided
+        sysclk_hz: hclk_hz,
+        hclk_hz,
+        pclk1_hz: rcc::frequency::<SPI2>().0,
+        pclk2_hz: rcc::frequency::<SPI1>().0,
+        kernels: KernelClocks::from_iter(kernels),
+    }
+}
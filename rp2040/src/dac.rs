@@ -0,0 +1,370 @@
+//! A bench signal source on DAC1
+//!
+//! Each output holds a level or plays a waveform. One period of the
+//! waveform sits in RAM, and DMA feeds it to the DAC in a circle, a sample
+//! on every update of the output's basic timer, TIM6 for OUT1 and TIM7 for
+//! OUT2.
+//!
+//! Changes don't glitch. Playing again with as many samples rewrites the
+//! period just behind the DMA and takes the new rate at the next timer
+//! update, so the output carries on from the same point. Stopping lets the
+//! period run out and rests on its first sample. Tables for
+//! [`DacShape::Table`] are uploaded through [`DacTableSink`].
+
+use core::{cell::RefCell, f32::consts::TAU, ptr};
+
+use embassy_stm32::{
+    dac::{self, DacCh1, DacCh2, DacDma1, DacDma2, TriggerSel},
+    dma::{AnyChannel, NoDma, Request, Transfer, TransferOptions},
+    pac::{self, timer::vals::Mms},
+    peripherals::{DAC1, DMA2_CH1, DMA2_CH2, PA4, PA5, TIM6, TIM7},
+    timer::low_level,
+    Peripheral,
+};
+use embassy_sync::blocking_mutex::{raw::CriticalSectionRawMutex, Mutex};
+use embassy_time::{Duration, Timer};
+use template_icd::{
+    BulkError, BulkResult, DacError, DacLevel, DacOutput, DacResult, DacShape, DacWave, DAC_MAX_RATE_HZ,
+    DAC_MAX_SAMPLES, DAC_VREF_MV,
+};
+
+use crate::{bulk::BulkSink, Shared};
+
+const MAX_SAMPLES: usize = DAC_MAX_SAMPLES as usize;
+/// [`stop`] sleeps until this close to the end of the period, then watches
+/// the DMA for it
+const STOP_SPIN: Duration = Duration::from_millis(1);
+
+struct Table {
+    samples: [i16; MAX_SAMPLES],
+    /// Zero while an upload is under way
+    len: u16,
+}
+
+/// Uploaded tables, apart from what plays so an upload never disturbs it
+static TABLES: Mutex<CriticalSectionRawMutex, RefCell<[Table; 2]>> = Mutex::new(RefCell::new(
+    [const {
+        Table {
+            samples: [0; MAX_SAMPLES],
+            len: 0,
+        }
+    }; 2],
+));
+
+fn index(output: DacOutput) -> usize {
+    match output {
+        DacOutput::Out1 => 0,
+        DacOutput::Out2 => 1,
+    }
+}
+
+/// One output, its DAC channel, DMA2 channel and timer all go by `index`
+struct Output {
+    index: usize,
+    dma: AnyChannel,
+    request: Request,
+    timer_hz: u32,
+    transfer: Option<Transfer<'static>>,
+    /// What DMA plays, the period rotated by two samples, see [`Output::start`]
+    buf: [u16; MAX_SAMPLES],
+    /// Samples in the period that plays
+    playing: Option<u16>,
+}
+
+impl Output {
+    fn new(index: usize, dma: AnyChannel, request: Request, timer_hz: u32) -> Self {
+        let output = Self {
+            index,
+            dma,
+            request,
+            timer_hz,
+            transfer: None,
+            buf: [0; MAX_SAMPLES],
+            playing: None,
+        };
+        // Every update triggers the DAC, levels get out with one forced by hand
+        output.timer().cr1().modify(|w| w.set_arpe(true));
+        output.timer().cr2().modify(|w| w.set_mms(Mms::UPDATE));
+        output
+    }
+
+    fn timer(&self) -> pac::timer::TimBasic {
+        [pac::TIM6, pac::TIM7][self.index]
+    }
+
+    fn dma_regs(&self) -> pac::bdma::Ch {
+        pac::DMA2.ch(self.index)
+    }
+
+    /// Both prescaler and reload are buffered, the new rate starts with
+    /// the next update
+    fn set_rate(&self, rate_hz: u32) {
+        let ticks = (self.timer_hz / rate_hz).max(2);
+        let psc = (ticks - 1) >> 16;
+        let arr = ticks / (psc + 1) - 1;
+        self.timer().psc().write_value(psc as u16);
+        self.timer().arr().write(|w| w.set_arr(arr as u16));
+    }
+
+    /// Stops the timer, and with it the output where it is
+    fn halt(&mut self) {
+        self.timer().cr1().modify(|w| w.set_cen(false));
+        // Dropping the transfer stops the DMA
+        self.transfer = None;
+        pac::DAC1.cr().modify(|w| w.set_dmaen(self.index, false));
+        self.playing = None;
+    }
+
+    fn level(&mut self, code: u16) {
+        self.halt();
+        pac::DAC1.dhr12r(self.index).write(|w| w.set_dhr(code));
+        self.timer().egr().write(|w| w.set_ug(true));
+    }
+
+    /// Plays `period`, given rotated by two samples
+    ///
+    /// Each update hands the DAC the sample in its holding register and has
+    /// DMA load the next one. The first sample goes out right away with a
+    /// forced update, the second waits in the holding register, and DMA
+    /// goes on with the third.
+    fn start(&mut self, period: &[u16], rate_hz: u32) {
+        self.halt();
+        let len = period.len();
+        self.buf[..len].copy_from_slice(period);
+        let dac = pac::DAC1;
+        let first = period[len - 2];
+        dac.dhr12r(self.index).write(|w| w.set_dhr(first));
+        self.set_rate(rate_hz);
+        self.timer().egr().write(|w| w.set_ug(true));
+        // The DAC takes a few cycles to move the sample out
+        while dac.dor(self.index).read().dor() != first {}
+        dac.dhr12r(self.index).write(|w| w.set_dhr(period[len - 1]));
+
+        let mut options = TransferOptions::default();
+        options.circular = true;
+        options.half_transfer_ir = false;
+        options.complete_transfer_ir = false;
+        // SAFETY: the buffer lives with the outputs in a static, and only
+        // `update` writes it while the transfer runs
+        self.transfer = Some(unsafe {
+            Transfer::new_write_raw(
+                self.dma.clone_unchecked(),
+                self.request,
+                &self.buf[..len] as *const [u16],
+                dac.dhr12r(self.index).as_ptr() as *mut u16,
+                options,
+            )
+        });
+        dac.cr().modify(|w| w.set_dmaen(self.index, true));
+        self.timer().cnt().write(|w| w.set_cnt(0));
+        self.timer().cr1().modify(|w| w.set_cen(true));
+        self.playing = Some(len as u16);
+    }
+
+    /// Swaps in a period of the same length from the sample DMA reads next,
+    /// which the copy outruns by far
+    fn update(&mut self, period: &[u16], rate_hz: u32) {
+        let len = period.len();
+        cortex_m::interrupt::free(|_| {
+            let next = len - usize::from(self.dma_regs().ndtr().read().ndt());
+            for i in (next..len).chain(0..next) {
+                // SAFETY: in bounds, volatile as DMA reads it meanwhile
+                unsafe { ptr::write_volatile(&mut self.buf[i], period[i]) };
+            }
+            self.set_rate(rate_hz);
+        });
+        self.playing = Some(len as u16);
+    }
+
+    /// Time until DMA wraps around, which is when the first sample goes out
+    fn period_left(&self) -> Duration {
+        let timer = self.timer();
+        let tick = u64::from(timer.psc().read()) + 1;
+        let arr = u64::from(timer.arr().read().arr());
+        let samples = u64::from(self.dma_regs().ndtr().read().ndt()).saturating_sub(1);
+        let ticks = (samples * (arr + 1) + arr - u64::from(timer.cnt().read().cnt())) * tick;
+        Duration::from_micros(ticks * 1_000_000 / u64::from(self.timer_hz))
+    }
+
+    /// Stops at the end of the period once it is near, otherwise says how
+    /// long to wait first
+    fn stop_at_wrap(&mut self) -> Option<Duration> {
+        self.playing?;
+        let left = self.period_left();
+        if left > STOP_SPIN {
+            return Some(left - STOP_SPIN);
+        }
+        let mut last = self.dma_regs().ndtr().read().ndt();
+        loop {
+            // The count reloads on the update that puts out the first sample
+            let wrapped = cortex_m::interrupt::free(|_| {
+                let ndt = self.dma_regs().ndtr().read().ndt();
+                let wrapped = ndt > last;
+                if wrapped {
+                    self.timer().cr1().modify(|w| w.set_cen(false));
+                }
+                last = ndt;
+                wrapped
+            });
+            if wrapped {
+                break;
+            }
+        }
+        self.halt();
+        None
+    }
+}
+
+/// Both outputs of DAC1
+pub struct DacOutputs {
+    outputs: [Output; 2],
+    _channels: (DacCh1<'static, DAC1>, DacCh2<'static, DAC1>),
+    _timers: (low_level::Timer<'static, TIM6>, low_level::Timer<'static, TIM7>),
+}
+
+impl DacOutputs {
+    pub fn new(dac: DAC1, dma1: DMA2_CH1, dma2: DMA2_CH2, out1: PA4, out2: PA5, tim6: TIM6, tim7: TIM7) -> Self {
+        let (mut ch1, mut ch2) = dac::Dac::new(dac, NoDma, NoDma, out1, out2).split();
+        ch1.set_trigger(TriggerSel::Tim6);
+        ch1.set_triggering(true);
+        ch1.enable();
+        ch2.set_trigger(TriggerSel::Tim7);
+        ch2.set_triggering(true);
+        ch2.enable();
+        let tim6 = low_level::Timer::new(tim6);
+        let tim7 = low_level::Timer::new(tim7);
+        let request1 = DacDma1::<DAC1>::request(&dma1);
+        let request2 = DacDma2::<DAC1>::request(&dma2);
+        Self {
+            outputs: [
+                Output::new(0, dma1.into(), request1, tim6.get_clock_frequency().0),
+                Output::new(1, dma2.into(), request2, tim7.get_clock_frequency().0),
+            ],
+            _channels: (ch1, ch2),
+            _timers: (tim6, tim7),
+        }
+    }
+
+    pub fn set(&mut self, level: DacLevel) -> DacResult {
+        if level.millivolts > DAC_VREF_MV {
+            return Err(DacError::OutOfRange);
+        }
+        self.outputs[index(level.output)].level(code(level.millivolts.into()));
+        Ok(())
+    }
+
+    pub fn play(&mut self, wave: &DacWave) -> DacResult {
+        if !(2..=DAC_MAX_SAMPLES).contains(&wave.samples) {
+            return Err(DacError::Samples);
+        }
+        if !(1..=DAC_MAX_RATE_HZ).contains(&wave.sample_rate_hz) {
+            return Err(DacError::SampleRate);
+        }
+        let peak_mv = u32::from(wave.offset_mv) + u32::from(wave.amplitude_mv);
+        if wave.amplitude_mv > wave.offset_mv || peak_mv > DAC_VREF_MV.into() {
+            return Err(DacError::OutOfRange);
+        }
+        let output = &mut self.outputs[index(wave.output)];
+        let len = wave.samples as usize;
+        let mut period = [0; MAX_SAMPLES];
+        TABLES.lock(|tables| {
+            let table = &tables.borrow()[output.index];
+            if wave.shape == DacShape::Table && table.len != wave.samples {
+                return Err(DacError::NoTable);
+            }
+            for (i, code) in period[..len].iter_mut().enumerate() {
+                *code = sample(wave, (i + 2) % len, &table.samples);
+            }
+            Ok(())
+        })?;
+        match output.playing {
+            Some(samples) if samples == wave.samples => output.update(&period[..len], wave.sample_rate_hz),
+            _ => output.start(&period[..len], wave.sample_rate_hz),
+        }
+        Ok(())
+    }
+
+    /// Both outputs to 0 V, for [`liveness`](crate::liveness)
+    pub fn zero_all(&mut self) {
+        for output in &mut self.outputs {
+            output.level(0);
+        }
+    }
+}
+
+/// Lets the period run out, then holds its first sample
+pub async fn stop(dac: &Shared<DacOutputs>, output: DacOutput) {
+    while let Some(wait) = dac.lock().await.outputs[index(output)].stop_at_wrap() {
+        Timer::after(wait).await;
+    }
+}
+
+fn code(millivolts: f32) -> u16 {
+    let code = millivolts * 4096.0 / f32::from(DAC_VREF_MV) + 0.5;
+    (code as u16).min(4095)
+}
+
+/// The DAC code `i` samples into the period
+fn sample(wave: &DacWave, i: usize, table: &[i16]) -> u16 {
+    let phase = i as f32 / f32::from(wave.samples);
+    let unit = match wave.shape {
+        DacShape::Sine => sine(phase),
+        DacShape::Triangle if phase < 0.25 => 4.0 * phase,
+        DacShape::Triangle if phase < 0.75 => 2.0 - 4.0 * phase,
+        DacShape::Triangle => 4.0 * phase - 4.0,
+        DacShape::Square if phase < 0.5 => 1.0,
+        DacShape::Square => -1.0,
+        DacShape::Table => f32::from(table[i]) / f32::from(i16::MAX),
+    };
+    code(f32::from(wave.offset_mv) + f32::from(wave.amplitude_mv) * unit.clamp(-1.0, 1.0))
+}
+
+/// sin(2π·`turns`) to well within a 12-bit step, without libm
+fn sine(turns: f32) -> f32 {
+    // Folded onto a quarter turn either side of zero, where the series
+    // converges quickly
+    let turns = match turns {
+        t if t < 0.25 => t,
+        t if t < 0.75 => 0.5 - t,
+        t => t - 1.0,
+    };
+    let x = turns * TAU;
+    let x2 = x * x;
+    x * (1.0 - x2 / 6.0 * (1.0 - x2 / 20.0 * (1.0 - x2 / 42.0)))
+}
+
+/// Writes a table into [`TABLES`], where it counts once the upload finishes
+pub struct DacTableSink(usize);
+
+impl DacTableSink {
+    pub fn new(output: DacOutput) -> Self {
+        Self(index(output))
+    }
+
+    /// The size of the upload
+    pub fn open(output: DacOutput, samples: u16) -> Result<u32, BulkError> {
+        if !(2..=DAC_MAX_SAMPLES).contains(&samples) {
+            return Err(BulkError::OutOfRange);
+        }
+        TABLES.lock(|tables| tables.borrow_mut()[index(output)].len = 0);
+        Ok(u32::from(samples) * 2)
+    }
+
+    pub fn finish(output: DacOutput, samples: u16) {
+        TABLES.lock(|tables| tables.borrow_mut()[index(output)].len = samples);
+    }
+}
+
+impl BulkSink for DacTableSink {
+    fn write(&mut self, offset: u32, data: &[u8]) -> BulkResult {
+        TABLES.lock(|tables| {
+            let samples = &mut tables.borrow_mut()[self.0].samples;
+            for (at, byte) in (offset as usize..).zip(data) {
+                let mut bytes = samples[at / 2].to_le_bytes();
+                bytes[at % 2] = *byte;
+                samples[at / 2] = i16::from_le_bytes(bytes);
+            }
+        });
+        Ok(())
+    }
+}
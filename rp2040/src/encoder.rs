@@ -0,0 +1,154 @@
+//! Quadrature encoder counting with TIM2
+//!
+//! TIM2 counts every edge of both encoder signals, up or down by their
+//! order, in its 32-bit counter. The position adds up the change since the
+//! counter was last looked at, so it never wraps as long as that happens
+//! before 2^31 counts go by. [`encoder_task`] looks at least every
+//! [`IDLE_PERIOD`], and works out the velocity as it goes.
+
+use core::cell::RefCell;
+
+use embassy_futures::select::{select, Either};
+use embassy_stm32::{
+    pac::{self, timer::vals::FilterValue},
+    peripherals::{PA0, PA1, TIM2},
+    timer::qei::{Qei, QeiPin},
+};
+use embassy_sync::{
+    blocking_mutex::{raw::CriticalSectionRawMutex, Mutex},
+    signal::Signal,
+};
+use embassy_time::{Duration, Instant, Ticker};
+use postcard_rpc::server::Sender;
+use static_cell::StaticCell;
+use template_icd::{
+    EncoderConfig, EncoderError, EncoderPublish, EncoderReading, EncoderResult, EncoderTopic, ENCODER_MIN_PUBLISH_MS,
+};
+
+use crate::{
+    app::AppTx,
+    session::TopicSeq,
+    tasks::pooled_task,
+    wallclock,
+};
+
+/// How often the counter is looked at while not publishing
+const IDLE_PERIOD: Duration = Duration::from_secs(1);
+
+static STATE: Mutex<CriticalSectionRawMutex, RefCell<State>> = Mutex::new(RefCell::new(State {
+    count: 0,
+    position: 0,
+    window: (0, Instant::from_ticks(0)),
+    velocity_cps: 0,
+}));
+static PUBLISH: Signal<CriticalSectionRawMutex, EncoderPublish> = Signal::new();
+
+struct State {
+    /// The counter when last looked at
+    count: u32,
+    position: i64,
+    /// Position and time the velocity is worked out from next
+    window: (i64, Instant),
+    velocity_cps: i32,
+}
+
+impl State {
+    fn update(&mut self) -> Instant {
+        let count = pac::TIM2.cnt().read();
+        self.position += i64::from(count.wrapping_sub(self.count) as i32);
+        self.count = count;
+        Instant::now()
+    }
+
+    fn reading(&self, at: Instant) -> EncoderReading {
+        EncoderReading {
+            position: self.position,
+            velocity_cps: self.velocity_cps,
+            timestamp: wallclock::timestamp(at),
+        }
+    }
+}
+
+/// Starts counting right away, from zero
+pub fn init(tim: TIM2, a: PA0, b: PA1) {
+    static QEI: StaticCell<Qei<'static, TIM2>> = StaticCell::new();
+    QEI.init(Qei::new(tim, QeiPin::new_ch1(a), QeiPin::new_ch2(b)));
+    // The driver sets a 16-bit reload, TIM2 counts on to 32 bits
+    pac::TIM2.arr().write_value(u32::MAX);
+}
+
+pub fn configure(config: EncoderConfig) -> EncoderResult {
+    if config.filter > 15 {
+        return Err(EncoderError::Filter);
+    }
+    let timer = pac::TIM2;
+    timer.ccmr_input(0).modify(|w| {
+        w.set_icf(0, FilterValue::from_bits(config.filter));
+        w.set_icf(1, FilterValue::from_bits(config.filter));
+    });
+    // Inverting A swaps which signal leads
+    timer.ccer().modify(|w| w.set_ccp(0, config.reversed));
+    Ok(())
+}
+
+pub fn read() -> EncoderReading {
+    STATE.lock(|state| {
+        let mut state = state.borrow_mut();
+        let at = state.update();
+        state.reading(at)
+    })
+}
+
+/// The velocity carries on, over the jump
+pub fn zero() {
+    STATE.lock(|state| {
+        let mut state = state.borrow_mut();
+        state.update();
+        state.window.0 -= state.position;
+        state.position = 0;
+    });
+}
+
+pub fn publish(period_ms: EncoderPublish) -> EncoderResult {
+    if period_ms != 0 && period_ms < ENCODER_MIN_PUBLISH_MS {
+        return Err(EncoderError::PublishPeriod);
+    }
+    PUBLISH.signal(period_ms);
+    Ok(())
+}
+
+pooled_task! {
+    /// Keeps the position up with the counter, and publishes it on request
+    pub fn encoder_task(sender: Sender<AppTx>) => track, ENCODER_POOL[1]
+}
+
+async fn track(sender: Sender<AppTx>) {
+    let mut seq = TopicSeq::new();
+    let mut period_ms = 0;
+    loop {
+        let period = match period_ms {
+            0 => IDLE_PERIOD,
+            ms => Duration::from_millis(ms.into()),
+        };
+        let mut ticker = Ticker::every(period);
+        period_ms = loop {
+            match select(PUBLISH.wait(), ticker.next()).await {
+                Either::First(next) => break next,
+                Either::Second(()) => {}
+            }
+            let reading = STATE.lock(|state| {
+                let mut state = state.borrow_mut();
+                let at = state.update();
+                let (from, since) = state.window;
+                let micros = (at - since).as_micros().max(1) as i64;
+                let cps = (state.position - from) * 1_000_000 / micros;
+                state.velocity_cps = cps.clamp(i32::MIN.into(), i32::MAX.into()) as i32;
+                state.window = (state.position, at);
+                state.reading(at)
+            });
+            if period_ms != 0 {
+                let _ = sender.publish::<EncoderTopic>(seq.advance(), &reading).await;
+            }
+        };
+    }
+}
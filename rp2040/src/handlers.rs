@@ -1,37 +1,408 @@
 use core::sync::atomic::{compiler_fence, Ordering};
 
 use embassy_time::{Instant, Timer};
-use postcard_rpc::{header::VarHeader, server::Sender};
-use template_icd::{LedState, SleepEndpoint, SleepMillis, SleptMillis};
+use postcard_rpc::{
+    header::{VarHeader, VarSeq},
+    server::Sender,
+};
+use template_icd::{
+    BulkAck, BulkChunk, BulkError, BulkFinish, BulkId, BulkOpenResult, BulkResult, BulkStatusResult, BulkTarget,
+    Connected, ConnectTopic, FirmwareStateResult, HostHello, HostHelloEndpoint, LedBrightness, LedState, PowerStats, ResetEndpoint,
+    SleepEndpoint, SleepMillis, SleptMillis,
+};
+#[cfg(full)]
+use template_icd::{
+    CancellableSleepEndpoint, CaptureMeasureEndpoint, CaptureRequest, CaptureResult, CaptureStream, CanConfig,
+    CanErrorsResult, ClockTree, ConfigEntry, ConfigGetResult, ConfigKey, ConfigListResult, ConfigResult, DacLevel, DacOutput, DacResult, DacStopEndpoint, DacWave, DeviceTime, EncoderConfig,
+    EncoderPublish, EncoderReading, EncoderResult, ExecutorStats, CanFilter, CanFrame, CanResult, I2cConfig, I2cRead,
+    I2cReadResult, I2cRegisterRead, I2cRegisterWrite, I2cResult, I2cScanResult, I2cWrite, I2cWriteRead, JobId,
+    JobRequest, JobResult, JobStartEndpoint, JobStatusResult, LogicCaptureEndpoint, LogicRequest, MemoryRead,
+    MemoryReadResult, MemoryRegions, MemoryResult, MemoryWrite, PatternPlay, PatternResult, PatternStatus,
+    PwmChannel, PwmConfig, PwmDuty, PwmResult, RamStats, SpiConfig, SpiConfigResult, SpiCsConfig, SpiResult,
+    SpiTransaction, SpiTransactionResult, TimeSet, TimeSetResult, UartConfig, UartData, UartResult,
+};
 
-use crate::app::{AppTx, Context, TaskContext};
+use crate::{
+    app::{AppTx, Context, TaskContext},
+    bulk::FlashSource,
+    power, session,
+    tasks::pooled_task,
+};
+#[cfg(full)]
+use crate::{
+    capture,
+    cancel::{self, cancellable},
+    clocks,
+    dac::{self, DacTableSink},
+    encoder,
+    jobs::{self, Job, JOB_SLOTS},
+    logic::{self, LogicSource},
+    memory::MemorySource,
+    pattern::{self, PatternSink},
+    ram,
+    tasks,
+    wallclock,
+};
 
 /// This is an example of a BLOCKING handler.
 pub fn unique_id(context: &mut Context, _header: VarHeader, _arg: ()) -> u64 {
+    // Hearing from the host is what confirms a freshly updated image
+    context.update.confirm();
     context.unique_id
 }
 
-/// Also a BLOCKING handler
-pub fn set_led(context: &mut Context, _header: VarHeader, arg: LedState) {
-    match arg {
-        LedState::Off => context.led.set_low(),
-        LedState::On => context.led.set_high(),
+/// An ASYNC handler, as spawned handlers may hold the LED
+pub async fn set_led(context: &mut Context, _header: VarHeader, arg: LedState) {
+    context.led.lock().await.set(arg);
+}
+
+pub async fn get_led(context: &mut Context, _header: VarHeader, _arg: ()) -> LedState {
+    context.led.lock().await.get()
+}
+
+pub async fn set_led_brightness(context: &mut Context, _header: VarHeader, arg: LedBrightness) {
+    context.led.lock().await.set_brightness(arg);
+}
+
+#[cfg(full)]
+pub async fn configure_pwm(context: &mut Context, _header: VarHeader, arg: PwmConfig) -> PwmResult {
+    context.pwm.lock().await.configure(arg)
+}
+
+#[cfg(full)]
+pub async fn set_pwm_duty(context: &mut Context, _header: VarHeader, arg: PwmDuty) -> PwmResult {
+    context.pwm.lock().await.set_duty(arg.channel, arg.duty)
+}
+
+#[cfg(full)]
+pub async fn disable_pwm(context: &mut Context, _header: VarHeader, arg: PwmChannel) -> PwmResult {
+    context.pwm.lock().await.disable(arg)
+}
+
+#[cfg(full)]
+pub async fn configure_i2c(context: &mut Context, _header: VarHeader, arg: I2cConfig) -> I2cResult {
+    context.i2c.lock().await.configure(arg)
+}
+
+#[cfg(full)]
+/// This is an ASYNC handler, the server waits for the transfer without
+/// blocking other tasks
+pub async fn i2c_write(context: &mut Context, _header: VarHeader, arg: I2cWrite) -> I2cResult {
+    context.i2c.lock().await.write(arg.address, &arg.data).await
+}
+
+#[cfg(full)]
+pub async fn i2c_read(context: &mut Context, _header: VarHeader, arg: I2cRead) -> I2cReadResult {
+    context.i2c.lock().await.read(arg.address, arg.len).await
+}
+
+#[cfg(full)]
+pub async fn i2c_write_read(context: &mut Context, _header: VarHeader, arg: I2cWriteRead) -> I2cReadResult {
+    context.i2c.lock().await.write_read(arg.address, &arg.data, arg.read_len).await
+}
+
+#[cfg(full)]
+pub async fn i2c_read_register(context: &mut Context, _header: VarHeader, arg: I2cRegisterRead) -> I2cReadResult {
+    context.i2c.lock().await.read_register(arg.address, arg.register, arg.len).await
+}
+
+#[cfg(full)]
+pub async fn i2c_write_register(context: &mut Context, _header: VarHeader, arg: I2cRegisterWrite) -> I2cResult {
+    context.i2c.lock().await.write_register(arg.address, arg.register, &arg.data).await
+}
+
+#[cfg(full)]
+pub async fn i2c_scan(context: &mut Context, _header: VarHeader, _arg: ()) -> I2cScanResult {
+    context.i2c.lock().await.scan().await
+}
+
+#[cfg(full)]
+pub async fn configure_spi(context: &mut Context, _header: VarHeader, arg: SpiConfig) -> SpiConfigResult {
+    context.spi.lock().await.configure(arg)
+}
+
+#[cfg(full)]
+pub async fn declare_spi_cs(context: &mut Context, _header: VarHeader, arg: SpiCsConfig) -> SpiResult {
+    context.spi.lock().await.declare_cs(arg)
+}
+
+#[cfg(full)]
+pub async fn spi_transaction(context: &mut Context, _header: VarHeader, arg: SpiTransaction) -> SpiTransactionResult {
+    context.spi.lock().await.transaction(&arg).await
+}
+
+#[cfg(full)]
+pub async fn configure_uart(context: &mut Context, _header: VarHeader, arg: UartConfig) -> UartResult {
+    context.uart.configure(arg).await
+}
+
+#[cfg(full)]
+/// This is an async TOPIC handler, topics have no reply so errors only get logged
+pub async fn uart_tx(context: &mut Context, _header: VarHeader, arg: UartData, sender: &Sender<AppTx>) {
+    if let Err(e) = context.uart.send(&arg).await {
+        let _ = sender.log_fmt(format_args!("UART TX error: {e:?}")).await;
     }
 }
 
-pub fn get_led(context: &mut Context, _header: VarHeader, _arg: ()) -> LedState {
-    match context.led.is_set_low() {
-        true => LedState::Off,
-        false => LedState::On,
+#[cfg(full)]
+pub async fn configure_can(context: &mut Context, _header: VarHeader, arg: CanConfig) -> CanResult {
+    context.can.configure(arg).await
+}
+
+#[cfg(full)]
+pub async fn set_can_filter(context: &mut Context, _header: VarHeader, arg: CanFilter) -> CanResult {
+    context.can.set_filter(arg).await
+}
+
+#[cfg(full)]
+pub async fn get_can_errors(context: &mut Context, _header: VarHeader, _arg: ()) -> CanErrorsResult {
+    context.can.errors().await
+}
+
+#[cfg(full)]
+pub fn cancel_request(_context: &mut Context, _header: VarHeader, arg: u32, _sender: &Sender<AppTx>) {
+    cancel::cancel(arg);
+}
+
+#[cfg(full)]
+pub async fn can_tx(context: &mut Context, _header: VarHeader, arg: CanFrame, sender: &Sender<AppTx>) {
+    if let Err(e) = context.can.send(&arg).await {
+        let _ = sender.log_fmt(format_args!("CAN TX error: {e:?}")).await;
+    }
+}
+
+#[cfg(full)]
+pub async fn get_config(context: &mut Context, _header: VarHeader, arg: ConfigKey) -> ConfigGetResult {
+    context.store.get(&arg).await
+}
+
+#[cfg(full)]
+pub async fn set_config(context: &mut Context, _header: VarHeader, arg: ConfigEntry) -> ConfigResult {
+    context.store.set(arg.key, &arg.value).await
+}
+
+#[cfg(full)]
+pub async fn delete_config(context: &mut Context, _header: VarHeader, arg: ConfigKey) -> ConfigResult {
+    context.store.delete(arg).await
+}
+
+#[cfg(full)]
+pub async fn list_config(context: &mut Context, _header: VarHeader, _arg: ()) -> ConfigListResult {
+    context.store.list().await
+}
+
+/// Settings already applied stay in effect until the next reset
+#[cfg(full)]
+pub async fn factory_reset(context: &mut Context, _header: VarHeader, _arg: ()) -> ConfigResult {
+    context.store.factory_reset().await
+}
+
+pub fn get_firmware_state(context: &mut Context, _header: VarHeader, _arg: ()) -> FirmwareStateResult {
+    context.update.state()
+}
+
+/// Each target is checked and prepared here, then read or written by the
+/// bulk handlers below
+pub fn bulk_open(context: &mut Context, _header: VarHeader, arg: BulkTarget) -> BulkOpenResult {
+    let size = match arg {
+        BulkTarget::Flash { offset, size } => FlashSource::check_range(offset, size)?,
+        BulkTarget::Firmware(start) => {
+            context.update.start(start).map_err(BulkError::Update)?;
+            start.size
+        }
+        #[cfg(full)]
+        BulkTarget::Memory { address, size } => MemorySource::check_range(address, size)?,
+        #[cfg(full)]
+        BulkTarget::DacTable { output, samples } => DacTableSink::open(output, samples)?,
+        #[cfg(full)]
+        BulkTarget::Logic => LogicSource::open()?,
+        #[cfg(full)]
+        BulkTarget::Pattern { words } => PatternSink::open(words)?,
+        #[cfg(not(full))]
+        BulkTarget::Memory { .. } | BulkTarget::DacTable { .. } | BulkTarget::Logic | BulkTarget::Pattern { .. } => {
+            return Err(BulkError::OutOfRange)
+        }
+    };
+    Ok(context.bulk.open(arg, size))
+}
+
+pub async fn bulk_upload(context: &mut Context, _header: VarHeader, arg: BulkChunk, sender: &Sender<AppTx>) {
+    match context.bulk.target(arg.id) {
+        Some(BulkTarget::Firmware(_)) => context.bulk.receive(&arg, &mut context.update, sender).await,
+        #[cfg(full)]
+        Some(BulkTarget::DacTable { output, .. }) => {
+            context.bulk.receive(&arg, &mut DacTableSink::new(output), sender).await;
+        }
+        #[cfg(full)]
+        Some(BulkTarget::Pattern { .. }) => context.bulk.receive(&arg, &mut PatternSink, sender).await,
+        _ => {}
+    }
+}
+
+pub async fn bulk_download_ack(context: &mut Context, _header: VarHeader, arg: BulkAck, sender: &Sender<AppTx>) {
+    match context.bulk.target(arg.id) {
+        Some(BulkTarget::Flash { offset, .. }) => {
+            let mut source = FlashSource::new(context.flash, offset);
+            context.bulk.send(&arg, &mut source, sender).await;
+        }
+        #[cfg(full)]
+        Some(BulkTarget::Memory { address, .. }) => {
+            context.bulk.send(&arg, &mut MemorySource::new(address), sender).await;
+        }
+        #[cfg(full)]
+        Some(BulkTarget::Logic) => context.bulk.send(&arg, &mut LogicSource, sender).await,
+        _ => {}
+    }
+}
+
+pub fn bulk_status(context: &mut Context, _header: VarHeader, arg: BulkId) -> BulkStatusResult {
+    context.bulk.status(arg)
+}
+
+pub fn bulk_finish(context: &mut Context, _header: VarHeader, arg: BulkFinish) -> BulkResult {
+    match context.bulk.finish(&arg)? {
+        BulkTarget::Flash { .. } | BulkTarget::Memory { .. } | BulkTarget::Logic => Ok(()),
+        BulkTarget::Firmware(_) => context.update.finish().map_err(BulkError::Update),
+        #[cfg(full)]
+        BulkTarget::DacTable { output, samples } => {
+            DacTableSink::finish(output, samples);
+            Ok(())
+        }
+        #[cfg(full)]
+        BulkTarget::Pattern { words } => {
+            PatternSink::finish(words);
+            Ok(())
+        }
+        // Never opened in the basic firmware
+        #[cfg(not(full))]
+        BulkTarget::DacTable { .. } | BulkTarget::Pattern { .. } => Ok(()),
     }
 }
 
-/// This is a SPAWN handler
-///
-/// The pool size of three means we can have up to three of these requests "in flight"
-/// at the same time. We will return an error if a fourth is requested at the same time
-#[embassy_executor::task(pool_size = 3)]
-pub async fn sleep_handler(_context: TaskContext, header: VarHeader, arg: SleepMillis, sender: Sender<AppTx>) {
+pub fn bulk_abort(context: &mut Context, _header: VarHeader, arg: BulkId) -> BulkResult {
+    context.bulk.abort(arg)
+}
+
+#[cfg(full)]
+pub fn memory_regions(context: &mut Context, _header: VarHeader, _arg: ()) -> MemoryRegions {
+    context.memory.regions()
+}
+
+#[cfg(full)]
+pub fn memory_read(context: &mut Context, _header: VarHeader, arg: MemoryRead) -> MemoryReadResult {
+    context.memory.read(arg)
+}
+
+#[cfg(full)]
+pub fn memory_unlock(context: &mut Context, _header: VarHeader, arg: u32) -> MemoryResult {
+    context.memory.unlock(arg)
+}
+
+#[cfg(full)]
+pub fn memory_write(context: &mut Context, _header: VarHeader, arg: MemoryWrite) -> MemoryResult {
+    context.memory.write(&arg)
+}
+
+#[cfg(full)]
+pub async fn dac_set(context: &mut Context, _header: VarHeader, arg: DacLevel) -> DacResult {
+    context.dac.lock().await.set(arg)
+}
+
+#[cfg(full)]
+pub async fn dac_play(context: &mut Context, _header: VarHeader, arg: DacWave) -> DacResult {
+    context.dac.lock().await.play(&arg)
+}
+
+#[cfg(full)]
+pub fn capture_stream(_context: &mut Context, _header: VarHeader, arg: CaptureStream) -> CaptureResult {
+    capture::stream(arg)
+}
+
+#[cfg(full)]
+pub fn encoder_configure(_context: &mut Context, _header: VarHeader, arg: EncoderConfig) -> EncoderResult {
+    encoder::configure(arg)
+}
+
+#[cfg(full)]
+pub fn encoder_read(_context: &mut Context, _header: VarHeader, _arg: ()) -> EncoderReading {
+    encoder::read()
+}
+
+#[cfg(full)]
+pub fn encoder_zero(_context: &mut Context, _header: VarHeader, _arg: ()) {
+    encoder::zero()
+}
+
+#[cfg(full)]
+pub fn encoder_publish(_context: &mut Context, _header: VarHeader, arg: EncoderPublish) -> EncoderResult {
+    encoder::publish(arg)
+}
+
+#[cfg(full)]
+pub fn pattern_play(_context: &mut Context, _header: VarHeader, arg: PatternPlay) -> PatternResult {
+    pattern::play(arg)
+}
+
+#[cfg(full)]
+pub fn pattern_stop(_context: &mut Context, _header: VarHeader, _arg: ()) {
+    pattern::stop()
+}
+
+#[cfg(full)]
+pub fn pattern_status(_context: &mut Context, _header: VarHeader, _arg: ()) -> PatternStatus {
+    pattern::status()
+}
+
+#[cfg(full)]
+pub fn executor_stats(_context: &mut Context, _header: VarHeader, _arg: ()) -> ExecutorStats {
+    tasks::stats()
+}
+
+#[cfg(full)]
+pub fn job_status(_context: &mut Context, _header: VarHeader, arg: JobId) -> JobStatusResult {
+    jobs::status(arg)
+}
+
+#[cfg(full)]
+pub fn job_cancel(_context: &mut Context, _header: VarHeader, arg: JobId) -> JobResult {
+    jobs::cancel(arg)
+}
+
+#[cfg(full)]
+pub fn ram_stats(_context: &mut Context, _header: VarHeader, _arg: ()) -> RamStats {
+    ram::stats()
+}
+
+#[cfg(full)]
+pub fn clock_tree(_context: &mut Context, _header: VarHeader, _arg: ()) -> ClockTree {
+    clocks::tree()
+}
+
+pub fn power_stats(_context: &mut Context, _header: VarHeader, _arg: ()) -> PowerStats {
+    power::stats()
+}
+
+#[cfg(full)]
+pub fn time_now(_context: &mut Context, _header: VarHeader, _arg: ()) -> DeviceTime {
+    wallclock::now()
+}
+
+#[cfg(full)]
+pub fn time_set(_context: &mut Context, _header: VarHeader, time: TimeSet) -> TimeSetResult {
+    wallclock::set(time)
+}
+
+pooled_task! {
+    /// This is a SPAWN handler
+    ///
+    /// The pool size of three means we can have up to three of these requests "in flight"
+    /// at the same time. We will return an error if a fourth is requested at the same time,
+    /// which [`tasks`] counts and reports to the host
+    pub fn sleep_handler(context: TaskContext, header: VarHeader, arg: SleepMillis, sender: Sender<AppTx>)
+        => sleep, SLEEP_POOL[3]
+}
+
+async fn sleep(_context: TaskContext, header: VarHeader, arg: SleepMillis, sender: Sender<AppTx>) {
     // We can send string logs, using the sender
     let _ = sender.log_str("Starting sleep...").await;
     let start = Instant::now();
@@ -40,3 +411,103 @@ pub async fn sleep_handler(_context: TaskContext, header: VarHeader, arg: SleepM
     // Async handlers have to manually reply, as embassy doesn't support returning by value
     let _ = sender.reply::<SleepEndpoint>(header.seq_no, &SleptMillis { millis: start.elapsed().as_millis() as u16 }).await;
 }
+
+#[cfg(full)]
+pooled_task! {
+    /// As [`sleep_handler`], but the host can cancel it, which frees the
+    /// slot early
+    pub fn cancellable_sleep_handler(context: TaskContext, header: VarHeader, arg: SleepMillis, sender: Sender<AppTx>)
+        => cancellable_sleep, CANCELLABLE_SLEEP_POOL[3]
+}
+
+#[cfg(full)]
+async fn cancellable_sleep(_context: TaskContext, header: VarHeader, arg: SleepMillis, sender: Sender<AppTx>) {
+    let start = Instant::now();
+    let res = cancellable(header.seq_no, Timer::after_millis(arg.millis.into())).await;
+    let res = res.map(|()| SleptMillis { millis: start.elapsed().as_millis() as u16 });
+    let _ = sender.reply::<CancellableSleepEndpoint>(header.seq_no, &res).await;
+}
+
+#[cfg(full)]
+pooled_task! {
+    /// A SPAWN handler that stays running with the job after answering
+    pub fn job_start(context: TaskContext, header: VarHeader, arg: JobRequest, sender: Sender<AppTx>)
+        => start_job, JOB_POOL[JOB_SLOTS]
+}
+
+#[cfg(full)]
+async fn start_job(context: TaskContext, header: VarHeader, arg: JobRequest, sender: Sender<AppTx>) {
+    let job = Job::new(sender.clone());
+    let _ = sender.reply::<JobStartEndpoint>(header.seq_no, &job.id()).await;
+    job.run(arg, context.flash).await;
+}
+
+pooled_task! {
+    /// Also a SPAWN handler, so the reply can go out before the reset
+    pub fn reset_handler(context: TaskContext, header: VarHeader, arg: (), sender: Sender<AppTx>)
+        => reset, RESET_POOL[1]
+}
+
+async fn reset(_context: TaskContext, header: VarHeader, _arg: (), sender: Sender<AppTx>) {
+    let _ = sender.reply::<ResetEndpoint>(header.seq_no, &()).await;
+    // Give the host a moment to read the reply out of the RTT buffer
+    Timer::after_millis(100).await;
+    cortex_m::peripheral::SCB::sys_reset();
+}
+
+pooled_task! {
+    /// A SPAWN handler, for the sender to announce the session on
+    pub fn host_hello(context: TaskContext, header: VarHeader, arg: HostHello, sender: Sender<AppTx>)
+        => hello, HELLO_POOL[1]
+}
+
+async fn hello(context: TaskContext, header: VarHeader, arg: HostHello, sender: Sender<AppTx>) {
+    if session::hello(arg.session) {
+        let msg = Connected {
+            unique_id: context.unique_id,
+            session: arg.session,
+        };
+        // Sequence numbers have just started over
+        let _ = sender.publish::<ConnectTopic>(VarSeq::Seq4(0), &msg).await;
+    }
+    let _ = sender.reply::<HostHelloEndpoint>(header.seq_no, &()).await;
+}
+
+#[cfg(full)]
+pooled_task! {
+    /// A SPAWN handler, as stopping waits for the end of the period
+    pub fn dac_stop(context: TaskContext, header: VarHeader, arg: DacOutput, sender: Sender<AppTx>)
+        => stop_dac, DAC_STOP_POOL[2]
+}
+
+#[cfg(full)]
+async fn stop_dac(context: TaskContext, header: VarHeader, arg: DacOutput, sender: Sender<AppTx>) {
+    dac::stop(context.dac, arg).await;
+    let _ = sender.reply::<DacStopEndpoint>(header.seq_no, &()).await;
+}
+
+#[cfg(full)]
+pooled_task! {
+    /// A SPAWN handler, answering once the window has passed
+    pub fn capture_measure(context: TaskContext, header: VarHeader, arg: CaptureRequest, sender: Sender<AppTx>)
+        => measure, CAPTURE_POOL[2]
+}
+
+#[cfg(full)]
+async fn measure(_context: TaskContext, header: VarHeader, arg: CaptureRequest, sender: Sender<AppTx>) {
+    let res = capture::measure(arg).await;
+    let _ = sender.reply::<CaptureMeasureEndpoint>(header.seq_no, &res).await;
+}
+
+#[cfg(full)]
+pooled_task! {
+    /// A SPAWN handler, answering once the capture is done
+    pub fn logic_capture(context: TaskContext, header: VarHeader, arg: LogicRequest, sender: Sender<AppTx>)
+        => capture_logic, LOGIC_POOL[2]
+}
+
+#[cfg(full)]
+async fn capture_logic(_context: TaskContext, header: VarHeader, arg: LogicRequest, sender: Sender<AppTx>) {
+    let res = logic::capture(arg).await;
+    let _ = sender.reply::<LogicCaptureEndpoint>(header.seq_no, &res).await;
+}
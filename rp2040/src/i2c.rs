@@ -0,0 +1,129 @@
+//! I2C master bridge on I2C2
+
+use embassy_embedded_hal::SetConfig;
+use embassy_stm32::{
+    i2c::{self, I2c},
+    mode::Async,
+    time::Hertz,
+};
+use embassy_time::{with_timeout, Duration, TimeoutError};
+use template_icd::{
+    I2cAddresses, I2cConfig, I2cData, I2cError, I2cReadResult, I2cRegister, I2cResult, I2cScanResult,
+    I2C_MAX_PAYLOAD,
+};
+
+/// Bus speed used until the host configures one
+pub const DEFAULT_FREQUENCY_HZ: u32 = 100_000;
+/// Operation timeout used until the host configures one
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_millis(100);
+
+/// Each address probed by a scan gets this long to answer
+const SCAN_TIMEOUT: Duration = Duration::from_millis(5);
+
+pub struct I2cBridge {
+    bus: I2c<'static, Async>,
+    timeout: Duration,
+}
+
+impl I2cBridge {
+    pub fn new(bus: I2c<'static, Async>) -> Self {
+        Self {
+            bus,
+            timeout: DEFAULT_TIMEOUT,
+        }
+    }
+
+    pub fn configure(&mut self, config: I2cConfig) -> I2cResult {
+        if !(10_000..=1_000_000).contains(&config.frequency_hz) {
+            return Err(I2cError::InvalidFrequency);
+        }
+        self.bus
+            .set_config(&Hertz(config.frequency_hz))
+            .map_err(|_| I2cError::InvalidFrequency)?;
+        self.timeout = Duration::from_millis(config.timeout_ms.max(1).into());
+        Ok(())
+    }
+
+    pub async fn write(&mut self, address: u8, data: &[u8]) -> I2cResult {
+        check_address(address)?;
+        finish(with_timeout(self.timeout, self.bus.write(address, data)).await)
+    }
+
+    pub async fn read(&mut self, address: u8, len: u16) -> I2cReadResult {
+        check_address(address)?;
+        let mut buf = read_buf(len)?;
+        finish(with_timeout(self.timeout, self.bus.read(address, &mut buf)).await)?;
+        Ok(buf)
+    }
+
+    pub async fn write_read(&mut self, address: u8, data: &[u8], len: u16) -> I2cReadResult {
+        check_address(address)?;
+        let mut buf = read_buf(len)?;
+        finish(with_timeout(self.timeout, self.bus.write_read(address, data, &mut buf)).await)?;
+        Ok(buf)
+    }
+
+    pub async fn read_register(&mut self, address: u8, register: I2cRegister, len: u16) -> I2cReadResult {
+        let (reg, reg_len) = register_bytes(register);
+        self.write_read(address, &reg[..reg_len], len).await
+    }
+
+    pub async fn write_register(&mut self, address: u8, register: I2cRegister, data: &[u8]) -> I2cResult {
+        let (reg, reg_len) = register_bytes(register);
+        check_address(address)?;
+        // The register address and data have to go out in a single write
+        // transfer, without a repeated start in between
+        finish(with_timeout(self.timeout, self.bus.write_vectored(address, &[&reg[..reg_len], data])).await)
+    }
+
+    /// Probe every non-reserved 7-bit address with a one byte read
+    pub async fn scan(&mut self) -> I2cScanResult {
+        let mut found = I2cAddresses::new();
+        let mut byte = [0u8; 1];
+        for address in 0x08..=0x77 {
+            match finish(with_timeout(SCAN_TIMEOUT, self.bus.read(address, &mut byte)).await) {
+                Ok(()) => {
+                    let _ = found.push(address);
+                }
+                Err(I2cError::Nack) => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(found)
+    }
+}
+
+fn check_address(address: u8) -> I2cResult {
+    if address > 0x7F {
+        return Err(I2cError::InvalidAddress);
+    }
+    Ok(())
+}
+
+fn read_buf(len: u16) -> Result<I2cData, I2cError> {
+    let len = len as usize;
+    if len == 0 || len > I2C_MAX_PAYLOAD {
+        return Err(I2cError::InvalidLength);
+    }
+    let mut buf = I2cData::new();
+    let _ = buf.resize(len, 0);
+    Ok(buf)
+}
+
+fn register_bytes(register: I2cRegister) -> ([u8; 2], usize) {
+    match register {
+        I2cRegister::Byte(r) => ([r, 0], 1),
+        I2cRegister::Word(r) => (r.to_be_bytes(), 2),
+    }
+}
+
+fn finish(res: Result<Result<(), i2c::Error>, TimeoutError>) -> I2cResult {
+    match res {
+        Ok(Ok(())) => Ok(()),
+        Ok(Err(i2c::Error::Nack)) => Err(I2cError::Nack),
+        Ok(Err(i2c::Error::Arbitration)) => Err(I2cError::ArbitrationLost),
+        Ok(Err(i2c::Error::Timeout)) | Err(TimeoutError) => Err(I2cError::Timeout),
+        Ok(Err(i2c::Error::ZeroLengthTransfer)) => Err(I2cError::InvalidLength),
+        Ok(Err(_)) => Err(I2cError::Bus),
+    }
+}
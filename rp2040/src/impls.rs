@@ -1,7 +1,7 @@
 use core::{fmt::Arguments, ops::DerefMut};
 use cobs::{decode_in_place, encode};
 use embassy_sync::{blocking_mutex::raw::RawMutex, mutex::Mutex};
-use embassy_time::Timer;
+use embassy_time::{Instant, Timer};
 use postcard_rpc::{
     header::{VarHeader, VarKeyKind},
     server::{
@@ -11,6 +11,8 @@ use postcard_rpc::{
 use rtt_target::{DownChannel, UpChannel};
 use serde::Serialize;
 
+use crate::{liveness, power, session};
+
 pub enum RttRxError {}
 
 impl AsWireRxErrorKind for RttRxError {
@@ -54,6 +56,7 @@ impl WireRx for RttRx {
 
     async fn receive<'a>(&mut self, outbuf: &'a mut [u8]) -> Result<&'a mut [u8], Self::Error> {
         let Self { channel, buf, used } = self;
+        let mut idle_since = Instant::now();
         'frame: loop {
             let window = &mut buf[*used..];
             if window.is_empty() {
@@ -61,9 +64,15 @@ impl WireRx for RttRx {
             }
             let read_ct = channel.read(window);
             if read_ct == 0 {
-                Timer::after_millis(1).await;
+                // A host that went away mid-frame would have the next one
+                // decoded with its leftovers in front
+                if *used > 0 && idle_since.elapsed() > session::RX_STALE && !buf[..*used].contains(&0) {
+                    *used = 0;
+                }
+                Timer::after(power::rx_poll_interval(idle_since.elapsed())).await;
                 continue 'frame;
             }
+            idle_since = Instant::now();
             // | used before | rx'd now        | later       |
             // | used before | used now | data | later       |
             //               |----------^ - pos
@@ -89,6 +98,7 @@ impl WireRx for RttRx {
                 let done = if let Ok(b) = decode_in_place(&mut buf[..to_decode]) {
                     // TODO bounds check
                     outbuf[..b].copy_from_slice(&buf[..b]);
+                    liveness::host_seen();
                     Some(b)
                 } else {
                     // bad frame, do we report this? or just move on?
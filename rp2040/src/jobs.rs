@@ -0,0 +1,205 @@
+//! Operations that take seconds, run as jobs
+//!
+//! Starting a job spawns [`job_start`](crate::handlers::job_start), which
+//! answers with the new [`JobId`] right away and then runs the job. Every
+//! change of its status goes out on [`JobTopic`], and the last
+//! [`HISTORY`] jobs can be asked about with their ID.
+
+use core::cell::RefCell;
+
+use embassy_futures::{
+    select::{select, Either},
+    yield_now,
+};
+use embassy_stm32::flash::BANK1_REGION;
+use embassy_sync::{
+    blocking_mutex::{raw::CriticalSectionRawMutex, Mutex},
+    signal::Signal,
+};
+use embassy_time::{block_for, Duration, Instant, Ticker};
+use postcard_rpc::server::Sender;
+use template_icd::{
+    JobError, JobId, JobOutput, JobRequest, JobResult, JobStatus, JobStatusResult, JobTopic, JobUpdate,
+};
+
+use crate::{app::AppTx, bulk::CRC, session::TopicSeq, SharedFlash};
+
+/// Jobs that can run at once
+pub const JOB_SLOTS: usize = 2;
+/// Jobs remembered, running or not
+const HISTORY: usize = 8;
+
+struct Entry {
+    id: JobId,
+    status: JobStatus,
+}
+
+struct Table {
+    next_id: JobId,
+    entries: [Option<Entry>; HISTORY],
+}
+
+static TABLE: Mutex<CriticalSectionRawMutex, RefCell<Table>> = Mutex::new(RefCell::new(Table {
+    next_id: 0,
+    entries: [const { None }; HISTORY],
+}));
+static CANCEL: [Signal<CriticalSectionRawMutex, ()>; HISTORY] = [const { Signal::new() }; HISTORY];
+
+pub fn status(id: JobId) -> JobStatusResult {
+    TABLE.lock(|table| {
+        let table = table.borrow();
+        let entry = table.entries.iter().flatten().find(|e| e.id == id);
+        entry.map(|e| e.status).ok_or(JobError::UnknownJob)
+    })
+}
+
+pub fn cancel(id: JobId) -> JobResult {
+    TABLE.lock(|table| {
+        let table = table.borrow();
+        let slot = table
+            .entries
+            .iter()
+            .position(|e| e.as_ref().is_some_and(|e| e.id == id))
+            .ok_or(JobError::UnknownJob)?;
+        match table.entries[slot].as_ref().map(|e| e.status) {
+            Some(JobStatus::Running { .. }) => {
+                CANCEL[slot].signal(());
+                Ok(())
+            }
+            _ => Err(JobError::NotRunning),
+        }
+    })
+}
+
+/// A running job, reporting on itself
+pub struct Job {
+    id: JobId,
+    slot: usize,
+    sender: Sender<AppTx>,
+    seq: TopicSeq,
+}
+
+impl Job {
+    /// Takes the entry of the oldest job that has ended. There is always
+    /// one, as no more than [`JOB_SLOTS`] run at once.
+    pub fn new(sender: Sender<AppTx>) -> Self {
+        let (id, slot) = TABLE.lock(|table| {
+            let mut table = table.borrow_mut();
+            let id = table.next_id;
+            table.next_id = table.next_id.wrapping_add(1);
+            // Empty entries first, then the one furthest back
+            let slot = table
+                .entries
+                .iter()
+                .enumerate()
+                .filter(|(_, e)| !matches!(e, Some(Entry { status: JobStatus::Running { .. }, .. })))
+                .max_by_key(|(_, e)| e.as_ref().map_or(JobId::MAX, |e| id.wrapping_sub(e.id)))
+                .map(|(slot, _)| slot)
+                .unwrap_or(0);
+            table.entries[slot] = Some(Entry {
+                id,
+                status: JobStatus::Running { done: 0, total: 0 },
+            });
+            CANCEL[slot].reset();
+            (id, slot)
+        });
+        Self {
+            id,
+            slot,
+            sender,
+            seq: TopicSeq::new(),
+        }
+    }
+
+    pub fn id(&self) -> JobId {
+        self.id
+    }
+
+    async fn set_status(&mut self, status: JobStatus) {
+        TABLE.lock(|table| {
+            if let Some(entry) = table.borrow_mut().entries[self.slot].as_mut() {
+                entry.status = status;
+            }
+        });
+        let update = JobUpdate { id: self.id, status };
+        let _ = self.sender.publish::<JobTopic>(self.seq.advance(), &update).await;
+    }
+
+    pub async fn progress(&mut self, done: u32, total: u32) {
+        self.set_status(JobStatus::Running { done, total }).await;
+    }
+
+    /// Run the job to its end, or until it is cancelled
+    pub async fn run(mut self, request: JobRequest, flash: &'static SharedFlash) {
+        let cancelled = CANCEL[self.slot].wait();
+        let res = match request {
+            JobRequest::Sleep { millis } => select(sleep(&mut self, millis), cancelled).await,
+            JobRequest::FlashCrc { offset, size } => select(flash_crc(&mut self, flash, offset, size), cancelled).await,
+            JobRequest::Load { millis } => select(load(&mut self, millis), cancelled).await,
+        };
+        let status = match res {
+            Either::First(Ok(output)) => JobStatus::Finished(output),
+            Either::First(Err(e)) => JobStatus::Failed(e),
+            Either::Second(()) => JobStatus::Cancelled,
+        };
+        self.set_status(status).await;
+    }
+}
+
+async fn sleep(job: &mut Job, millis: u32) -> Result<JobOutput, JobError> {
+    let start = Instant::now();
+    let mut ticker = Ticker::every(Duration::from_millis(100));
+    loop {
+        let elapsed = start.elapsed().as_millis().min(millis.into()) as u32;
+        job.progress(elapsed, millis).await;
+        if elapsed >= millis {
+            return Ok(JobOutput::Slept { millis: elapsed });
+        }
+        ticker.next().await;
+    }
+}
+
+async fn flash_crc(job: &mut Job, flash: &'static SharedFlash, offset: u32, size: u32) -> Result<JobOutput, JobError> {
+    /// Read between yields, the flash stays locked meanwhile
+    const CHUNK: u32 = 256;
+    /// Progress goes out this often
+    const REPORT: u32 = 4096;
+
+    match offset.checked_add(size) {
+        Some(end) if end <= BANK1_REGION.size => {}
+        _ => return Err(JobError::OutOfRange),
+    }
+    let mut digest = CRC.digest();
+    let mut buf = [0u8; CHUNK as usize];
+    let mut done = 0;
+    while done < size {
+        let len = (size - done).min(CHUNK);
+        let buf = &mut buf[..len as usize];
+        flash
+            .lock(|flash| flash.borrow_mut().blocking_read(offset + done, buf))
+            .map_err(|_| JobError::Flash)?;
+        digest.update(buf);
+        done += len;
+        if done % REPORT == 0 {
+            job.progress(done, size).await;
+        }
+        yield_now().await;
+    }
+    Ok(JobOutput::FlashCrc(digest.finalize()))
+}
+
+/// Busy for `millis`, giving the executor back only between chunks
+async fn load(job: &mut Job, millis: u32) -> Result<JobOutput, JobError> {
+    /// Longest stretch without a yield, what other thread mode tasks wait for
+    const CHUNK: u32 = 50;
+
+    let mut done = 0;
+    while done < millis {
+        let len = (millis - done).min(CHUNK);
+        block_for(Duration::from_millis(len.into()));
+        done += len;
+        job.progress(done, millis).await;
+        yield_now().await;
+    }
+    Ok(JobOutput::Busy { millis })
+}
@@ -0,0 +1,83 @@
+//! The user LED, on PC0
+//!
+//! TIM1 CH1 drives it, so it can be dimmed as well as switched. With the
+//! `low-power` feature it is a plain output instead, as the timer would
+//! keep the core out of STOP, and any brightness but zero is fully on.
+
+#[cfg(feature = "low-power")]
+use embassy_stm32::gpio::Output;
+#[cfg(not(feature = "low-power"))]
+use embassy_stm32::{peripherals::TIM1, timer::simple_pwm::SimplePwm};
+#[cfg(not(feature = "low-power"))]
+use template_icd::DUTY_MAX;
+use template_icd::{LedBrightness, LedState};
+
+/// The LED is dimmed at a fixed frequency, well above visible flicker
+pub const LED_PWM_HZ: u32 = 1_000;
+
+pub struct Led {
+    #[cfg(not(feature = "low-power"))]
+    pwm: SimplePwm<'static, TIM1>,
+    #[cfg(feature = "low-power")]
+    pin: Output<'static>,
+}
+
+#[cfg(not(feature = "low-power"))]
+impl Led {
+    pub fn new(mut pwm: SimplePwm<'static, TIM1>) -> Self {
+        let mut ch = pwm.ch1();
+        ch.set_duty_cycle_fully_off();
+        ch.enable();
+        Self { pwm }
+    }
+
+    pub fn set(&mut self, state: LedState) {
+        let mut ch = self.pwm.ch1();
+        match state {
+            LedState::Off => ch.set_duty_cycle_fully_off(),
+            LedState::On => ch.set_duty_cycle_fully_on(),
+        }
+    }
+
+    /// Dims the LED, anything above [`DUTY_MAX`] is fully on
+    pub fn set_brightness(&mut self, duty: LedBrightness) {
+        self.pwm.ch1().set_duty_cycle_fraction(duty.min(DUTY_MAX), DUTY_MAX);
+    }
+
+    /// On at any brightness
+    pub fn get(&mut self) -> LedState {
+        match self.pwm.ch1().current_duty_cycle() {
+            0 => LedState::Off,
+            _ => LedState::On,
+        }
+    }
+}
+
+#[cfg(feature = "low-power")]
+impl Led {
+    pub fn new(pin: Output<'static>) -> Self {
+        Self { pin }
+    }
+
+    pub fn set(&mut self, state: LedState) {
+        match state {
+            LedState::Off => self.pin.set_low(),
+            LedState::On => self.pin.set_high(),
+        }
+    }
+
+    /// Can't dim without the timer, anything but zero is fully on
+    pub fn set_brightness(&mut self, duty: LedBrightness) {
+        self.set(match duty {
+            0 => LedState::Off,
+            _ => LedState::On,
+        });
+    }
+
+    pub fn get(&mut self) -> LedState {
+        match self.pin.is_set_high() {
+            true => LedState::On,
+            false => LedState::Off,
+        }
+    }
+}
@@ -0,0 +1,87 @@
+//! Driving outputs to safe states when the host goes silent
+//!
+//! Every frame from the host counts as a sign of life, the host CLI pings
+//! while it runs. Once [`HOST_TIMEOUT_MS`](template_icd::config_keys::HOST_TIMEOUT_MS)
+//! pass without one, [`monitor_task`] drives the registered [`SafeOutput`]s
+//! to their safe states and publishes [`HostLostTopic`]. The next frame ends
+//! that, the outputs stay as they were left until the host sets them again.
+
+use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, signal::Signal};
+use embassy_time::{with_timeout, Duration};
+use postcard_rpc::server::Sender;
+use template_icd::{HostLost, HostLostTopic, LedState};
+
+use crate::{
+    app::AppTx,
+    led::Led,
+    session::{self, TopicSeq},
+    tasks::pooled_task,
+    Shared,
+};
+#[cfg(full)]
+use crate::{dac::DacOutputs, pattern, pwm::PwmOutputs};
+
+/// Unless the config store says otherwise
+pub const DEFAULT_HOST_TIMEOUT_MS: u32 = 5_000;
+
+static SEEN: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+
+/// Called for every frame that comes in
+pub fn host_seen() {
+    SEEN.signal(());
+}
+
+/// An output, and the state it is driven to once the host is lost
+pub enum SafeOutput {
+    Led(&'static Shared<Led>, LedState),
+    /// Every channel disabled
+    #[cfg(full)]
+    Pwm(&'static Shared<PwmOutputs>),
+    /// Both outputs at 0 V
+    #[cfg(full)]
+    Dac(&'static Shared<DacOutputs>),
+    /// The pattern generator stopped, its pins back as they were before it
+    /// played, whether it was still playing or holding the last word
+    #[cfg(full)]
+    Pattern,
+}
+
+impl SafeOutput {
+    async fn make_safe(&self) {
+        match self {
+            Self::Led(led, state) => led.lock().await.set(*state),
+            #[cfg(full)]
+            Self::Pwm(pwm) => pwm.lock().await.disable_all(),
+            #[cfg(full)]
+            Self::Dac(dac) => dac.lock().await.zero_all(),
+            #[cfg(full)]
+            Self::Pattern => pattern::stop(),
+        }
+    }
+}
+
+pooled_task! {
+    /// Does nothing with a timeout of zero
+    pub fn monitor_task(timeout_ms: u32, outputs: &'static [SafeOutput], sender: Sender<AppTx>) => monitor, MONITOR_POOL[1]
+}
+
+async fn monitor(timeout_ms: u32, outputs: &'static [SafeOutput], sender: Sender<AppTx>) {
+    if timeout_ms == 0 {
+        return;
+    }
+    let timeout = Duration::from_millis(timeout_ms.into());
+    let mut seq = TopicSeq::new();
+    loop {
+        // Nothing to lose before the host has been seen, at boot or since
+        // the last time it went silent
+        SEEN.wait().await;
+        while with_timeout(timeout, SEEN.wait()).await.is_ok() {}
+
+        for output in outputs {
+            output.make_safe().await;
+        }
+        session::end();
+        let msg = HostLost { silent_ms: timeout_ms };
+        let _ = sender.publish::<HostLostTopic>(seq.advance(), &msg).await;
+    }
+}
@@ -0,0 +1,309 @@
+//! Logic analyzer on eight pins of a GPIO port
+//!
+//! TIM16 paces DMA2 channel 3, which copies a byte of the port's input
+//! register into a ring at every update. The capture moves the samples on
+//! from there into a second, bigger ring and looks for the trigger among
+//! them as they go by. Once enough samples have come after it the timer
+//! stops, and the bigger ring is turned so the capture starts at its
+//! beginning, ready for [`BulkTarget::Logic`](template_icd::BulkTarget::Logic).
+//!
+//! The firmware has to keep up with the samples while it captures. When it
+//! falls a ring behind, the capture fails with [`LogicError::Overrun`]
+//! rather than coming back with a gap in it.
+
+use core::{future::poll_fn, task::Poll};
+
+use embassy_stm32::{
+    dma::{ReadableRingBuffer, Request, TransferOptions},
+    pac::{self, gpio::vals::Moder},
+    peripherals::{DMA2_CH3, TIM16},
+    timer::{low_level, UpDma},
+};
+use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, mutex::Mutex};
+use embassy_time::{with_deadline, Duration, Instant};
+use static_cell::ConstStaticCell;
+use template_icd::{
+    BulkError, BulkResult, LogicCapture, LogicEdge, LogicError, LogicPins, LogicPort, LogicRequest, LogicResult,
+    LogicTrigger, LOGIC_MAX_RATE_HZ, LOGIC_MAX_SAMPLES, LOGIC_MAX_TIMEOUT_MS,
+};
+
+use crate::bulk::BulkSource;
+
+/// What DMA fills, each half wakes the capture
+pub const RING_SIZE: usize = 1024;
+pub const MAX_SAMPLES: usize = LOGIC_MAX_SAMPLES as usize;
+
+static RING: ConstStaticCell<[u8; RING_SIZE]> = ConstStaticCell::new([0; RING_SIZE]);
+static SAMPLES: ConstStaticCell<[u8; MAX_SAMPLES]> = ConstStaticCell::new([0; MAX_SAMPLES]);
+
+/// `None` until [`init`], held for the whole of a capture
+static ANALYZER: Mutex<CriticalSectionRawMutex, Option<Analyzer>> = Mutex::new(None);
+
+struct Analyzer {
+    timer: low_level::Timer<'static, TIM16>,
+    dma: DMA2_CH3,
+    request: Request,
+    ring: &'static mut [u8; RING_SIZE],
+    /// A ring while capturing, the last capture from its start after
+    samples: &'static mut [u8; MAX_SAMPLES],
+    last: Option<LogicCapture>,
+}
+
+impl Analyzer {
+    /// Both prescaler and reload are buffered, the update that starts the
+    /// capture loads them. Returns the rate they come to.
+    fn set_rate(&self, rate_hz: u32) -> u32 {
+        let timer_hz = self.timer.get_clock_frequency().0;
+        let ticks = (timer_hz / rate_hz).max(2);
+        let psc = (ticks - 1) >> 16;
+        let arr = ticks / (psc + 1) - 1;
+        let regs = self.timer.regs_core();
+        regs.psc().write_value(psc as u16);
+        regs.arr().write(|w| w.set_arr(arr as u16));
+        timer_hz / ((psc + 1) * (arr + 1))
+    }
+
+    fn halt(&self) {
+        self.timer.regs_core().cr1().modify(|w| w.set_cen(false));
+        self.timer.enable_update_dma(false);
+    }
+
+    /// Captures into `samples`, returning where the trigger was
+    async fn run(&mut self, request: &LogicRequest) -> Result<Option<u32>, LogicError> {
+        let regs = self.timer.regs_core();
+        // Loads the rate, before DMA would take the update as a request
+        regs.egr().write(|w| w.set_ug(true));
+        regs.cnt().write(|w| w.set_cnt(0));
+        let gpio = port(request.pins.port);
+        let idr = gpio.idr().as_ptr().cast::<u8>();
+        // SAFETY: the ring lives in a static, and the transfer stops when
+        // it drops at the end of the capture
+        let mut ring = unsafe {
+            ReadableRingBuffer::new(
+                &mut self.dma,
+                self.request,
+                idr.add(usize::from(request.pins.high_byte)),
+                &mut self.ring[..],
+                TransferOptions::default(),
+            )
+        };
+        ring.start();
+        self.timer.enable_update_dma(true);
+        regs.cr1().modify(|w| w.set_cen(true));
+
+        let deadline = Instant::now() + Duration::from_millis(request.timeout_ms.into());
+        let after = request.samples - request.pretrigger;
+        // Taken so far, and as many as the capture ends at once the
+        // trigger is found
+        let mut count = 0;
+        let mut end = None;
+        let mut trigger = None;
+        let mut prev = None;
+        if request.trigger == LogicTrigger::Immediate {
+            end = Some(request.samples);
+        }
+        let end = loop {
+            let wanted = match end {
+                Some(end) if count >= end => break end,
+                Some(end) => end - count,
+                // The trigger may come with the first of these, and what
+                // follows the end must not reach back to the start
+                None => after + (LOGIC_MAX_SAMPLES - request.samples),
+            };
+            let available = match end {
+                Some(_) => available(&mut ring).await?,
+                None => with_deadline(deadline, available(&mut ring))
+                    .await
+                    .map_err(|_| LogicError::Timeout)??,
+            };
+            let at = count as usize % MAX_SAMPLES;
+            let len = available.min(wanted as usize).min(MAX_SAMPLES - at);
+            let (read, _) = ring
+                .read(&mut self.samples[at..at + len])
+                .map_err(|_| LogicError::Overrun)?;
+            for sample in &mut self.samples[at..at + read] {
+                *sample &= request.pins.channels;
+                let before = *prev.get_or_insert(*sample);
+                prev = Some(*sample);
+                if end.is_none() && count >= request.pretrigger && triggers(request.trigger, before, *sample) {
+                    trigger = Some(count);
+                    end = Some(count + after);
+                }
+                count += 1;
+            }
+        };
+        regs.cr1().modify(|w| w.set_cen(false));
+        drop(ring);
+
+        // Anything past the end went where nothing of the capture was
+        let start = end - request.samples;
+        self.samples.rotate_left(start as usize % MAX_SAMPLES);
+        Ok(trigger.map(|at| at - start))
+    }
+}
+
+/// Takes the timer and DMA channel for good, capture starts on request
+pub fn init(tim: TIM16, dma: DMA2_CH3) {
+    let request = UpDma::<TIM16>::request(&dma);
+    let analyzer = Analyzer {
+        timer: low_level::Timer::new(tim),
+        dma,
+        request,
+        ring: RING.take(),
+        samples: SAMPLES.take(),
+        last: None,
+    };
+    // Nothing else has the lock before the server starts
+    if let Ok(mut slot) = ANALYZER.try_lock() {
+        *slot = Some(analyzer);
+    }
+}
+
+fn port(port: LogicPort) -> pac::gpio::Gpio {
+    match port {
+        LogicPort::A => pac::GPIOA,
+        LogicPort::B => pac::GPIOB,
+        LogicPort::C => pac::GPIOC,
+        LogicPort::D => pac::GPIOD,
+        LogicPort::E => pac::GPIOE,
+        LogicPort::F => pac::GPIOF,
+        LogicPort::G => pac::GPIOG,
+    }
+}
+
+fn pin(pins: &LogicPins, channel: usize) -> usize {
+    channel + if pins.high_byte { 8 } else { 0 }
+}
+
+/// Makes inputs of the channels nothing uses yet, returning which
+fn claim(pins: &LogicPins) -> u8 {
+    let gpio = port(pins.port);
+    let mut claimed = 0;
+    cortex_m::interrupt::free(|_| {
+        let moder = gpio.moder().read();
+        for channel in (0..8).filter(|&c| pins.channels & (1 << c) != 0) {
+            if moder.moder(pin(pins, channel)) == Moder::ANALOG {
+                gpio.moder().modify(|w| w.set_moder(pin(pins, channel), Moder::INPUT));
+                claimed |= 1 << channel;
+            }
+        }
+    });
+    claimed
+}
+
+/// Puts the channels [`claim`] took back to analog
+fn release(pins: &LogicPins, claimed: u8) {
+    let gpio = port(pins.port);
+    cortex_m::interrupt::free(|_| {
+        for channel in (0..8).filter(|&c| claimed & (1 << c) != 0) {
+            gpio.moder().modify(|w| w.set_moder(pin(pins, channel), Moder::ANALOG));
+        }
+    });
+}
+
+fn triggers(trigger: LogicTrigger, before: u8, sample: u8) -> bool {
+    match trigger {
+        LogicTrigger::Immediate => true,
+        LogicTrigger::Edge { channel, edge } => {
+            let bit = 1 << channel;
+            let (was, is) = (before & bit != 0, sample & bit != 0);
+            match edge {
+                LogicEdge::Rising => !was && is,
+                LogicEdge::Falling => was && !is,
+                LogicEdge::Either => was != is,
+            }
+        }
+        LogicTrigger::Pattern { mask, value } => sample & mask == value,
+    }
+}
+
+/// Waits for samples in the ring, woken at every half of it
+async fn available(ring: &mut ReadableRingBuffer<'_, u8>) -> Result<usize, LogicError> {
+    poll_fn(|cx| {
+        ring.set_waker(cx.waker());
+        match ring.len() {
+            Ok(0) => Poll::Pending,
+            Ok(len) => Poll::Ready(Ok(len)),
+            Err(_) => Poll::Ready(Err(LogicError::Overrun)),
+        }
+    })
+    .await
+}
+
+fn check(request: &LogicRequest) -> Result<(), LogicError> {
+    if request.pins.channels == 0 {
+        return Err(LogicError::Pins);
+    }
+    if !(1..=LOGIC_MAX_RATE_HZ).contains(&request.rate_hz) {
+        return Err(LogicError::Rate);
+    }
+    if !(1..=LOGIC_MAX_SAMPLES).contains(&request.samples) {
+        return Err(LogicError::Samples);
+    }
+    if !(1..=LOGIC_MAX_TIMEOUT_MS).contains(&request.timeout_ms) {
+        return Err(LogicError::Timeout);
+    }
+    // Only waiting for the trigger has a deadline, the samples after it
+    // are read for as long as they take
+    let duration_ms = u64::from(request.samples) * 1_000 / u64::from(request.rate_hz);
+    if duration_ms > LOGIC_MAX_TIMEOUT_MS.into() {
+        return Err(LogicError::Rate);
+    }
+    let captured = match request.trigger {
+        LogicTrigger::Immediate => true,
+        LogicTrigger::Edge { channel, .. } => channel < 8 && request.pins.channels & (1 << channel) != 0,
+        LogicTrigger::Pattern { mask, .. } => mask & !request.pins.channels == 0,
+    };
+    // The trigger is a sample of the capture, so something has to follow
+    if !captured || request.pretrigger >= request.samples {
+        return Err(LogicError::Trigger);
+    }
+    Ok(())
+}
+
+/// Captures once, the samples stay until the next capture
+pub async fn capture(request: LogicRequest) -> LogicResult {
+    check(&request)?;
+    let mut analyzer = ANALYZER.try_lock().map_err(|_| LogicError::Busy)?;
+    let analyzer = analyzer.as_mut().ok_or(LogicError::Busy)?;
+    analyzer.last = None;
+    let rate_hz = analyzer.set_rate(request.rate_hz);
+    let claimed = claim(&request.pins);
+    let res = analyzer.run(&request).await;
+    analyzer.halt();
+    release(&request.pins, claimed);
+    let capture = LogicCapture {
+        pins: request.pins,
+        rate_hz,
+        samples: request.samples,
+        trigger: res?,
+    };
+    analyzer.last = Some(capture);
+    Ok(capture)
+}
+
+/// The last capture, for bulk downloads
+pub struct LogicSource;
+
+impl LogicSource {
+    /// The size of the last capture
+    pub fn open() -> Result<u32, BulkError> {
+        let analyzer = ANALYZER.try_lock().map_err(|_| BulkError::Logic(LogicError::Busy))?;
+        match analyzer.as_ref().and_then(|a| a.last) {
+            Some(capture) => Ok(capture.samples),
+            None => Err(BulkError::Logic(LogicError::NoCapture)),
+        }
+    }
+}
+
+impl BulkSource for LogicSource {
+    fn read(&mut self, offset: u32, buf: &mut [u8]) -> BulkResult {
+        let analyzer = ANALYZER.try_lock().map_err(|_| BulkError::Logic(LogicError::Busy))?;
+        let Some(analyzer) = analyzer.as_ref().filter(|a| a.last.is_some()) else {
+            return Err(BulkError::Logic(LogicError::NoCapture));
+        };
+        let start = offset as usize;
+        buf.copy_from_slice(&analyzer.samples[start..start + buf.len()]);
+        Ok(())
+    }
+}
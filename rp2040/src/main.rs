@@ -1,56 +1,348 @@
 #![no_std]
 #![no_main]
 
+use core::cell::RefCell;
+
 use app::AppTx;
-use embassy_executor::Spawner;
-use embassy_sync::{blocking_mutex::raw::ThreadModeRawMutex, mutex::Mutex};
+use embassy_executor::{InterruptExecutor, Spawner};
+use embassy_embedded_hal::flash::partition::BlockingPartition;
+use embassy_sync::{
+    blocking_mutex::{self, raw::CriticalSectionRawMutex},
+    mutex::Mutex,
+};
 use embassy_time::{Duration, Instant, Ticker};
 use impls::{RttRx, RttTx, RttTxInner};
-use postcard_rpc::{header::VarSeq, server::{Dispatch, Sender, Server}};
+use postcard_rpc::server::{Dispatch, Sender, Server};
 use rtt_target::rtt_init;
 use static_cell::{ConstStaticCell, StaticCell};
-use template_icd::{HelloTopic, HelloWorld};
-use embassy_stm32::gpio::{Output, Level, Speed};
+use template_icd::{config_keys, HelloTopic, HelloWorld, LedState};
+use embassy_stm32::{
+    flash::{Bank1Region, Blocking, Flash},
+    interrupt,
+    interrupt::{InterruptExt, Priority},
+};
+#[cfg(not(feature = "low-power"))]
+use embassy_stm32::{
+    gpio::OutputType,
+    time::Hertz,
+    timer::{
+        simple_pwm::{PwmPin, SimplePwm},
+        low_level::CountingMode,
+    },
+};
+#[cfg(any(full, feature = "low-power"))]
+use embassy_stm32::gpio::{Level, Output, Speed};
+#[cfg(full)]
+use embassy_stm32::{
+    bind_interrupts,
+    i2c::I2c,
+    peripherals,
+    spi::Spi,
+    timer::complementary_pwm::{ComplementaryPwm, ComplementaryPwmPin},
+    usart::Uart,
+};
 
 use {panic_reset as _};
 
 pub mod app;
+pub mod bulk;
+#[cfg(full)]
+pub mod cancel;
+#[cfg(full)]
+pub mod can;
+#[cfg(full)]
+pub mod capture;
+pub mod clocks;
+#[cfg(full)]
+pub mod dac;
+#[cfg(full)]
+pub mod encoder;
 pub mod handlers;
+#[cfg(full)]
+pub mod i2c;
 pub mod impls;
+#[cfg(full)]
+pub mod jobs;
+pub mod led;
+pub mod liveness;
+#[cfg(full)]
+pub mod logic;
+#[cfg(full)]
+pub mod memory;
+#[cfg(full)]
+pub mod pattern;
+pub mod power;
+#[cfg(full)]
+pub mod pwm;
+#[cfg(full)]
+pub mod ram;
+pub mod session;
+#[cfg(full)]
+pub mod spi;
+pub mod store;
+pub mod tasks;
+#[cfg(full)]
+pub mod uart;
+pub mod update;
+#[cfg(full)]
+pub mod wallclock;
+
+#[cfg(full)]
+bind_interrupts!(struct Irqs {
+    I2C2_EV => embassy_stm32::i2c::EventInterruptHandler<peripherals::I2C2>;
+    I2C2_ER => embassy_stm32::i2c::ErrorInterruptHandler<peripherals::I2C2>;
+    USART2 => embassy_stm32::usart::InterruptHandler<peripherals::USART2>;
+    FDCAN1_IT0 => embassy_stm32::can::IT0InterruptHandler<peripherals::FDCAN1>;
+    FDCAN1_IT1 => embassy_stm32::can::IT1InterruptHandler<peripherals::FDCAN1>;
+    TIM4 => capture::InterruptHandler;
+});
+
+/// A peripheral shared between the server and spawned handlers, locked
+/// only for as long as one of them uses it
+pub type Shared<T> = Mutex<CriticalSectionRawMutex, T>;
+
+/// The flash, shared between the config store and the update slots
+pub type SharedFlash = blocking_mutex::Mutex<CriticalSectionRawMutex, RefCell<Bank1Region<'static, Blocking>>>;
+pub type FlashPartition = BlockingPartition<'static, CriticalSectionRawMutex, Bank1Region<'static, Blocking>>;
+
+/// Each way, the host reads and writes these directly
+pub const RTT_CHANNEL_SIZE: usize = 1024;
+/// The COBS framing buffers of the RTT transport, the largest frame that fits
+pub const RTT_FRAME_SIZE: usize = 1024;
+
+/// The RPC server runs here, ahead of everything in thread mode. Spawned
+/// handlers still go to the thread mode executor.
+static SERVER_EXECUTOR: InterruptExecutor = InterruptExecutor::new();
+/// Unless the config store says otherwise, see [`config_keys::SERVER_PRIORITY`]
+const DEFAULT_SERVER_PRIORITY: u8 = 6;
+/// Unless the config store says otherwise, see [`config_keys::HEARTBEAT_MS`].
+/// Slower with `low-power`, to leave room for STOP between beats.
+const DEFAULT_HEARTBEAT_MS: u32 = if cfg!(feature = "low-power") { 1_000 } else { 300 };
+
+/// Not otherwise used, its vector runs the server executor
+#[interrupt]
+unsafe fn UART4() {
+    SERVER_EXECUTOR.on_interrupt()
+}
+
+/// By hand rather than with `embassy_executor::main`, which can't take
+/// the low-power executor
+#[cortex_m_rt::entry]
+fn main() -> ! {
+    power::executor().run(|spawner| tasks::must_spawn(&spawner, main_task(spawner)))
+}
+
+tasks::pooled_task! {
+    fn main_task(spawner: Spawner) => init, MAIN_POOL[1]
+}
 
-#[embassy_executor::main]
-async fn main(spawner: Spawner) {
+async fn init(spawner: Spawner) {
+    #[cfg(full)]
+    ram::paint_stack();
     use rtt_target::ChannelMode;
     let channels = rtt_init! {
         up: {
             0: {
-                size: 1024,
+                size: RTT_CHANNEL_SIZE,
                 mode: ChannelMode::BlockIfFull,
                 name: "postcard-rpc uplink",
             }
         }
         down: {
             0: {
-                size: 1024,
+                size: RTT_CHANNEL_SIZE,
                 name: "postcard-rpc downlink",
             }
         }
     };
 
     // SYSTEM INIT
-    let mut p = embassy_stm32::init(Default::default());
-    // Obtain the flash ID
-    let unique_id = 123456789 as u64;
+    let mut p = embassy_stm32::init(clocks::config());
+    #[cfg(feature = "low-power")]
+    power::init(p.RTC);
+
+    // Anything not in the config store keeps its default
+    static FLASH: StaticCell<SharedFlash> = StaticCell::new();
+    let flash = FLASH.init(blocking_mutex::Mutex::new(RefCell::new(
+        Flash::new_blocking(p.FLASH).into_blocking_regions().bank1_region,
+    )));
+    let update = update::FirmwareUpdate::new(flash, &spawner);
+    let mut store = store::ConfigStore::new(flash);
+    let unique_id = store.load(config_keys::UNIQUE_ID).await.unwrap_or(123456789);
+    let heartbeat_ms = store.load(config_keys::HEARTBEAT_MS).await.unwrap_or(DEFAULT_HEARTBEAT_MS);
+    let server_priority: u8 = store.load(config_keys::SERVER_PRIORITY).await.unwrap_or(DEFAULT_SERVER_PRIORITY);
+    let host_timeout_ms = store
+        .load(config_keys::HOST_TIMEOUT_MS)
+        .await
+        .unwrap_or(liveness::DEFAULT_HOST_TIMEOUT_MS);
 
     let pbufs = app::PBUFS.take();
-    let led = Output::new(p.PC0, Level::Low, Speed::Low);
+    #[cfg(not(feature = "low-power"))]
+    let led = led::Led::new(SimplePwm::new(
+        p.TIM1,
+        Some(PwmPin::new_ch1(p.PC0, OutputType::PushPull)),
+        None,
+        None,
+        None,
+        Hertz(led::LED_PWM_HZ),
+        CountingMode::EdgeAlignedUp,
+    ));
+    #[cfg(feature = "low-power")]
+    let led = led::Led::new(Output::new(p.PC0, Level::Low, Speed::Low));
 
-    let context = app::Context { unique_id, led };
+    #[cfg(full)]
+    let (pwm, i2c, spi, uart, uart_rx, dac, generator, can_config) = {
+        // Outputs stay disabled until the host configures them, the frequency
+        // here is only a placeholder
+        let pwm = pwm::PwmOutputs::new(
+            SimplePwm::new(
+                p.TIM3,
+                Some(PwmPin::new_ch1(p.PA6, OutputType::PushPull)),
+                None,
+                Some(PwmPin::new_ch3(p.PB0, OutputType::PushPull)),
+                Some(PwmPin::new_ch4(p.PB1, OutputType::PushPull)),
+                Hertz(1_000),
+                CountingMode::EdgeAlignedUp,
+            ),
+            ComplementaryPwm::new(
+                p.TIM8,
+                Some(PwmPin::new_ch1(p.PA15, OutputType::PushPull)),
+                Some(ComplementaryPwmPin::new_ch1(p.PA7, OutputType::PushPull)),
+                None,
+                None,
+                None,
+                None,
+                None,
+                None,
+                Hertz(1_000),
+                CountingMode::EdgeAlignedUp,
+            ),
+        );
 
-    static BUF_TX_1: ConstStaticCell<[u8; 1024]> = ConstStaticCell::new([0u8; 1024]);
-    static BUF_TX_2: ConstStaticCell<[u8; 1024]> = ConstStaticCell::new([0u8; 1024]);
-    static BUF_RX: ConstStaticCell<[u8; 1024]> = ConstStaticCell::new([0u8; 1024]);
-    static TX_STO: StaticCell<Mutex<ThreadModeRawMutex, RttTxInner>> = StaticCell::new();
+        let mut i2c = i2c::I2cBridge::new(I2c::new(
+            p.I2C2,
+            p.PA9,
+            p.PA8,
+            Irqs,
+            p.DMA1_CH1,
+            p.DMA1_CH2,
+            Hertz(i2c::DEFAULT_FREQUENCY_HZ),
+            Default::default(),
+        ));
+        if let Some(cfg) = store.load(config_keys::I2C).await {
+            let _ = i2c.configure(cfg);
+        }
+
+        let mut spi = spi::SpiBridge::new(
+            Spi::new(
+                p.SPI1,
+                p.PB3,
+                p.PB5,
+                p.PB4,
+                p.DMA1_CH3,
+                p.DMA1_CH4,
+                Default::default(),
+            ),
+            [
+                Output::new(p.PB10, Level::High, Speed::VeryHigh),
+                Output::new(p.PB11, Level::High, Speed::VeryHigh),
+                Output::new(p.PB12, Level::High, Speed::VeryHigh),
+            ],
+        );
+        if let Some(cfg) = store.load(config_keys::SPI).await {
+            let _ = spi.configure(cfg);
+        }
+
+        // Starts at 115200 8N1 unless stored otherwise, the host can change that later
+        static UART_RX_RING: ConstStaticCell<[u8; uart::RX_RING_SIZE]> = ConstStaticCell::new([0u8; uart::RX_RING_SIZE]);
+        let (uart_tx, uart_rx) = Uart::new(
+            p.USART2,
+            p.PA3,
+            p.PA2,
+            Irqs,
+            p.DMA1_CH5,
+            p.DMA1_CH6,
+            Default::default(),
+        )
+        .unwrap()
+        .split();
+        let mut uart_rx = uart_rx.into_ring_buffered(UART_RX_RING.take());
+        if let Some(cfg) = store.load(config_keys::UART).await {
+            // Receiving starts with the first read, so this takes effect then
+            let _ = uart_rx.set_config(&uart::usart_config(cfg));
+        }
+        let uart = uart::UartBridge::new(uart_tx);
+
+        // Both outputs start at 0 V, DMA1 is taken so DMA2 feeds them
+        let dac = dac::DacOutputs::new(p.DAC1, p.DMA2_CH1, p.DMA2_CH2, p.PA4, p.PA5, p.TIM6, p.TIM7);
+
+        // Idle until a measurement starts
+        capture::init(p.TIM4, p.PB6, p.PB7, Irqs);
+        encoder::init(p.TIM2, p.PA0, p.PA1);
+        logic::init(p.TIM16, p.DMA2_CH3);
+        let generator = pattern::init(p.TIM17, p.DMA2_CH4, p.PA10, p.EXTI10);
+
+        let can_config = store.load(config_keys::CAN).await;
+        (pwm, i2c, spi, uart, uart_rx, dac, generator, can_config)
+    };
+
+    static LED: StaticCell<Shared<led::Led>> = StaticCell::new();
+    let led = &*LED.init(Mutex::new(led));
+    #[cfg(full)]
+    let (pwm, i2c, spi, dac) = {
+        static I2C: StaticCell<Shared<i2c::I2cBridge>> = StaticCell::new();
+        static SPI: StaticCell<Shared<spi::SpiBridge>> = StaticCell::new();
+        static PWM: StaticCell<Shared<pwm::PwmOutputs>> = StaticCell::new();
+        static DAC: StaticCell<Shared<dac::DacOutputs>> = StaticCell::new();
+        (
+            &*PWM.init(Mutex::new(pwm)),
+            &*I2C.init(Mutex::new(i2c)),
+            &*SPI.init(Mutex::new(spi)),
+            &*DAC.init(Mutex::new(dac)),
+        )
+    };
+    #[cfg(full)]
+    let safe_outputs = {
+        static SAFE_OUTPUTS: StaticCell<[liveness::SafeOutput; 4]> = StaticCell::new();
+        SAFE_OUTPUTS.init([
+            liveness::SafeOutput::Led(led, LedState::Off),
+            liveness::SafeOutput::Pwm(pwm),
+            liveness::SafeOutput::Dac(dac),
+            liveness::SafeOutput::Pattern,
+        ])
+    };
+    #[cfg(not(full))]
+    let safe_outputs = {
+        static SAFE_OUTPUTS: StaticCell<[liveness::SafeOutput; 1]> = StaticCell::new();
+        SAFE_OUTPUTS.init([liveness::SafeOutput::Led(led, LedState::Off)])
+    };
+    let context = app::Context {
+        unique_id,
+        led,
+        #[cfg(full)]
+        pwm,
+        #[cfg(full)]
+        i2c,
+        #[cfg(full)]
+        spi,
+        #[cfg(full)]
+        dac,
+        #[cfg(full)]
+        uart,
+        #[cfg(full)]
+        can: can::CanBridge::default(),
+        store,
+        update,
+        flash,
+        bulk: bulk::BulkTransfers::default(),
+        #[cfg(full)]
+        memory: memory::Memory::default(),
+    };
+
+    static BUF_TX_1: ConstStaticCell<[u8; RTT_FRAME_SIZE]> = ConstStaticCell::new([0u8; RTT_FRAME_SIZE]);
+    static BUF_TX_2: ConstStaticCell<[u8; RTT_FRAME_SIZE]> = ConstStaticCell::new([0u8; RTT_FRAME_SIZE]);
+    static BUF_RX: ConstStaticCell<[u8; RTT_FRAME_SIZE]> = ConstStaticCell::new([0u8; RTT_FRAME_SIZE]);
+    static TX_STO: StaticCell<Mutex<CriticalSectionRawMutex, RttTxInner>> = StaticCell::new();
 
     let tx_impl = RttTx {
         inner: TX_STO.init(Mutex::new(RttTxInner {
@@ -65,9 +357,9 @@ async fn main(spawner: Spawner) {
         used: 0,
     };
 
-    let dispatcher = app::MyApp::new(context, spawner.into());
+    let dispatcher = app::MyApp::new(context, spawner.make_send().into());
     let vkk = dispatcher.min_key_len();
-    let mut server: app::AppServer = Server::new(
+    let server: app::AppServer = Server::new(
         tx_impl,
         rx_impl,
         pbufs.rx_buf.as_mut_slice(),
@@ -77,25 +369,51 @@ async fn main(spawner: Spawner) {
     let sender = server.sender();
     // We need to spawn the USB task so that USB messages are handled by
     // embassy-usb
-    spawner.must_spawn(logging_task(sender));
+    tasks::must_spawn(&spawner, logging_task(sender.clone(), heartbeat_ms));
+    tasks::must_spawn(&spawner, tasks::rejected_task(sender.clone()));
+    #[cfg(full)]
+    {
+        tasks::must_spawn(&spawner, uart::uart_rx_task(uart_rx, sender.clone()));
+        tasks::must_spawn(&spawner, can::can_task(p.FDCAN1, p.PA11, p.PA12, can_config, sender.clone()));
+        tasks::must_spawn(&spawner, capture::stream_task(sender.clone()));
+        tasks::must_spawn(&spawner, encoder::encoder_task(sender.clone()));
+        tasks::must_spawn(&spawner, pattern::pattern_task(generator));
+    }
+    tasks::must_spawn(&spawner, liveness::monitor_task(host_timeout_ms, safe_outputs, sender));
+
+    // Levels run from 0, the most urgent, to 15. Peripheral interrupts
+    // stay at 0, ahead of the server.
+    interrupt::UART4.set_priority(Priority::from(server_priority.min(15) << 4));
+    let server_spawner = SERVER_EXECUTOR.start(interrupt::UART4);
+    tasks::must_spawn_send(&server_spawner, server_task(server));
+}
 
-    // Begin running!
+tasks::pooled_task! {
+    /// Begin running!
+    fn server_task(server: app::AppServer) => serve, SERVER_POOL[1]
+}
+
+async fn serve(mut server: app::AppServer) {
     loop {
-        // If the host disconnects, we'll return an error here.
-        // If this happens, just wait until the host reconnects
+        // RTT never reports the host going away, hosts coming and going
+        // are told apart by the `session` module instead
         let _ = server.run().await;
     }
 }
 
-/// This task is a "sign of life" logger
-#[embassy_executor::task]
-pub async fn logging_task(sender: Sender<AppTx>) {
-    let mut ticker = Ticker::every(Duration::from_millis(300));
+tasks::pooled_task! {
+    /// This task is a "sign of life" logger
+    pub fn logging_task(sender: Sender<AppTx>, period_ms: u32) => heartbeat, LOGGING_POOL[1]
+}
+
+async fn heartbeat(sender: Sender<AppTx>, period_ms: u32) {
+    let mut ticker = Ticker::every(Duration::from_millis(period_ms.into()));
     let start = Instant::now();
+    let mut seq = session::TopicSeq::new();
     let mut ctr = 0u32;
     loop {
         ticker.next().await;
-        let _ = sender.publish::<HelloTopic>(VarSeq::Seq4(ctr), &((ctr * 10) as u64)).await;
+        let _ = sender.publish::<HelloTopic>(seq.advance(), &((ctr * 10) as u64)).await;
         ctr += 1;
     }
 }
@@ -0,0 +1,209 @@
+//! Raw memory access for diagnostics
+//!
+//! Only the regions in [`REGIONS`] can be reached. Accesses run with bus
+//! faults ignored, so the holes between peripherals inside a region answer
+//! [`MemoryError::BusFault`] instead of bringing the device down.
+
+use core::{arch::asm, ptr};
+
+use cortex_m::peripheral::SCB;
+use template_icd::{
+    BulkError, BulkResult, MemoryAccess, MemoryData, MemoryError, MemoryRead, MemoryReadResult, MemoryRegion,
+    MemoryRegionName, MemoryRegions, MemoryResult, MemoryWrite, MEMORY_MAX_DATA,
+};
+
+use crate::bulk::BulkSource;
+
+struct Region {
+    name: &'static str,
+    start: u32,
+    size: u32,
+    access: MemoryAccess,
+    writable: bool,
+}
+
+impl Region {
+    const fn new(name: &'static str, start: u32, size: u32, access: MemoryAccess, writable: bool) -> Self {
+        Self {
+            name,
+            start,
+            size,
+            access,
+            writable,
+        }
+    }
+}
+
+/// The allowlist, for the STM32G431
+const REGIONS: &[Region] = &[
+    Region::new("flash", 0x0800_0000, 0x2_0000, MemoryAccess::Bytes, false),
+    Region::new("system memory", 0x1FFF_0000, 0x7000, MemoryAccess::Bytes, false),
+    Region::new("otp", 0x1FFF_7000, 0x400, MemoryAccess::Bytes, false),
+    // Package, unique ID and flash size
+    Region::new("device info", 0x1FFF_7500, 0x100, MemoryAccess::Bytes, false),
+    Region::new("option bytes", 0x1FFF_7800, 0x30, MemoryAccess::Words, false),
+    Region::new("ccm sram", 0x1000_0000, 0x2800, MemoryAccess::Bytes, true),
+    // SRAM1 and SRAM2, then CCM SRAM again
+    Region::new("sram", 0x2000_0000, 0x8000, MemoryAccess::Bytes, true),
+    Region::new("apb1", 0x4000_0000, 0xA400, MemoryAccess::Words, true),
+    Region::new("apb2", 0x4001_0000, 0x6400, MemoryAccess::Words, true),
+    Region::new("ahb1", 0x4002_0000, 0x4400, MemoryAccess::Words, true),
+    Region::new("gpio", 0x4800_0000, 0x1C00, MemoryAccess::Words, true),
+    // ADC, DAC and RNG
+    Region::new("ahb2", 0x5000_0000, 0x6_0C00, MemoryAccess::Words, true),
+    Region::new("cortex-m", 0xE000_0000, 0x10_0000, MemoryAccess::Words, true),
+];
+
+#[derive(Default)]
+pub struct Memory {
+    unlocked: bool,
+}
+
+impl Memory {
+    pub fn regions(&self) -> MemoryRegions {
+        REGIONS
+            .iter()
+            .map(|r| MemoryRegion {
+                name: MemoryRegionName::try_from(r.name).unwrap_or_default(),
+                start: r.start,
+                size: r.size,
+                access: r.access,
+                writable: r.writable,
+            })
+            .collect()
+    }
+
+    pub fn read(&self, arg: MemoryRead) -> MemoryReadResult {
+        if arg.len as usize > MEMORY_MAX_DATA {
+            return Err(MemoryError::TooLong);
+        }
+        let mut data = MemoryData::new();
+        // Fits, checked above
+        let _ = data.resize(arg.len as usize, 0);
+        read(arg.address, &mut data)?;
+        Ok(data)
+    }
+
+    /// Writes stay unlocked until another key is sent, or the device resets
+    pub fn unlock(&mut self, key: u32) -> MemoryResult {
+        if !cfg!(feature = "memory-write") {
+            return Err(MemoryError::WritesDisabled);
+        }
+        self.unlocked = key == template_icd::MEMORY_UNLOCK_KEY;
+        Ok(())
+    }
+
+    pub fn write(&mut self, arg: &MemoryWrite) -> MemoryResult {
+        if !cfg!(feature = "memory-write") {
+            return Err(MemoryError::WritesDisabled);
+        }
+        if !self.unlocked {
+            return Err(MemoryError::Locked);
+        }
+        let region = region(arg.address, arg.data.len() as u32)?;
+        if !region.writable {
+            return Err(MemoryError::ReadOnly);
+        }
+        let data = &arg.data;
+        without_bus_faults(|| match region.access {
+            MemoryAccess::Bytes => {
+                for (i, b) in data.iter().enumerate() {
+                    // SAFETY: inside an allowed region, and the host asked for it
+                    unsafe { ptr::write_volatile((arg.address as usize + i) as *mut u8, *b) };
+                }
+            }
+            MemoryAccess::Words => {
+                for (i, word) in data.chunks_exact(4).enumerate() {
+                    let word = u32::from_le_bytes(word.try_into().unwrap());
+                    // SAFETY: as above, and aligned
+                    unsafe { ptr::write_volatile((arg.address as usize + 4 * i) as *mut u32, word) };
+                }
+            }
+        })
+    }
+}
+
+/// Check a range against the allowlist, returning the region it lies in
+fn region(address: u32, len: u32) -> Result<&'static Region, MemoryError> {
+    let region = REGIONS
+        .iter()
+        .find(|r| address >= r.start && address - r.start < r.size)
+        .ok_or(MemoryError::NotAllowed)?;
+    if len > region.size - (address - region.start) {
+        return Err(MemoryError::NotAllowed);
+    }
+    if region.access == MemoryAccess::Words && !(address.is_multiple_of(4) && len.is_multiple_of(4)) {
+        return Err(MemoryError::Misaligned);
+    }
+    Ok(region)
+}
+
+fn read(address: u32, buf: &mut [u8]) -> MemoryResult {
+    let region = region(address, buf.len() as u32)?;
+    without_bus_faults(|| match region.access {
+        MemoryAccess::Bytes => {
+            for (i, b) in buf.iter_mut().enumerate() {
+                // SAFETY: inside an allowed region, reads have no side effects there
+                *b = unsafe { ptr::read_volatile((address as usize + i) as *const u8) };
+            }
+        }
+        MemoryAccess::Words => {
+            for (i, word) in buf.chunks_exact_mut(4).enumerate() {
+                // SAFETY: inside an allowed region and aligned. Some registers
+                // clear flags when read, which is up to the host.
+                let value = unsafe { ptr::read_volatile((address as usize + 4 * i) as *const u32) };
+                word.copy_from_slice(&value.to_le_bytes());
+            }
+        }
+    })
+}
+
+/// Run `f` with precise bus faults ignored rather than taken, and report
+/// whether any happened. Every interrupt is held off meanwhile.
+fn without_bus_faults(f: impl FnOnce()) -> MemoryResult {
+    /// In the configurable fault status register
+    const BUS_FAULT_STATUS: u32 = 0xFF << 8;
+    /// Bus faults are ignored by code running at priority -1 and above
+    const BFHFNMIGN: u32 = 1 << 8;
+
+    // SAFETY: FAULTMASK only masks interrupts, and is cleared again before
+    // returning. Nothing else in the firmware touches CCR or the fault status.
+    let faulted = unsafe {
+        let scb = &*SCB::PTR;
+        scb.cfsr.write(BUS_FAULT_STATUS);
+        scb.ccr.modify(|r| r | BFHFNMIGN);
+        asm!("cpsid f");
+        f();
+        asm!("dsb", "isb", "cpsie f");
+        scb.ccr.modify(|r| r & !BFHFNMIGN);
+        let status = scb.cfsr.read() & BUS_FAULT_STATUS;
+        scb.cfsr.write(status);
+        status != 0
+    };
+    match faulted {
+        true => Err(MemoryError::BusFault),
+        false => Ok(()),
+    }
+}
+
+/// Memory from `base` on, for bulk downloads
+pub struct MemorySource {
+    base: u32,
+}
+
+impl MemorySource {
+    pub fn new(base: u32) -> Self {
+        Self { base }
+    }
+
+    /// The size of the range, if it may be read
+    pub fn check_range(address: u32, size: u32) -> Result<u32, BulkError> {
+        region(address, size).map(|_| size).map_err(BulkError::Memory)
+    }
+}
+
+impl BulkSource for MemorySource {
+    fn read(&mut self, offset: u32, buf: &mut [u8]) -> BulkResult {
+        read(self.base + offset, buf).map_err(BulkError::Memory)
+    }
+}
@@ -0,0 +1,421 @@
+//! Pattern generator on eight pins of a GPIO port
+//!
+//! TIM17 paces DMA2 channel 4, which writes the port's set/reset register
+//! at every update. Continuous patterns that fit the ring loop in place,
+//! longer ones stream through it. Patterns played once or a number of
+//! times stream through it too, followed by a ring of the last word, so
+//! the pins stop exactly on it however late the timer stops. Should the
+//! firmware fall a whole ring behind, playing stops with
+//! [`PatternState::Underrun`].
+//!
+//! Each word goes out as a set/reset pair for the channels played, so the
+//! other pins of the port are never written. Only pins that are analog or
+//! inputs are taken, the others belong to something else.
+
+use core::{cell::RefCell, future::pending};
+
+use embassy_futures::select::{select, Either};
+use embassy_stm32::{
+    dma::{Request, Transfer, TransferOptions, WritableRingBuffer},
+    exti::ExtiInput,
+    gpio::Pull,
+    pac::{
+        self,
+        gpio::vals::{Moder, Ot},
+    },
+    peripherals::{DMA2_CH4, EXTI10, PA10, TIM17},
+    timer::{low_level, UpDma},
+};
+use embassy_sync::{
+    blocking_mutex::{raw::CriticalSectionRawMutex, Mutex},
+    signal::Signal,
+};
+use static_cell::ConstStaticCell;
+use template_icd::{
+    BulkError, BulkResult, LogicEdge, LogicPins, LogicPort, PatternError, PatternPlay, PatternRepeat, PatternResult,
+    PatternState, PatternStatus, PATTERN_MAX_RATE_HZ, PATTERN_MAX_WORDS,
+};
+
+use crate::{
+    bulk::BulkSink,
+    tasks::pooled_task,
+};
+
+pub const MAX_WORDS: usize = PATTERN_MAX_WORDS as usize;
+/// What DMA plays from, the pattern itself when continuous and no longer.
+/// A quarter of the longest pattern, as each word takes four bytes here.
+pub const RING_SIZE: usize = MAX_WORDS / 4;
+/// Words moved into the ring at a time
+const CHUNK: usize = 64;
+/// PA10, kept as the trigger input
+const TRIGGER_PIN: usize = 10;
+
+/// Set/reset words, as DMA writes them
+static RING: ConstStaticCell<[u32; RING_SIZE]> = ConstStaticCell::new([0; RING_SIZE]);
+
+/// The uploaded pattern, kept apart from what plays
+static PATTERN: Mutex<CriticalSectionRawMutex, RefCell<Pattern>> = Mutex::new(RefCell::new(Pattern {
+    words: [0; MAX_WORDS],
+    len: 0,
+}));
+static STATE: Mutex<CriticalSectionRawMutex, RefCell<State>> = Mutex::new(RefCell::new(State {
+    state: PatternState::Idle,
+    rate_hz: 0,
+    timer_hz: 0,
+    claimed: None,
+}));
+/// What to play next, `None` to stop
+static PLAY: Signal<CriticalSectionRawMutex, Option<PatternPlay>> = Signal::new();
+
+struct Pattern {
+    words: [u8; MAX_WORDS],
+    /// Zero while an upload is under way
+    len: u16,
+}
+
+struct State {
+    state: PatternState,
+    rate_hz: u32,
+    timer_hz: u32,
+    /// The pins the generator has made outputs of, which it may take again
+    claimed: Option<LogicPins>,
+}
+
+fn set_state(state: PatternState) {
+    STATE.lock(|s| s.borrow_mut().state = state);
+}
+
+/// Prescaler and reload for the rate, and the rate they come to
+fn prescale(timer_hz: u32, rate_hz: u32) -> (u16, u16, u32) {
+    let ticks = (timer_hz / rate_hz).max(2);
+    let psc = (ticks - 1) >> 16;
+    let arr = ticks / (psc + 1) - 1;
+    (psc as u16, arr as u16, timer_hz / ((psc + 1) * (arr + 1)))
+}
+
+/// The set/reset word that puts `word` on the channels of `pins`
+fn set_reset(word: u8, pins: &LogicPins) -> u32 {
+    let shift = if pins.high_byte { 8 } else { 0 };
+    let set = u32::from(word & pins.channels);
+    let reset = u32::from(!word & pins.channels);
+    (set | reset << 16) << shift
+}
+
+/// Fills `out` with the pattern's words from `from` on, round and round
+fn fill(out: &mut [u32], from: u64, pins: &LogicPins) {
+    PATTERN.lock(|p| {
+        let p = p.borrow();
+        let len = u64::from(p.len);
+        for (i, slot) in (from..).zip(out.iter_mut()) {
+            *slot = set_reset(p.words[(i % len) as usize], pins);
+        }
+    });
+}
+
+/// The pins a pattern drives, and what they were before
+struct Claimed {
+    pins: LogicPins,
+    moder: u32,
+    otyper: u32,
+}
+
+pub struct Generator {
+    timer: low_level::Timer<'static, TIM17>,
+    dma: DMA2_CH4,
+    request: Request,
+    trigger: ExtiInput<'static>,
+    ring: &'static mut [u32; RING_SIZE],
+    claimed: Option<Claimed>,
+}
+
+impl Generator {
+    /// Both prescaler and reload are buffered, the update before playing
+    /// loads them
+    fn set_rate(&self, rate_hz: u32) {
+        let (psc, arr, _) = prescale(self.timer.get_clock_frequency().0, rate_hz);
+        let regs = self.timer.regs_core();
+        regs.psc().write_value(psc);
+        regs.arr().write(|w| w.set_arr(arr));
+        regs.egr().write(|w| w.set_ug(true));
+        regs.cnt().write(|w| w.set_cnt(0));
+    }
+
+    fn halt(&self) {
+        self.timer.regs_core().cr1().modify(|w| w.set_cen(false));
+        self.timer.enable_update_dma(false);
+    }
+
+    /// Makes push-pull outputs of the pins, which [`play`] has checked
+    fn claim(&mut self, pins: LogicPins) {
+        let gpio = port(pins.port);
+        STATE.lock(|state| state.borrow_mut().claimed = Some(pins));
+        cortex_m::interrupt::free(|_| {
+            self.claimed = Some(Claimed {
+                pins,
+                moder: gpio.moder().read().0,
+                otyper: gpio.otyper().read().0,
+            });
+            for pin in channels(&pins) {
+                gpio.otyper().modify(|w| w.set_ot(pin, Ot::PUSH_PULL));
+                gpio.moder().modify(|w| w.set_moder(pin, Moder::OUTPUT));
+            }
+        });
+    }
+
+    /// Puts the pins of the last pattern back as they were
+    fn release(&mut self) {
+        let Some(claimed) = self.claimed.take() else {
+            return;
+        };
+        STATE.lock(|state| state.borrow_mut().claimed = None);
+        let gpio = port(claimed.pins.port);
+        cortex_m::interrupt::free(|_| {
+            for pin in channels(&claimed.pins) {
+                let mask = 3 << (2 * pin);
+                gpio.moder().modify(|w| w.0 = (w.0 & !mask) | (claimed.moder & mask));
+                gpio.otyper().modify(|w| w.0 = (w.0 & !(1 << pin)) | (claimed.otyper & (1 << pin)));
+            }
+        });
+    }
+
+    /// Plays until done, or for good when continuous
+    async fn play(&mut self, play: &PatternPlay) -> PatternState {
+        let pins = play.pins;
+        self.release();
+        self.claim(pins);
+        self.set_rate(play.rate_hz);
+
+        let bsrr = port(pins.port).bsrr().as_ptr().cast::<u32>();
+        let len = PATTERN.lock(|p| usize::from(p.borrow().len));
+        // Uploads wait for playing to stop, this is only for safety
+        if len == 0 {
+            return PatternState::Idle;
+        }
+        let mut last = 0;
+        fill(core::slice::from_mut(&mut last), len as u64 - 1, &pins);
+
+        let total = match play.repeat {
+            PatternRepeat::Once => len as u64,
+            PatternRepeat::Times(times) => len as u64 * u64::from(times),
+            // Never comes to an end
+            PatternRepeat::Continuous if len > RING_SIZE => u64::MAX,
+            PatternRepeat::Continuous => {
+                fill(&mut self.ring[..len], 0, &pins);
+                let mut options = TransferOptions::default();
+                options.circular = true;
+                options.half_transfer_ir = false;
+                options.complete_transfer_ir = false;
+                // SAFETY: the ring lives in a static, and the transfer stops
+                // when it drops along with playing
+                let _transfer =
+                    unsafe { Transfer::new_write(&mut self.dma, self.request, &self.ring[..len], bsrr, options) };
+                start(&self.timer, &mut self.trigger, play.trigger).await;
+                return pending().await;
+            }
+        };
+
+        // The pattern, the number of times over, then only the last word
+        let first = total.min(RING_SIZE as u64) as usize;
+        fill(&mut self.ring[..first], 0, &pins);
+        self.ring[first..].fill(last);
+        let mut next = first as u64;
+        // Once a ring of last words is in, all before them have played
+        let mut lasts = RING_SIZE - first;
+        // SAFETY: as for continuous patterns
+        let mut ring = unsafe {
+            WritableRingBuffer::new(
+                &mut self.dma,
+                self.request,
+                bsrr,
+                &mut self.ring[..],
+                TransferOptions::default(),
+            )
+        };
+        ring.start();
+        start(&self.timer, &mut self.trigger, play.trigger).await;
+        let mut chunk = [0; CHUNK];
+        while lasts < RING_SIZE {
+            let n = if next < total {
+                let n = (total - next).min(CHUNK as u64) as usize;
+                fill(&mut chunk[..n], next, &pins);
+                next += n as u64;
+                n
+            } else {
+                let n = (RING_SIZE - lasts).min(CHUNK);
+                chunk[..n].fill(last);
+                lasts += n;
+                n
+            };
+            if ring.write_exact(&chunk[..n]).await.is_err() {
+                return PatternState::Underrun;
+            }
+        }
+        PatternState::Done
+    }
+}
+
+/// Starts the timer, on the trigger if there is one
+async fn start(timer: &low_level::Timer<'static, TIM17>, trigger: &mut ExtiInput<'static>, edge: Option<LogicEdge>) {
+    if let Some(edge) = edge {
+        match edge {
+            LogicEdge::Rising => trigger.wait_for_rising_edge().await,
+            LogicEdge::Falling => trigger.wait_for_falling_edge().await,
+            LogicEdge::Either => trigger.wait_for_any_edge().await,
+        }
+    }
+    set_state(PatternState::Playing);
+    timer.enable_update_dma(true);
+    timer.regs_core().cr1().modify(|w| w.set_cen(true));
+}
+
+/// Takes the timer, DMA channel and trigger pin for good, for
+/// [`pattern_task`]
+pub fn init(tim: TIM17, dma: DMA2_CH4, trigger: PA10, exti: EXTI10) -> Generator {
+    let request = UpDma::<TIM17>::request(&dma);
+    let timer = low_level::Timer::new(tim);
+    let timer_hz = timer.get_clock_frequency().0;
+    STATE.lock(|state| state.borrow_mut().timer_hz = timer_hz);
+    Generator {
+        timer,
+        dma,
+        request,
+        trigger: ExtiInput::new(trigger, exti, Pull::None),
+        ring: RING.take(),
+        claimed: None,
+    }
+}
+
+fn port(port: LogicPort) -> pac::gpio::Gpio {
+    match port {
+        LogicPort::A => pac::GPIOA,
+        LogicPort::B => pac::GPIOB,
+        LogicPort::C => pac::GPIOC,
+        LogicPort::D => pac::GPIOD,
+        LogicPort::E => pac::GPIOE,
+        LogicPort::F => pac::GPIOF,
+        LogicPort::G => pac::GPIOG,
+    }
+}
+
+/// The pin numbers of the channels
+fn channels(pins: &LogicPins) -> impl Iterator<Item = usize> {
+    let (channels, first) = (pins.channels, if pins.high_byte { 8 } else { 0 });
+    (0..8).filter(move |c| channels & (1 << c) != 0).map(move |c| c + first)
+}
+
+/// Whether the generator may drive `pin`, which has to be analog or an
+/// input unless the generator made an output of it
+fn free(pin: usize, pins: &LogicPins, claimed: Option<LogicPins>) -> bool {
+    let ours = claimed.is_some_and(|c| c.port == pins.port && channels(&c).any(|p| p == pin));
+    let moder = port(pins.port).moder().read().moder(pin);
+    ours || moder == Moder::ANALOG || moder == Moder::INPUT
+}
+
+/// Checks the request, the task takes it from there
+pub fn play(play: PatternPlay) -> PatternResult {
+    let on_trigger = play.pins.port == LogicPort::A && channels(&play.pins).any(|pin| pin == TRIGGER_PIN);
+    let claimed = STATE.lock(|state| state.borrow().claimed);
+    let taken = channels(&play.pins).any(|pin| !free(pin, &play.pins, claimed));
+    if play.pins.channels == 0 || on_trigger || taken {
+        return Err(PatternError::Pins);
+    }
+    if !(1..=PATTERN_MAX_RATE_HZ).contains(&play.rate_hz) {
+        return Err(PatternError::Rate);
+    }
+    if play.repeat == PatternRepeat::Times(0) {
+        return Err(PatternError::Repeat);
+    }
+    // Along with the check for a pattern, so an upload can't start in
+    // between, see [`PatternSink::open`]
+    let rate_hz = STATE.lock(|state| {
+        if PATTERN.lock(|p| p.borrow().len) == 0 {
+            return Err(PatternError::NoPattern);
+        }
+        let mut state = state.borrow_mut();
+        (_, _, state.rate_hz) = prescale(state.timer_hz, play.rate_hz);
+        state.state = match play.trigger {
+            Some(_) => PatternState::Armed,
+            None => PatternState::Playing,
+        };
+        Ok(state.rate_hz)
+    })?;
+    PLAY.signal(Some(play));
+    Ok(rate_hz)
+}
+
+/// Stops right away, putting the pins back as they were
+pub fn stop() {
+    set_state(PatternState::Idle);
+    PLAY.signal(None);
+}
+
+pub fn status() -> PatternStatus {
+    let words = PATTERN.lock(|p| p.borrow().len);
+    STATE.lock(|state| {
+        let state = state.borrow();
+        PatternStatus {
+            state: state.state,
+            rate_hz: state.rate_hz,
+            words,
+        }
+    })
+}
+
+pooled_task! {
+    /// Plays what [`play`] asks for, until the next request
+    pub fn pattern_task(generator: Generator) => play_patterns, PATTERN_POOL[1]
+}
+
+async fn play_patterns(mut generator: Generator) {
+    let mut next = None;
+    loop {
+        let play = match next.take() {
+            Some(play) => play,
+            None => PLAY.wait().await,
+        };
+        let Some(play) = play else {
+            generator.release();
+            continue;
+        };
+        match select(PLAY.wait(), generator.play(&play)).await {
+            Either::First(request) => next = Some(request),
+            Either::Second(state) => set_state(state),
+        }
+        generator.halt();
+    }
+}
+
+/// Writes a pattern into [`PATTERN`], where it counts once the upload
+/// finishes
+pub struct PatternSink;
+
+impl PatternSink {
+    /// The size of the upload
+    pub fn open(words: u16) -> Result<u32, BulkError> {
+        if !(1..=PATTERN_MAX_WORDS).contains(&words) {
+            return Err(BulkError::OutOfRange);
+        }
+        // In one go, as [`play`] checks for a pattern and starts
+        STATE.lock(|state| {
+            if matches!(state.borrow().state, PatternState::Armed | PatternState::Playing) {
+                return Err(BulkError::Pattern(PatternError::Busy));
+            }
+            PATTERN.lock(|p| p.borrow_mut().len = 0);
+            Ok(u32::from(words))
+        })
+    }
+
+    pub fn finish(words: u16) {
+        PATTERN.lock(|p| p.borrow_mut().len = words);
+    }
+}
+
+impl BulkSink for PatternSink {
+    fn write(&mut self, offset: u32, data: &[u8]) -> BulkResult {
+        PATTERN.lock(|p| {
+            let start = offset as usize;
+            p.borrow_mut().words[start..start + data.len()].copy_from_slice(data);
+        });
+        Ok(())
+    }
+}
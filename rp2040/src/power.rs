@@ -0,0 +1,177 @@
+//! Idling, and the time spent in each power mode
+//!
+//! The thread mode executor sleeps with WFE whenever it runs out of work.
+//! With the `low-power` feature it enters Stop1 instead, as long as no
+//! peripheral driver holds its clocks and no timer is due within 250 ms.
+//! That build is the basic firmware, see build.rs, with the LED as the
+//! only peripheral and on a plain output, so nothing holds STOP off. The
+//! RTC keeps time in STOP and its wakeup alarm, set for the next timer, is
+//! the only wake source. RTT can't wake the core, the receiver polls slower
+//! while the link is quiet to leave room for STOP, and debug access stays
+//! on in STOP so the host can still reach the RTT buffers.
+//!
+//! The executor's trace hooks tell when it goes idle and when it picks up
+//! work again. The executor sets SLEEPDEEP right before the WFE that enters
+//! STOP, and only clears it the next time it runs out of work, so whether
+//! an idle stretch was spent in STOP is read back from SLEEPDEEP as it ends.
+
+use core::cell::RefCell;
+
+use cortex_m::peripheral::{scb::VectActive, SCB};
+use embassy_sync::blocking_mutex::{raw::CriticalSectionRawMutex, Mutex};
+use embassy_time::{Duration, Instant};
+use template_icd::PowerStats;
+
+#[cfg(not(feature = "low-power"))]
+pub use embassy_executor::Executor;
+#[cfg(feature = "low-power")]
+pub use embassy_stm32::low_power::Executor;
+
+/// The receiver polls every millisecond for this long after the last byte
+const RX_FAST_POLL: Duration = Duration::from_millis(50);
+/// The HAL's time driver only enters STOP with the next timer at least
+/// this far off
+const MIN_STOP: Duration = Duration::from_millis(250);
+/// How often the receiver polls a quiet link, far enough apart for STOP
+/// in between
+const RX_SLOW_POLL: Duration = Duration::from_millis(2 * MIN_STOP.as_millis());
+
+#[derive(Clone, Copy)]
+struct Idle {
+    since: Option<Instant>,
+    sleep: Duration,
+    stop: Duration,
+}
+
+static IDLE: Mutex<CriticalSectionRawMutex, RefCell<Idle>> = Mutex::new(RefCell::new(Idle {
+    since: None,
+    sleep: Duration::from_ticks(0),
+    stop: Duration::from_ticks(0),
+}));
+
+#[cfg(not(feature = "low-power"))]
+pub fn executor() -> &'static mut Executor {
+    static EXECUTOR: static_cell::StaticCell<Executor> = static_cell::StaticCell::new();
+    EXECUTOR.init(Executor::new())
+}
+
+#[cfg(feature = "low-power")]
+pub fn executor() -> &'static mut Executor {
+    Executor::take()
+}
+
+/// Selects Stop1 and hands the RTC to the executor, to keep time in STOP
+/// and wake the core for the next timer
+#[cfg(feature = "low-power")]
+pub fn init(rtc: embassy_stm32::peripherals::RTC) {
+    use embassy_stm32::{
+        pac,
+        rtc::{Rtc, RtcConfig},
+    };
+
+    // The HAL only selects the STOP mode on other families, the G4 would
+    // stay at Stop0 from reset. Stop1 also puts the main regulator in low
+    // power.
+    pac::PWR.cr1().modify(|w| w.set_lpms(STOP1));
+    // Keeps the debug port clocked in STOP, for RTT
+    pac::DBGMCU.cr().modify(|w| w.set_dbg_stop(true));
+
+    static RTC: static_cell::StaticCell<Rtc> = static_cell::StaticCell::new();
+    embassy_stm32::low_power::stop_with_rtc(RTC.init(Rtc::new(rtc, RtcConfig::default())));
+}
+
+/// PWR_CR1.LPMS for Stop1
+#[cfg(feature = "low-power")]
+const STOP1: u8 = 0b001;
+
+fn stop_ready() -> bool {
+    #[cfg(feature = "low-power")]
+    return embassy_stm32::low_power::stop_ready(embassy_stm32::low_power::StopMode::Stop1);
+    #[cfg(not(feature = "low-power"))]
+    false
+}
+
+/// How long the RTT receiver waits before looking again, with nothing
+/// received for `quiet`
+pub fn rx_poll_interval(quiet: Duration) -> Duration {
+    if cfg!(feature = "low-power") && quiet > RX_FAST_POLL {
+        RX_SLOW_POLL
+    } else {
+        Duration::from_millis(1)
+    }
+}
+
+impl Idle {
+    /// Books the idle stretch that ends now, if one is going on
+    fn end(&mut self) {
+        let Some(since) = self.since.take() else {
+            return;
+        };
+        let idle = since.elapsed();
+        if deep_sleep() {
+            self.stop += idle;
+        } else {
+            self.sleep += idle;
+        }
+    }
+}
+
+/// Whether the executor's last WFE entered STOP, see the module docs
+fn deep_sleep() -> bool {
+    // SAFETY: a read of SCR, which only the thread mode executor writes
+    unsafe { (*SCB::PTR).scr.read() & SCB_SCR_SLEEPDEEP != 0 }
+}
+
+const SCB_SCR_SLEEPDEEP: u32 = 1 << 2;
+
+/// Only the thread mode executor idles the core, the server executor runs
+/// in an interrupt
+fn thread_mode() -> bool {
+    SCB::vect_active() == VectActive::ThreadMode
+}
+
+pub fn stats() -> PowerStats {
+    let uptime = Instant::now().duration_since(Instant::from_ticks(0));
+    IDLE.lock(|idle| {
+        // Asked from the server, thread mode may be idle meanwhile
+        let mut idle = *idle.borrow();
+        idle.end();
+        let sleep_ms = idle.sleep.as_millis();
+        let stop_ms = idle.stop.as_millis();
+        PowerStats {
+            low_power: cfg!(feature = "low-power"),
+            run_ms: uptime.as_millis().saturating_sub(sleep_ms + stop_ms),
+            sleep_ms,
+            stop_ms,
+            stop_ready: stop_ready(),
+        }
+    })
+}
+
+#[no_mangle]
+extern "Rust" fn _embassy_trace_executor_idle(_executor_id: u32) {
+    if thread_mode() {
+        IDLE.lock(|idle| {
+            let mut idle = idle.borrow_mut();
+            // Woken by an interrupt that left no work for thread mode
+            idle.end();
+            idle.since = Some(Instant::now());
+        });
+    }
+}
+
+#[no_mangle]
+extern "Rust" fn _embassy_trace_task_exec_begin(_executor_id: u32, _task_id: u32) {
+    if thread_mode() {
+        IDLE.lock(|idle| idle.borrow_mut().end());
+    }
+}
+
+#[no_mangle]
+extern "Rust" fn _embassy_trace_task_exec_end(_executor_id: u32, _task_id: u32) {}
+
+#[no_mangle]
+extern "Rust" fn _embassy_trace_task_new(_executor_id: u32, _task_id: u32) {}
+
+#[no_mangle]
+extern "Rust" fn _embassy_trace_task_ready_begin(_executor_id: u32, _task_id: u32) {}
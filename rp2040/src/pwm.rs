@@ -0,0 +1,185 @@
+//! PWM outputs on the general-purpose timers
+
+use embassy_stm32::{
+    pac,
+    peripherals::{TIM3, TIM8},
+    rcc,
+    time::Hertz,
+    timer::{
+        complementary_pwm::ComplementaryPwm, low_level::OutputPolarity, simple_pwm::SimplePwm, Channel,
+    },
+};
+use template_icd::{PwmChannel, PwmConfig, PwmError, PwmPolarity, PwmResult, PwmStatus, DUTY_MAX};
+
+/// Longest dead time the TIM8 dead-time generator can insert, in timer ticks
+const MAX_DEAD_TIME_TICKS: u64 = 4 * 1008;
+
+/// The PWM outputs exposed over the ICD, see [`PwmChannel`] for the pins
+pub struct PwmOutputs {
+    tim3: SimplePwm<'static, TIM3>,
+    tim8: ComplementaryPwm<'static, TIM8>,
+    /// Duty of each enabled channel, indexed by [`index`]
+    duties: [Option<u16>; 4],
+}
+
+impl PwmOutputs {
+    pub fn new(tim3: SimplePwm<'static, TIM3>, tim8: ComplementaryPwm<'static, TIM8>) -> Self {
+        Self {
+            tim3,
+            tim8,
+            duties: [None; 4],
+        }
+    }
+
+    pub fn configure(&mut self, config: PwmConfig) -> PwmResult {
+        let PwmConfig {
+            channel,
+            frequency_hz,
+            duty,
+            polarity,
+            complementary,
+        } = config;
+        if duty > DUTY_MAX {
+            return Err(PwmError::DutyOutOfRange);
+        }
+        let timer_hz = timer_hz(channel);
+        if frequency_hz == 0 || frequency_hz > timer_hz / 2 {
+            return Err(PwmError::FrequencyOutOfRange);
+        }
+        let polarity = match polarity {
+            PwmPolarity::ActiveHigh => OutputPolarity::ActiveHigh,
+            PwmPolarity::ActiveLow => OutputPolarity::ActiveLow,
+        };
+
+        match tim3_channel(channel) {
+            Some(ch) => {
+                if complementary.is_some() {
+                    return Err(PwmError::NoComplementaryOutput);
+                }
+                self.tim3.set_frequency(Hertz(frequency_hz));
+                let mut pwm = self.tim3.channel(ch);
+                pwm.set_polarity(polarity);
+                pwm.enable();
+            }
+            None => {
+                let dead_time = match complementary {
+                    Some(c) => {
+                        let ticks = c.dead_time_ns as u64 * timer_hz as u64 / 1_000_000_000;
+                        if ticks > MAX_DEAD_TIME_TICKS {
+                            return Err(PwmError::DeadTimeOutOfRange);
+                        }
+                        Some(ticks as u16)
+                    }
+                    None => None,
+                };
+                self.tim8.disable(Channel::Ch1);
+                self.tim8.set_frequency(Hertz(frequency_hz));
+                self.tim8.set_polarity(Channel::Ch1, polarity);
+                self.tim8.set_dead_time(dead_time.unwrap_or(0));
+                self.tim8.enable(Channel::Ch1);
+                if dead_time.is_none() {
+                    // `enable` turns on both outputs, leave CH1N idle
+                    pac::TIM8.ccer().modify(|w| w.set_ccne(0, false));
+                }
+            }
+        }
+
+        self.duties[index(channel)] = Some(duty);
+        // A new frequency changes the period of every channel on the timer,
+        // so rescale all of them rather than just this one
+        self.apply_duties();
+        Ok(self.status(channel))
+    }
+
+    pub fn set_duty(&mut self, channel: PwmChannel, duty: u16) -> PwmResult {
+        if duty > DUTY_MAX {
+            return Err(PwmError::DutyOutOfRange);
+        }
+        let Some(slot) = self.duties[index(channel)].as_mut() else {
+            return Err(PwmError::NotConfigured);
+        };
+        *slot = duty;
+        self.apply_duties();
+        Ok(self.status(channel))
+    }
+
+    pub fn disable(&mut self, channel: PwmChannel) -> PwmResult {
+        match tim3_channel(channel) {
+            Some(ch) => self.tim3.channel(ch).disable(),
+            None => self.tim8.disable(Channel::Ch1),
+        }
+        self.duties[index(channel)] = None;
+        Ok(self.status(channel))
+    }
+
+    /// Every channel, as after a reset
+    pub fn disable_all(&mut self) {
+        for channel in CHANNELS {
+            let _ = self.disable(channel);
+        }
+    }
+
+    fn apply_duties(&mut self) {
+        for (channel, duty) in CHANNELS.into_iter().zip(self.duties) {
+            let Some(duty) = duty else {
+                continue;
+            };
+            match tim3_channel(channel) {
+                Some(ch) => self.tim3.channel(ch).set_duty_cycle_fraction(duty, DUTY_MAX),
+                None => {
+                    let max = self.tim8.get_max_duty() as u32;
+                    self.tim8.set_duty(Channel::Ch1, (duty as u32 * max / DUTY_MAX as u32) as u16);
+                }
+            }
+        }
+    }
+
+    fn status(&self, channel: PwmChannel) -> PwmStatus {
+        let (resolution, prescaler) = match tim3_channel(channel) {
+            Some(_) => (self.tim3.max_duty_cycle(), pac::TIM3.psc().read()),
+            None => (self.tim8.get_max_duty(), pac::TIM8.psc().read()),
+        };
+        let duty = self.duties[index(channel)];
+        PwmStatus {
+            channel,
+            enabled: duty.is_some(),
+            frequency_hz: timer_hz(channel) / ((prescaler as u32 + 1) * resolution as u32),
+            duty: duty.unwrap_or(0),
+            resolution,
+        }
+    }
+}
+
+const CHANNELS: [PwmChannel; 4] = [
+    PwmChannel::Tim3Ch1,
+    PwmChannel::Tim3Ch3,
+    PwmChannel::Tim3Ch4,
+    PwmChannel::Tim8Ch1,
+];
+
+fn index(channel: PwmChannel) -> usize {
+    match channel {
+        PwmChannel::Tim3Ch1 => 0,
+        PwmChannel::Tim3Ch3 => 1,
+        PwmChannel::Tim3Ch4 => 2,
+        PwmChannel::Tim8Ch1 => 3,
+    }
+}
+
+fn tim3_channel(channel: PwmChannel) -> Option<Channel> {
+    match channel {
+        PwmChannel::Tim3Ch1 => Some(Channel::Ch1),
+        PwmChannel::Tim3Ch3 => Some(Channel::Ch3),
+        PwmChannel::Tim3Ch4 => Some(Channel::Ch4),
+        PwmChannel::Tim8Ch1 => None,
+    }
+}
+
+/// The timer's kernel clock as [`clocks`](crate::clocks) set it up, which
+/// the limits and the reported frequency both go by
+fn timer_hz(channel: PwmChannel) -> u32 {
+    match tim3_channel(channel) {
+        Some(_) => rcc::frequency::<TIM3>().0,
+        None => rcc::frequency::<TIM8>().0,
+    }
+}
@@ -0,0 +1,96 @@
+//! RAM usage, from the linker layout and a painted stack
+//!
+//! cortex-m-rt puts the statics at the bottom of RAM and the stack at the
+//! top, growing down towards them. [`paint_stack`] fills the space between
+//! with a pattern at boot, and the lowest word that no longer holds it is
+//! as deep as the stack has been.
+
+use core::{mem::size_of, ptr::addr_of};
+
+use template_icd::{RamBuffer, RamBufferName, RamBuffers, RamStats};
+
+use crate::{app::BufStorage, logic, pattern, tasks, uart, RTT_CHANNEL_SIZE, RTT_FRAME_SIZE};
+
+const PAINT: u32 = 0xCAFE_F00D;
+
+/// Left alone below the stack pointer while painting, for the frames of
+/// the calls made meanwhile
+const PAINT_MARGIN: usize = 256;
+
+/// The big statics, which decide most of the RAM use
+const BUFFERS: &[(&str, usize)] = &[
+    ("task arena", tasks::ARENA_SIZE),
+    ("packet buffers", size_of::<BufStorage>()),
+    ("rtt up", RTT_CHANNEL_SIZE),
+    ("rtt down", RTT_CHANNEL_SIZE),
+    ("frame tx 1", RTT_FRAME_SIZE),
+    ("frame tx 2", RTT_FRAME_SIZE),
+    ("frame rx", RTT_FRAME_SIZE),
+    ("uart rx ring", uart::RX_RING_SIZE),
+    ("logic ring", logic::RING_SIZE),
+    ("logic samples", logic::MAX_SAMPLES),
+    ("pattern ring", pattern::RING_SIZE),
+    ("pattern", pattern::MAX_WORDS),
+];
+
+extern "C" {
+    static __sdata: u32;
+    static __sheap: u32;
+    static _stack_start: u32;
+}
+
+fn ram_start() -> usize {
+    addr_of!(__sdata) as usize
+}
+
+fn stack_bottom() -> usize {
+    addr_of!(__sheap) as usize
+}
+
+fn stack_top() -> usize {
+    addr_of!(_stack_start) as usize
+}
+
+/// Fill the unused stack with the pattern, first thing in main while no
+/// interrupt is running
+pub fn paint_stack() {
+    cortex_m::interrupt::free(|_| {
+        let end = cortex_m::register::msp::read() as usize - PAINT_MARGIN;
+        let mut word = stack_bottom() as *mut u32;
+        while (word as usize) < end {
+            // SAFETY: between the statics and the stack pointer, nothing lives there
+            unsafe {
+                word.write_volatile(PAINT);
+                word = word.add(1);
+            }
+        }
+    });
+}
+
+/// The lowest address the stack has reached
+fn stack_low_water() -> usize {
+    let mut word = stack_bottom() as *const u32;
+    // SAFETY: as in `paint_stack`, and only reads
+    while (word as usize) < stack_top() && unsafe { word.read_volatile() } == PAINT {
+        word = unsafe { word.add(1) };
+    }
+    word as usize
+}
+
+pub fn stats() -> RamStats {
+    let low = stack_low_water();
+    RamStats {
+        ram_size: (stack_top() - ram_start()) as u32,
+        statics: (stack_bottom() - ram_start()) as u32,
+        stack_size: (stack_top() - stack_bottom()) as u32,
+        stack_peak: (stack_top() - low) as u32,
+        free: (low - stack_bottom()) as u32,
+        buffers: BUFFERS
+            .iter()
+            .map(|&(name, size)| RamBuffer {
+                name: RamBufferName::try_from(name).unwrap_or_default(),
+                size: size as u32,
+            })
+            .collect::<RamBuffers>(),
+    }
+}
@@ -0,0 +1,67 @@
+//! Host sessions
+//!
+//! RTT can't tell when a host attaches or goes away, so a host starts each
+//! session with [`HostHelloEndpoint`](template_icd::HostHelloEndpoint). A
+//! session ID the device hasn't seen last starts the sequence numbers of
+//! every topic over from zero, and the device announces itself on
+//! [`ConnectTopic`](template_icd::ConnectTopic). The session ends when the
+//! host goes silent, see [`liveness`](crate::liveness). Partial frames a
+//! host left behind are dropped by the RTT receiver after [`RX_STALE`].
+
+use core::{
+    cell::Cell,
+    sync::atomic::{AtomicU32, Ordering},
+};
+
+use embassy_sync::blocking_mutex::{raw::CriticalSectionRawMutex, Mutex};
+use embassy_time::Duration;
+use postcard_rpc::header::VarSeq;
+
+/// Bytes of a frame that sit this long without the rest following are
+/// from a host that went away mid-frame
+pub const RX_STALE: Duration = Duration::from_millis(100);
+
+/// Counts up with every new session
+static GENERATION: AtomicU32 = AtomicU32::new(0);
+static CURRENT: Mutex<CriticalSectionRawMutex, Cell<Option<u32>>> = Mutex::new(Cell::new(None));
+
+/// Whether `id` starts a new session
+pub fn hello(id: u32) -> bool {
+    CURRENT.lock(|current| {
+        if current.get() == Some(id) {
+            return false;
+        }
+        current.set(Some(id));
+        GENERATION.fetch_add(1, Ordering::Relaxed);
+        true
+    })
+}
+
+/// The host is gone, even the same session ID starts over when it is back
+pub fn end() {
+    CURRENT.lock(|current| current.set(None));
+}
+
+/// Sequence numbers of a topic, from zero in every session
+#[derive(Default)]
+pub struct TopicSeq {
+    generation: u32,
+    next: u32,
+}
+
+impl TopicSeq {
+    pub const fn new() -> Self {
+        Self { generation: 0, next: 0 }
+    }
+
+    pub fn advance(&mut self) -> VarSeq {
+        let generation = GENERATION.load(Ordering::Relaxed);
+        if generation != self.generation {
+            self.generation = generation;
+            self.next = 0;
+        }
+        let seq = self.next;
+        self.next = self.next.wrapping_add(1);
+        VarSeq::Seq4(seq)
+    }
+}
@@ -0,0 +1,167 @@
+//! SPI master bridge on SPI1, with GPIO chip selects
+
+use embassy_stm32::{
+    gpio::Output,
+    mode::Async,
+    peripherals::SPI1,
+    rcc,
+    spi::{self, BitOrder, Spi, MODE_0, MODE_1, MODE_2, MODE_3},
+    time::Hertz,
+};
+use embassy_time::Timer;
+use template_icd::{
+    SpiBitOrder, SpiConfig, SpiConfigResult, SpiCsConfig, SpiCsPin, SpiData, SpiError, SpiMode, SpiOp,
+    SpiResult, SpiTransaction, SpiTransactionResult, SPI_MAX_DATA,
+};
+
+struct ChipSelect {
+    pin: Output<'static>,
+    active_high: bool,
+    declared: bool,
+}
+
+pub struct SpiBridge {
+    bus: Spi<'static, Async>,
+    /// Indexed by [`cs_index`]
+    cs: [ChipSelect; 3],
+}
+
+impl SpiBridge {
+    /// The chip select pins must start out high, which is inactive until
+    /// they are declared otherwise
+    pub fn new(bus: Spi<'static, Async>, cs: [Output<'static>; 3]) -> Self {
+        Self {
+            bus,
+            cs: cs.map(|pin| ChipSelect {
+                pin,
+                active_high: false,
+                declared: false,
+            }),
+        }
+    }
+
+    pub fn configure(&mut self, config: SpiConfig) -> SpiConfigResult {
+        let mut cfg = spi::Config::default();
+        cfg.mode = match config.mode {
+            SpiMode::Mode0 => MODE_0,
+            SpiMode::Mode1 => MODE_1,
+            SpiMode::Mode2 => MODE_2,
+            SpiMode::Mode3 => MODE_3,
+        };
+        cfg.bit_order = match config.bit_order {
+            SpiBitOrder::MsbFirst => BitOrder::MsbFirst,
+            SpiBitOrder::LsbFirst => BitOrder::LsbFirst,
+        };
+        // The smallest divider is 2, and the driver panics if asked for more
+        if config.frequency_hz == 0 || config.frequency_hz > rcc::frequency::<SPI1>().0 / 2 {
+            return Err(SpiError::InvalidFrequency);
+        }
+        cfg.frequency = Hertz(config.frequency_hz);
+        self.bus.set_config(&cfg).map_err(|_| SpiError::InvalidFrequency)?;
+        Ok(self.bus.get_current_config().frequency.0)
+    }
+
+    pub fn declare_cs(&mut self, config: SpiCsConfig) -> SpiResult {
+        let cs = &mut self.cs[cs_index(config.pin)];
+        cs.active_high = config.active_high;
+        cs.declared = true;
+        set_cs(cs, false);
+        Ok(())
+    }
+
+    /// Run all ops with the chip select held, the server does not handle
+    /// any other request until this returns
+    pub async fn transaction(&mut self, tx: &SpiTransaction) -> SpiTransactionResult {
+        // Validate everything up front, so a bad request never toggles CS
+        let mut write_len = 0usize;
+        let mut read_len = 0usize;
+        for op in tx.ops.iter() {
+            match *op {
+                SpiOp::Write(n) => write_len += n as usize,
+                SpiOp::Read(n) => read_len += n as usize,
+                SpiOp::Transfer(n) => {
+                    write_len += n as usize;
+                    read_len += n as usize;
+                }
+                SpiOp::DelayUs(_) => {}
+            }
+        }
+        if write_len != tx.data.len() {
+            return Err(SpiError::DataLengthMismatch);
+        }
+        if read_len > SPI_MAX_DATA {
+            return Err(SpiError::ReplyTooLarge);
+        }
+        let cs = match tx.cs {
+            Some(pin) if !self.cs[cs_index(pin)].declared => return Err(SpiError::CsNotDeclared),
+            Some(pin) => Some(&mut self.cs[cs_index(pin)]),
+            None => None,
+        };
+
+        let mut rx = SpiData::new();
+        let _ = rx.resize(read_len, 0);
+
+        if let Some(cs) = cs {
+            set_cs(cs, true);
+            let res = run_ops(&mut self.bus, tx, &mut rx).await;
+            set_cs(cs, false);
+            res
+        } else {
+            run_ops(&mut self.bus, tx, &mut rx).await
+        }
+        .map(|()| rx)
+    }
+}
+
+async fn run_ops(bus: &mut Spi<'static, Async>, tx: &SpiTransaction, rx: &mut [u8]) -> SpiResult {
+    let mut written = 0;
+    let mut read = 0;
+    for op in tx.ops.iter() {
+        match *op {
+            SpiOp::Write(n) => {
+                let n = n as usize;
+                bus.write(&tx.data[written..][..n]).await.map_err(spi_error)?;
+                written += n;
+            }
+            SpiOp::Read(n) => {
+                let n = n as usize;
+                bus.read(&mut rx[read..][..n]).await.map_err(spi_error)?;
+                read += n;
+            }
+            SpiOp::Transfer(n) => {
+                let n = n as usize;
+                bus.transfer(&mut rx[read..][..n], &tx.data[written..][..n])
+                    .await
+                    .map_err(spi_error)?;
+                written += n;
+                read += n;
+            }
+            SpiOp::DelayUs(us) => Timer::after_micros(us.into()).await,
+        }
+    }
+    Ok(())
+}
+
+fn set_cs(cs: &mut ChipSelect, active: bool) {
+    if active == cs.active_high {
+        cs.pin.set_high();
+    } else {
+        cs.pin.set_low();
+    }
+}
+
+fn cs_index(pin: SpiCsPin) -> usize {
+    match pin {
+        SpiCsPin::Pb10 => 0,
+        SpiCsPin::Pb11 => 1,
+        SpiCsPin::Pb12 => 2,
+    }
+}
+
+fn spi_error(e: spi::Error) -> SpiError {
+    match e {
+        spi::Error::Overrun => SpiError::Overrun,
+        spi::Error::ModeFault => SpiError::ModeFault,
+        _ => SpiError::Bus,
+    }
+}
@@ -0,0 +1,178 @@
+//! Key-value config store on the last flash pages
+//!
+//! Records are appended and wear-levelled across the pages by
+//! sequential-storage, each one carrying a CRC. The G431 flash can't
+//! clear bits of a written word, so deleting a key appends a marker
+//! record rather than erasing anything.
+
+use core::ops::Range;
+
+use embassy_embedded_hal::{adapter::BlockingAsync, flash::partition::BlockingPartition};
+use sequential_storage::{
+    cache::NoCache,
+    erase_all,
+    map::{fetch_all_items, fetch_item, store_item, Key, SerializationError, Value},
+};
+use serde::de::DeserializeOwned;
+use template_icd::{
+    ConfigError, ConfigGetResult, ConfigKey, ConfigKeys, ConfigListResult, ConfigResult, ConfigValue,
+    CONFIG_MAX_KEY, CONFIG_MAX_VALUE,
+};
+
+use crate::{FlashPartition, SharedFlash};
+
+/// The last four 2K pages, offset from the start of flash. Both linker
+/// layouts keep the program out of them.
+const STORE_OFFSET: u32 = 0x1_E000;
+const STORE_SIZE: u32 = 0x2000;
+/// Offsets within the store partition
+const STORE_RANGE: Range<u32> = 0..STORE_SIZE;
+
+/// Holds the largest record: key, length, marker and value
+const BUF_SIZE: usize = (1 + CONFIG_MAX_KEY + 1 + CONFIG_MAX_VALUE).next_multiple_of(8);
+
+/// Stored as a length byte followed by the UTF-8 bytes
+#[derive(Clone, PartialEq, Eq)]
+struct StoreKey(ConfigKey);
+
+impl Key for StoreKey {
+    fn serialize_into(&self, buffer: &mut [u8]) -> Result<usize, SerializationError> {
+        let bytes = self.0.as_bytes();
+        let len = 1 + bytes.len();
+        if buffer.len() < len {
+            return Err(SerializationError::BufferTooSmall);
+        }
+        buffer[0] = bytes.len() as u8;
+        buffer[1..len].copy_from_slice(bytes);
+        Ok(len)
+    }
+
+    fn deserialize_from(buffer: &[u8]) -> Result<(Self, usize), SerializationError> {
+        let len = Self::get_len(buffer)?;
+        let bytes = buffer.get(1..len).ok_or(SerializationError::BufferTooSmall)?;
+        let key = core::str::from_utf8(bytes)
+            .ok()
+            .and_then(|s| ConfigKey::try_from(s).ok())
+            .ok_or(SerializationError::InvalidFormat)?;
+        Ok((Self(key), len))
+    }
+
+    fn get_len(buffer: &[u8]) -> Result<usize, SerializationError> {
+        let len = buffer.first().ok_or(SerializationError::BufferTooSmall)?;
+        Ok(1 + *len as usize)
+    }
+}
+
+/// A value, or the marker left behind by deleting its key
+enum Record<'a> {
+    Value(&'a [u8]),
+    Deleted,
+}
+
+impl<'a> Value<'a> for Record<'a> {
+    fn serialize_into(&self, buffer: &mut [u8]) -> Result<usize, SerializationError> {
+        let value = match self {
+            Record::Value(value) => value,
+            Record::Deleted => &[][..],
+        };
+        let len = 1 + value.len();
+        if buffer.len() < len {
+            return Err(SerializationError::BufferTooSmall);
+        }
+        buffer[0] = matches!(self, Record::Value(_)) as u8;
+        buffer[1..len].copy_from_slice(value);
+        Ok(len)
+    }
+
+    fn deserialize_from(buffer: &'a [u8]) -> Result<Self, SerializationError> {
+        match buffer.split_first() {
+            Some((1, value)) => Ok(Record::Value(value)),
+            Some((0, _)) => Ok(Record::Deleted),
+            _ => Err(SerializationError::InvalidFormat),
+        }
+    }
+}
+
+pub struct ConfigStore {
+    flash: BlockingAsync<FlashPartition>,
+    buf: [u8; BUF_SIZE],
+}
+
+impl ConfigStore {
+    pub fn new(flash: &'static SharedFlash) -> Self {
+        Self {
+            flash: BlockingAsync::new(BlockingPartition::new(flash, STORE_OFFSET, STORE_SIZE)),
+            buf: [0u8; BUF_SIZE],
+        }
+    }
+
+    pub async fn get(&mut self, key: &ConfigKey) -> ConfigGetResult {
+        let key = StoreKey(key.clone());
+        let record = fetch_item::<_, Record, _>(&mut self.flash, STORE_RANGE, &mut NoCache::new(), &mut self.buf, &key)
+            .await
+            .map_err(store_error)?;
+        match record {
+            Some(Record::Value(value)) => ConfigValue::from_slice(value).map_err(|_| ConfigError::Corrupted),
+            Some(Record::Deleted) | None => Err(ConfigError::NotFound),
+        }
+    }
+
+    /// Flash is written, and erased when a page fills up, with the
+    /// executor stalled. That takes up to a few tens of milliseconds.
+    pub async fn set(&mut self, key: ConfigKey, value: &[u8]) -> ConfigResult {
+        self.store(key, &Record::Value(value)).await
+    }
+
+    pub async fn delete(&mut self, key: ConfigKey) -> ConfigResult {
+        self.get(&key).await?;
+        self.store(key, &Record::Deleted).await
+    }
+
+    async fn store(&mut self, key: ConfigKey, record: &Record<'_>) -> ConfigResult {
+        store_item(&mut self.flash, STORE_RANGE, &mut NoCache::new(), &mut self.buf, &StoreKey(key), record)
+            .await
+            .map_err(store_error)
+    }
+
+    pub async fn list(&mut self) -> ConfigListResult {
+        let mut cache = NoCache::new();
+        let mut items = fetch_all_items::<StoreKey, _, _>(&mut self.flash, STORE_RANGE, &mut cache, &mut self.buf)
+            .await
+            .map_err(store_error)?;
+        // Records come oldest first, so the last one for a key wins
+        let mut keys = ConfigKeys::new();
+        while let Some((StoreKey(key), record)) = items.next::<StoreKey, Record>(&mut self.buf).await.map_err(store_error)? {
+            let pos = keys.iter().position(|k| *k == key);
+            match (record, pos) {
+                (Record::Value(_), None) => keys.push(key).map_err(|_| ConfigError::TooManyKeys)?,
+                (Record::Deleted, Some(pos)) => {
+                    keys.swap_remove(pos);
+                }
+                _ => {}
+            }
+        }
+        Ok(keys)
+    }
+
+    pub async fn factory_reset(&mut self) -> ConfigResult {
+        erase_all(&mut self.flash, STORE_RANGE).await.map_err(store_error)
+    }
+
+    /// Decode a typed value, `None` if the key is missing or doesn't decode
+    /// as `T`, which leaves the caller on its defaults
+    pub async fn load<T: DeserializeOwned>(&mut self, key: &str) -> Option<T> {
+        let key = ConfigKey::try_from(key).ok()?;
+        let value = self.get(&key).await.ok()?;
+        postcard::from_bytes(&value).ok()
+    }
+}
+
+fn store_error<E>(e: sequential_storage::Error<E>) -> ConfigError {
+    match e {
+        sequential_storage::Error::FullStorage | sequential_storage::Error::ItemTooBig => ConfigError::Full,
+        sequential_storage::Error::Corrupted { .. } | sequential_storage::Error::SerializationError(_) => {
+            ConfigError::Corrupted
+        }
+        _ => ConfigError::Flash,
+    }
+}
@@ -0,0 +1,255 @@
+//! Bookkeeping for the executor's task pools
+//!
+//! embassy-executor keeps no statistics, so every task is declared with
+//! [`pooled_task!`], which puts a [`Pool`] next to it, and is spawned
+//! through [`spawn`]. Pools are carved out of the task arena the first time
+//! something is spawned from them and never given back. That is also when
+//! a pool joins the ones [`stats`] adds up.
+
+use core::{
+    cell::Cell,
+    future::Future,
+    mem::size_of,
+    sync::atomic::{AtomicU32, AtomicU8, Ordering},
+};
+
+use embassy_executor::{raw::TaskStorage, SendSpawner, SpawnError, SpawnToken, Spawner};
+use embassy_sync::{
+    blocking_mutex::{raw::CriticalSectionRawMutex, Mutex},
+    channel::Channel,
+};
+use postcard_rpc::server::Sender;
+use template_icd::{ExecutorStats, SpawnRejected, SpawnRejectedTopic, TaskName, TaskPoolStats, TaskPools};
+
+use crate::{app::AppTx, session::TopicSeq};
+
+/// Has to match the `task-arena-size-*` feature of embassy-executor
+pub const ARENA_SIZE: usize = 8192;
+
+/// The pools spawned from so far, most recent first, linked through
+/// [`Pool::next`]
+static LISTED: Mutex<CriticalSectionRawMutex, Cell<Option<&'static Pool>>> = Mutex::new(Cell::new(None));
+
+/// Pools with a rejection the host hasn't heard about yet
+static REJECTIONS: Channel<CriticalSectionRawMutex, &'static Pool, 4> = Channel::new();
+
+/// Declares a task that runs the async fn `$body`, and its [`Pool`] as
+/// `$pool` with `$size` slots. `$name` makes the task's spawn tokens, for
+/// [`spawn`] and the server's spawn handlers, and names the pool.
+///
+/// embassy doesn't name the future a task's slot holds, so [`slot_bytes`]
+/// sizes a copy of the task, with the same arguments and body.
+macro_rules! pooled_task {
+    (
+        $(#[$attr:meta])*
+        $vis:vis fn $name:ident($($arg:ident: $ty:ty),* $(,)?) => $body:path, $pool:ident[$size:expr]
+    ) => {
+        $vis static $pool: $crate::tasks::Pool = {
+            async fn sized($($arg: $ty),*) {
+                let _slot = $pool.slot();
+                $body($($arg),*).await
+            }
+            $crate::tasks::Pool::new(stringify!($name), $size, $crate::tasks::slot_bytes(&sized))
+        };
+
+        $(#[$attr])*
+        $vis fn $name($($arg: $ty),*) -> $crate::tasks::Counted<impl Sized> {
+            #[embassy_executor::task(pool_size = $size)]
+            async fn task($($arg: $ty),*) {
+                let _slot = $pool.slot();
+                $body($($arg),*).await
+            }
+            $pool.token(task($($arg),*))
+        }
+    };
+}
+pub(crate) use pooled_task;
+
+pooled_task! {
+    /// Tells the host about rejected spawns
+    pub fn rejected_task(sender: Sender<AppTx>) => report_rejected, REJECTED_POOL[1]
+}
+
+pub struct Pool {
+    /// The task function
+    name: &'static str,
+    size: u8,
+    slot_bytes: usize,
+    running: AtomicU8,
+    high_water: AtomicU8,
+    spawned: AtomicU32,
+    rejected: AtomicU32,
+    /// In [`LISTED`] once spawned from, `None` until then
+    next: Mutex<CriticalSectionRawMutex, Cell<Option<Option<&'static Pool>>>>,
+}
+
+impl Pool {
+    /// Use [`pooled_task!`] rather than this
+    pub const fn new(name: &'static str, size: usize, slot_bytes: usize) -> Self {
+        Self {
+            name,
+            size: size as u8,
+            slot_bytes,
+            running: AtomicU8::new(0),
+            high_water: AtomicU8::new(0),
+            spawned: AtomicU32::new(0),
+            rejected: AtomicU32::new(0),
+            next: Mutex::new(Cell::new(None)),
+        }
+    }
+
+    /// Pairs a token of this pool's task with the pool, for [`spawn`]
+    pub fn token<S>(&'static self, token: SpawnToken<S>) -> Counted<S> {
+        Counted { pool: self, token }
+    }
+
+    /// Puts the pool in [`LISTED`], once
+    fn list(&'static self) {
+        LISTED.lock(|first| {
+            self.next.lock(|next| {
+                if next.get().is_none() {
+                    next.set(Some(first.replace(Some(self))));
+                }
+            })
+        });
+    }
+
+    fn started(&self) {
+        self.spawned.fetch_add(1, Ordering::Relaxed);
+        let running = self.running.fetch_add(1, Ordering::Relaxed) + 1;
+        self.high_water.fetch_max(running, Ordering::Relaxed);
+    }
+
+    /// Held by the task for as long as it runs, so its slot counts as
+    /// free again afterwards
+    pub fn slot(&'static self) -> Slot {
+        Slot(self)
+    }
+
+    fn stats(&self) -> TaskPoolStats {
+        let spawned = self.spawned.load(Ordering::Relaxed);
+        let rejected = self.rejected.load(Ordering::Relaxed);
+        let allocated = spawned != 0 || rejected != 0;
+        TaskPoolStats {
+            name: TaskName::try_from(self.name).unwrap_or_default(),
+            size: self.size,
+            running: self.running.load(Ordering::Relaxed),
+            high_water: self.high_water.load(Ordering::Relaxed),
+            spawned,
+            rejected,
+            arena_bytes: if allocated { self.arena_bytes() } else { 0 },
+        }
+    }
+
+    fn arena_bytes(&self) -> u32 {
+        (self.size as usize * self.slot_bytes) as u32
+    }
+}
+
+pub struct Slot(&'static Pool);
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        self.0.running.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// A spawn token, with the pool it came from
+pub struct Counted<S> {
+    pool: &'static Pool,
+    token: SpawnToken<S>,
+}
+
+/// Async functions, by arguments
+pub trait TaskFn<Args> {
+    type Fut: Future + 'static;
+}
+
+macro_rules! task_fn {
+    ($($arg:ident),*) => {
+        impl<F, Fut, $($arg),*> TaskFn<($($arg,)*)> for F
+        where
+            F: Fn($($arg),*) -> Fut,
+            Fut: Future + 'static,
+        {
+            type Fut = Fut;
+        }
+    };
+}
+
+task_fn!();
+task_fn!(A);
+task_fn!(A, B);
+task_fn!(A, B, C);
+task_fn!(A, B, C, D);
+task_fn!(A, B, C, D, E);
+
+/// Arena bytes for one slot of a task, going by the future of `_task`
+pub const fn slot_bytes<F: TaskFn<Args>, Args>(_task: &F) -> usize {
+    size_of::<TaskStorage<F::Fut>>()
+}
+
+/// Spawn a task, counting it against its pool
+pub fn spawn<S>(spawner: &Spawner, token: Counted<S>) -> Result<(), SpawnError> {
+    let res = spawner.spawn(token.token);
+    count(token.pool, &res);
+    res
+}
+
+/// As [`spawn`], onto another executor
+pub fn spawn_send<S: Send>(spawner: &SendSpawner, token: Counted<S>) -> Result<(), SpawnError> {
+    let res = spawner.spawn(token.token);
+    count(token.pool, &res);
+    res
+}
+
+fn count(pool: &'static Pool, res: &Result<(), SpawnError>) {
+    pool.list();
+    match res {
+        Ok(()) => pool.started(),
+        Err(_) => {
+            pool.rejected.fetch_add(1, Ordering::Relaxed);
+            // Dropped when full, the counts still add up
+            let _ = REJECTIONS.try_send(pool);
+        }
+    }
+}
+
+/// As [`spawn`], for tasks that have to start
+pub fn must_spawn<S>(spawner: &Spawner, token: Counted<S>) {
+    spawn(spawner, token).unwrap();
+}
+
+/// As [`spawn_send`], for tasks that have to start
+pub fn must_spawn_send<S: Send>(spawner: &SendSpawner, token: Counted<S>) {
+    spawn_send(spawner, token).unwrap();
+}
+
+pub fn stats() -> ExecutorStats {
+    let mut stats = ExecutorStats {
+        arena_size: ARENA_SIZE as u32,
+        arena_used: 0,
+        pools: TaskPools::new(),
+    };
+    let mut pool = LISTED.lock(Cell::get);
+    while let Some(p) = pool {
+        let pool_stats = p.stats();
+        stats.arena_used += pool_stats.arena_bytes;
+        // Past the capacity the host only sees the arena total
+        let _ = stats.pools.push(pool_stats);
+        pool = p.next.lock(Cell::get).flatten();
+    }
+    stats
+}
+
+async fn report_rejected(sender: Sender<AppTx>) {
+    let mut seq = TopicSeq::new();
+    loop {
+        let pool = REJECTIONS.receive().await;
+        let msg = SpawnRejected {
+            name: TaskName::try_from(pool.name).unwrap_or_default(),
+            rejected: pool.rejected.load(Ordering::Relaxed),
+        };
+        let _ = sender.publish::<SpawnRejectedTopic>(seq.advance(), &msg).await;
+    }
+}
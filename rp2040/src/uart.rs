@@ -0,0 +1,107 @@
+//! UART passthrough on USART2, tunnelled over the UART topics
+
+use embassy_futures::select::{select, Either};
+use embassy_stm32::{
+    mode::Async,
+    usart::{self, ConfigError, DataBits, Parity, RingBufferedUartRx, StopBits, UartTx},
+};
+use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, signal::Signal};
+use postcard_rpc::server::Sender;
+use template_icd::{UartConfig, UartData, UartError, UartParity, UartResult, UartRxTopic, UartStopBits};
+
+use crate::{
+    app::AppTx,
+    session::TopicSeq,
+    tasks::pooled_task,
+};
+
+/// Size of the RX DMA ring buffer. The driver hands out at most half of it
+/// per read, which is also the most we send in one topic message.
+pub const RX_RING_SIZE: usize = 2 * template_icd::UART_MAX_CHUNK;
+
+/// Reconfiguring the USART also turns off the DMA receiver, so the new
+/// config goes to [`uart_rx_task`], which applies it and starts receiving again
+static CONFIG_REQUEST: Signal<CriticalSectionRawMutex, usart::Config> = Signal::new();
+static CONFIG_RESULT: Signal<CriticalSectionRawMutex, Result<(), ConfigError>> = Signal::new();
+
+pub struct UartBridge {
+    tx: UartTx<'static, Async>,
+}
+
+impl UartBridge {
+    pub fn new(tx: UartTx<'static, Async>) -> Self {
+        Self { tx }
+    }
+
+    pub async fn configure(&mut self, config: UartConfig) -> UartResult {
+        CONFIG_RESULT.reset();
+        CONFIG_REQUEST.signal(usart_config(config));
+        CONFIG_RESULT.wait().await.map_err(uart_error)
+    }
+
+    /// Transmit bytes from the host. This holds up the server until the
+    /// last byte is out, which keeps a fast host from outrunning the UART.
+    pub async fn send(&mut self, data: &[u8]) -> Result<(), usart::Error> {
+        self.tx.write(data).await
+    }
+}
+
+/// The driver works the divider out from the USART2 kernel clock the HAL
+/// reports, so the baud rate holds for any clock tree
+pub fn usart_config(config: UartConfig) -> usart::Config {
+    let mut cfg = usart::Config::default();
+    cfg.baudrate = config.baud_rate;
+    // Always 8 data bits, the driver adds the parity bit on top
+    cfg.data_bits = DataBits::DataBits8;
+    cfg.parity = match config.parity {
+        UartParity::None => Parity::ParityNone,
+        UartParity::Even => Parity::ParityEven,
+        UartParity::Odd => Parity::ParityOdd,
+    };
+    cfg.stop_bits = match config.stop_bits {
+        UartStopBits::One => StopBits::STOP1,
+        UartStopBits::Half => StopBits::STOP0P5,
+        UartStopBits::Two => StopBits::STOP2,
+        UartStopBits::OneAndHalf => StopBits::STOP1P5,
+    };
+    cfg
+}
+
+fn uart_error(e: ConfigError) -> UartError {
+    match e {
+        ConfigError::BaudrateTooLow => UartError::BaudRateTooLow,
+        ConfigError::BaudrateTooHigh => UartError::BaudRateTooHigh,
+        _ => UartError::Unsupported,
+    }
+}
+
+pooled_task! {
+    /// Forward everything received on the UART to the host, batched by the
+    /// line going idle or the ring buffer filling up
+    pub fn uart_rx_task(rx: RingBufferedUartRx<'static>, sender: Sender<AppTx>) => receive, UART_RX_POOL[1]
+}
+
+async fn receive(mut rx: RingBufferedUartRx<'static>, sender: Sender<AppTx>) {
+    let mut seq = TopicSeq::new();
+    let mut buf = [0u8; template_icd::UART_MAX_CHUNK];
+    loop {
+        match select(rx.read(&mut buf), CONFIG_REQUEST.wait()).await {
+            Either::First(Ok(len)) => {
+                // The chunk never exceeds the buffer, so this can't fail
+                let Ok(data) = UartData::from_slice(&buf[..len]) else {
+                    continue;
+                };
+                let _ = sender.publish::<UartRxTopic>(seq.advance(), &data).await;
+            }
+            // The driver stops on errors and restarts on the next read
+            Either::First(Err(e)) => {
+                let _ = sender.log_fmt(format_args!("UART RX error: {e:?}")).await;
+            }
+            Either::Second(cfg) => {
+                let res = rx.set_config(&cfg);
+                rx.start_uart();
+                CONFIG_RESULT.signal(res);
+            }
+        }
+    }
+}
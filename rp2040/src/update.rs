@@ -0,0 +1,217 @@
+//! A/B firmware updates through the bootloader crate
+//!
+//! Images come over a bulk transfer into the DFU slot, and the bootloader
+//! swaps them into the active slot on the next reset. A new image runs on
+//! trial until it answers [`GetUniqueIdEndpoint`](template_icd::GetUniqueIdEndpoint),
+//! a reset before then swaps the previous image back.
+//!
+//! Only with the `dfu` feature, which links the firmware for the active
+//! slot in `memory-dfu.x`. Without it updates fail with
+//! [`UpdateError::Unsupported`](template_icd::UpdateError::Unsupported).
+
+pub use imp::FirmwareUpdate;
+
+#[cfg(feature = "dfu")]
+mod imp {
+    use embassy_boot_stm32::{AlignedBuffer, BlockingFirmwareUpdater, FirmwareUpdaterConfig, State};
+    use embassy_executor::Spawner;
+    use embassy_futures::select::{select, Either};
+    use embassy_stm32::flash::{BANK1_REGION, WRITE_SIZE};
+    use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, signal::Signal};
+    use embassy_time::Timer;
+    use embedded_storage::nor_flash::{NorFlash, ReadNorFlash};
+    use sha2::Sha256;
+    use static_cell::ConstStaticCell;
+    use template_icd::{BulkError, BulkResult, FirmwareState, FirmwareStateResult, UpdateError, UpdateResult, UpdateStart};
+
+    use crate::{
+        bulk::BulkSink,
+        tasks::{self, pooled_task},
+        FlashPartition, SharedFlash,
+    };
+
+    /// A new image that hasn't been confirmed by then gets rolled back
+    const TRIAL_TIMEOUT_SECS: u64 = 30;
+
+    static CONFIRMED: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+
+    extern "C" {
+        static __bootloader_active_start: u32;
+        static __bootloader_state_start: u32;
+        static __bootloader_state_end: u32;
+        static __bootloader_dfu_start: u32;
+        static __bootloader_dfu_end: u32;
+    }
+
+    /// As [`FirmwareUpdaterConfig::from_linkerfile_blocking`], which only
+    /// takes flash behind a `NoopRawMutex`. Ours is shared with handlers on
+    /// the server executor.
+    fn partitions(flash: &'static SharedFlash) -> FirmwareUpdaterConfig<FlashPartition, FlashPartition> {
+        // SAFETY: only the addresses of the linker symbols are used
+        let (dfu_start, dfu_end, state_start, state_end) = unsafe {
+            (
+                &__bootloader_dfu_start as *const u32 as u32,
+                &__bootloader_dfu_end as *const u32 as u32,
+                &__bootloader_state_start as *const u32 as u32,
+                &__bootloader_state_end as *const u32 as u32,
+            )
+        };
+        FirmwareUpdaterConfig {
+            dfu: FlashPartition::new(flash, dfu_start, dfu_end - dfu_start),
+            state: FlashPartition::new(flash, state_start, state_end - state_start),
+        }
+    }
+
+    struct Pending {
+        size: u32,
+        sha256: [u8; 32],
+    }
+
+    pub struct FirmwareUpdate {
+        updater: BlockingFirmwareUpdater<'static, FlashPartition, FlashPartition>,
+        /// A second view of the update slot, for writing the image into the
+        /// space erased by `start`
+        dfu: FlashPartition,
+        pending: Option<Pending>,
+        trial: bool,
+    }
+
+    impl FirmwareUpdate {
+        /// Starts the rollback timer when running a new image on trial
+        pub fn new(flash: &'static SharedFlash, spawner: &Spawner) -> Self {
+            static STATE_BUF: ConstStaticCell<AlignedBuffer<WRITE_SIZE>> =
+                ConstStaticCell::new(AlignedBuffer([0; WRITE_SIZE]));
+            let dfu = partitions(flash).dfu;
+            let mut updater = BlockingFirmwareUpdater::new(partitions(flash), &mut STATE_BUF.take().0);
+            let trial = matches!(updater.get_state(), Ok(State::Swap));
+            if trial {
+                tasks::must_spawn(spawner, trial_task());
+            }
+            Self {
+                updater,
+                dfu,
+                pending: None,
+                trial,
+            }
+        }
+
+        /// Erases the whole slot, which stalls the executor for around half a second
+        pub fn start(&mut self, arg: UpdateStart) -> UpdateResult {
+            // SAFETY: only the address of the linker symbol is used
+            let active = BANK1_REGION.base + unsafe { &__bootloader_active_start as *const u32 as u32 };
+            if arg.address != active {
+                return Err(UpdateError::WrongAddress);
+            }
+            if arg.size as usize > self.dfu.capacity() - BANK1_REGION.erase_size as usize {
+                return Err(UpdateError::TooLarge);
+            }
+            if !(arg.size as usize).is_multiple_of(WRITE_SIZE) {
+                return Err(UpdateError::Misaligned);
+            }
+            if self.trial {
+                return Err(UpdateError::NotConfirmed);
+            }
+            self.pending = None;
+            self.updater.prepare_update().map_err(|_| UpdateError::Flash)?;
+            self.pending = Some(Pending {
+                size: arg.size,
+                sha256: arg.sha256,
+            });
+            Ok(())
+        }
+
+        fn write(&mut self, offset: u32, data: &[u8]) -> UpdateResult {
+            let pending = self.pending.as_ref().ok_or(UpdateError::NotStarted)?;
+            if !data.len().is_multiple_of(WRITE_SIZE) {
+                return Err(UpdateError::Misaligned);
+            }
+            if offset + data.len() as u32 > pending.size {
+                return Err(UpdateError::TooLarge);
+            }
+            self.dfu.write(offset, data).map_err(|_| UpdateError::Flash)
+        }
+
+        /// Checks the hash and marks the image for the bootloader, it is
+        /// swapped in on the next reset. The bulk transfer has already
+        /// checked that the whole image arrived.
+        pub fn finish(&mut self) -> UpdateResult {
+            let pending = self.pending.take().ok_or(UpdateError::NotStarted)?;
+            let mut hash = [0u8; 32];
+            let mut buf = [0u8; 64];
+            self.updater
+                .hash::<Sha256>(pending.size, &mut buf, &mut hash)
+                .map_err(|_| UpdateError::Flash)?;
+            if hash != pending.sha256 {
+                return Err(UpdateError::HashMismatch);
+            }
+            self.updater.mark_updated().map_err(|_| UpdateError::Flash)
+        }
+
+        pub fn state(&self) -> FirmwareStateResult {
+            Ok(match self.trial {
+                true => FirmwareState::Trial,
+                false => FirmwareState::Confirmed,
+            })
+        }
+
+        /// Keep the running image, a no-op unless it is on trial
+        pub fn confirm(&mut self) {
+            if self.trial && self.updater.mark_booted().is_ok() {
+                self.trial = false;
+                CONFIRMED.signal(());
+            }
+        }
+    }
+
+    impl BulkSink for FirmwareUpdate {
+        fn write(&mut self, offset: u32, data: &[u8]) -> BulkResult {
+            FirmwareUpdate::write(self, offset, data).map_err(BulkError::Update)
+        }
+    }
+
+    pooled_task! {
+        fn trial_task() => trial, TRIAL_POOL[1]
+    }
+
+    async fn trial() {
+        if let Either::First(()) = select(Timer::after_secs(TRIAL_TIMEOUT_SECS), CONFIRMED.wait()).await {
+            cortex_m::peripheral::SCB::sys_reset();
+        }
+    }
+}
+
+#[cfg(not(feature = "dfu"))]
+mod imp {
+    use embassy_executor::Spawner;
+    use template_icd::{BulkError, BulkResult, FirmwareStateResult, UpdateError, UpdateResult, UpdateStart};
+
+    use crate::{bulk::BulkSink, SharedFlash};
+
+    pub struct FirmwareUpdate;
+
+    impl FirmwareUpdate {
+        pub fn new(_flash: &'static SharedFlash, _spawner: &Spawner) -> Self {
+            Self
+        }
+
+        pub fn start(&mut self, _arg: UpdateStart) -> UpdateResult {
+            Err(UpdateError::Unsupported)
+        }
+
+        pub fn finish(&mut self) -> UpdateResult {
+            Err(UpdateError::Unsupported)
+        }
+
+        pub fn state(&self) -> FirmwareStateResult {
+            Err(UpdateError::Unsupported)
+        }
+
+        pub fn confirm(&mut self) {}
+    }
+
+    impl BulkSink for FirmwareUpdate {
+        fn write(&mut self, _offset: u32, _data: &[u8]) -> BulkResult {
+            Err(BulkError::Update(UpdateError::Unsupported))
+        }
+    }
+}
@@ -0,0 +1,97 @@
+//! Absolute time, as set by the host
+//!
+//! The host works out the Unix time at some moment of our uptime and sets
+//! it with [`TimeSetEndpoint`](template_icd::TimeSetEndpoint). From then on
+//! [`timestamp`] turns an [`Instant`] into Unix time. The time driver runs
+//! from HSI16 or the HSE crystal rather than a 32 kHz crystal on LSE, so
+//! its rate is off by up to a percent; each setting after the first
+//! measures that drift against the first one and corrects for it. The RTC
+//! is left to [`power`](crate::power), which keeps `Instant` going through
+//! STOP with it.
+
+use core::cell::Cell;
+
+use embassy_sync::blocking_mutex::{raw::CriticalSectionRawMutex, Mutex};
+use embassy_time::Instant;
+use template_icd::{DeviceTime, TimeError, TimeSet, TimeSetReport, TimeSetResult, Timestamp};
+
+/// Settings closer together than this don't tell the drift apart from the
+/// host's error in each
+const MIN_DRIFT_SPAN_US: u64 = 10_000_000;
+/// More than any oscillator we run from is off by
+const MAX_DRIFT_PPB: i64 = 20_000_000;
+
+#[derive(Clone, Copy)]
+struct Setting {
+    /// The first setting, drift is measured from it
+    first: TimeSet,
+    /// The latest setting, times are worked out from it
+    last: TimeSet,
+    drift_ppb: Option<i32>,
+}
+
+impl Setting {
+    fn unix_us(&self, uptime_us: u64) -> u64 {
+        let elapsed = uptime_us as i64 - self.last.uptime_us as i64;
+        // In milliseconds so a day at the largest drift stays within i64
+        let correction = elapsed / 1_000 * -i64::from(self.drift_ppb.unwrap_or(0)) / 1_000_000;
+        (self.last.unix_us as i64 + elapsed + correction) as u64
+    }
+}
+
+static SETTING: Mutex<CriticalSectionRawMutex, Cell<Option<Setting>>> = Mutex::new(Cell::new(None));
+
+/// When `at` was, in Unix time once the host has set it
+pub fn timestamp(at: Instant) -> Timestamp {
+    let uptime_us = at.as_micros();
+    match SETTING.lock(Cell::get) {
+        Some(setting) => Timestamp::Unix(setting.unix_us(uptime_us)),
+        None => Timestamp::Uptime(uptime_us),
+    }
+}
+
+pub fn now() -> DeviceTime {
+    let uptime_us = Instant::now().as_micros();
+    DeviceTime {
+        uptime_us,
+        unix_us: SETTING.lock(Cell::get).map(|setting| setting.unix_us(uptime_us)),
+    }
+}
+
+pub fn set(time: TimeSet) -> TimeSetResult {
+    // Everything below is worked out in i64
+    if i64::try_from(time.uptime_us).is_err() || i64::try_from(time.unix_us).is_err() {
+        return Err(TimeError::OutOfRange);
+    }
+    SETTING.lock(|cell| {
+        let Some(mut setting) = cell.get() else {
+            cell.set(Some(Setting {
+                first: time,
+                last: time,
+                drift_ppb: None,
+            }));
+            return Ok(TimeSetReport {
+                error_us: None,
+                drift_ppb: None,
+            });
+        };
+        let error_us = setting.unix_us(time.uptime_us) as i64 - time.unix_us as i64;
+        let span = time.uptime_us.saturating_sub(setting.first.uptime_us);
+        if span >= MIN_DRIFT_SPAN_US {
+            let ours = span as i64;
+            let theirs = time.unix_us as i64 - setting.first.unix_us as i64;
+            let drift = ours
+                .checked_sub(theirs)
+                .and_then(|d| d.checked_mul(1_000_000))
+                .ok_or(TimeError::OutOfRange)?
+                / (ours / 1_000);
+            setting.drift_ppb = Some(drift.clamp(-MAX_DRIFT_PPB, MAX_DRIFT_PPB) as i32);
+        }
+        setting.last = time;
+        cell.set(Some(setting));
+        Ok(TimeSetReport {
+            error_us: Some(error_us),
+            drift_ppb: setting.drift_ppb,
+        })
+    })
+}